@@ -0,0 +1,153 @@
+pub const NTSC_CPU_CLOCK: u32 = 1_789_773;
+pub const NTSC_CYCLES_PER_FRAME: u64 = 29_781;
+const VGM_SAMPLE_RATE: u64 = 44_100;
+const VGM_VERSION: u32 = 0x0000_0171;
+const VGM_HEADER_SIZE: usize = 0x100;
+
+pub fn is_apu_register(addr: u16) -> bool {
+    matches!(addr, 0x4000..=0x4017)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ApuWrite {
+    pub cycle: u64,
+    pub addr: u16,
+    pub data: u8,
+}
+
+/**
+Records every CPU write to $4000-$4017 with the CPU cycle it happened on,
+counted from the cycle the recording started. A write made by an
+instruction is stamped with the instruction's last cycle, the one a
+6502 store writes on.
+
+The log can be exported as a VGM 1.71 file using the NES APU command
+(0xB4 aa dd) so that the music can be replayed by any VGM player.
+$4014 (OAM DMA) and $4016 (controller strobe) are kept in the log but
+are not APU registers, so they are left out of the VGM stream.
+*/
+pub struct ApuLog {
+    start_cycle: u64,
+    writes: Vec<ApuWrite>,
+}
+
+impl ApuLog {
+    pub fn new(start_cycle: u64) -> Self {
+        ApuLog {
+            start_cycle,
+            writes: vec![],
+        }
+    }
+
+    pub fn record(&mut self, cycle: u64, addr: u16, data: u8) {
+        self.writes.push(ApuWrite {
+            cycle: cycle - self.start_cycle,
+            addr,
+            data,
+        });
+    }
+
+    pub fn writes(&self) -> &[ApuWrite] {
+        &self.writes
+    }
+
+    fn cycle_to_sample(cycle: u64) -> u64 {
+        cycle * VGM_SAMPLE_RATE / NTSC_CPU_CLOCK as u64
+    }
+
+    fn push_wait(data: &mut Vec<u8>, mut samples: u64) {
+        while samples > 0 {
+            match samples {
+                735 => {
+                    data.push(0x62);
+                    samples = 0;
+                }
+                882 => {
+                    data.push(0x63);
+                    samples = 0;
+                }
+                1..=16 => {
+                    data.push(0x70 + (samples - 1) as u8);
+                    samples = 0;
+                }
+                _ => {
+                    let n = samples.min(0xFFFF);
+                    data.push(0x61);
+                    data.extend_from_slice(&(n as u16).to_le_bytes());
+                    samples -= n;
+                }
+            }
+        }
+    }
+
+    // `end_cycle` is the cycle the recording stopped at, so trailing
+    // silence after the last write is preserved.
+    pub fn to_vgm(&self, end_cycle: u64) -> Vec<u8> {
+        let end_cycle = end_cycle - self.start_cycle;
+        let mut data = Vec::new();
+        let mut last_sample = 0;
+        for write in &self.writes {
+            if write.addr == 0x4014 || write.addr == 0x4016 {
+                continue;
+            }
+            let sample = Self::cycle_to_sample(write.cycle);
+            Self::push_wait(&mut data, sample - last_sample);
+            last_sample = sample;
+            data.push(0xB4);
+            data.push((write.addr - 0x4000) as u8);
+            data.push(write.data);
+        }
+        let total_samples = Self::cycle_to_sample(end_cycle).max(last_sample);
+        Self::push_wait(&mut data, total_samples - last_sample);
+        data.push(0x66);
+
+        let mut vgm = vec![0u8; VGM_HEADER_SIZE];
+        let mut put32 = |offset: usize, value: u32| {
+            vgm[offset..offset + 4].copy_from_slice(&value.to_le_bytes());
+        };
+        put32(0x04, (VGM_HEADER_SIZE + data.len() - 4) as u32);
+        put32(0x08, VGM_VERSION);
+        put32(0x18, total_samples as u32);
+        put32(0x24, 60);
+        put32(0x34, (VGM_HEADER_SIZE - 0x34) as u32);
+        put32(0x84, NTSC_CPU_CLOCK);
+        vgm[0..4].copy_from_slice(b"Vgm ");
+        vgm.extend_from_slice(&data);
+        vgm
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_to_vgm() {
+        let mut log = ApuLog::new(7);
+        log.record(7, 0x4015, 0x0F);
+        log.record(7, 0x4014, 0x02);
+        log.record(7 + NTSC_CPU_CLOCK as u64 / 60, 0x4000, 0xBF);
+        let vgm = log.to_vgm(7 + NTSC_CPU_CLOCK as u64 / 60);
+        assert_eq!(&vgm[0..4], b"Vgm ");
+        assert_eq!(
+            u32::from_le_bytes(vgm[0x04..0x08].try_into().unwrap()) as usize,
+            vgm.len() - 4
+        );
+        assert_eq!(
+            u32::from_le_bytes(vgm[0x84..0x88].try_into().unwrap()),
+            NTSC_CPU_CLOCK
+        );
+        // 29829 cycles is 734 samples, one short of the 1/60s wait shortcut
+        assert_eq!(
+            &vgm[VGM_HEADER_SIZE..],
+            &[0xB4, 0x15, 0x0F, 0x61, 0xDE, 0x02, 0xB4, 0x00, 0xBF, 0x66]
+        );
+    }
+
+    #[test]
+    fn test_long_wait() {
+        let mut data = Vec::new();
+        ApuLog::push_wait(&mut data, 0x10000 + 3);
+        assert_eq!(data, vec![0x61, 0xFF, 0xFF, 0x73]);
+    }
+}
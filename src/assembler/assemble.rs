@@ -0,0 +1,1227 @@
+use std::str::FromStr;
+
+use error_stack::{Report, Result, bail};
+
+use super::diagnostic::{into_report, Diagnostic, Subject};
+use super::expr::Expr;
+use super::linker::{MemoryLayout, Segment};
+use super::preprocessor::SourceLine;
+use crate::cpu::addressing_mode::AddressingMode;
+use crate::error::NesError;
+use crate::instructions::{is_official_opcode, InstFactory, INST_FACTORIES_BY_NAME_MODE, INST_FACTORIES_BY_OP_CODE};
+
+const MAX_CONSTANT_DEPTH: usize = 64;
+const MAX_PASSES: usize = 64;
+const BRANCHES: [&str; 8] = ["BEQ", "BNE", "BCS", "BCC", "BVS", "BVC", "BPL", "BMI"];
+// Other names in use for the unofficial instructions.
+const MNEMONIC_ALIASES: [(&str, &str); 14] = [
+    ("ASO", "SLO"), ("LSE", "SRE"), ("ISC", "ISB"), ("INS", "ISB"), ("DCM", "DCP"),
+    ("LAS", "LAR"), ("LAE", "LAR"), ("SBX", "AXS"), ("AAX", "SAX"), ("AAC", "ANC"),
+    ("JAM", "KIL"), ("HLT", "KIL"), ("DOP", "NOP"), ("SKB", "NOP"),
+];
+
+pub struct Assembler {
+    label_addr: std::collections::HashMap<String, u16>,
+    // constant name -> (expression, address of the definition)
+    constants: std::collections::HashMap<String, (Expr, u16)>,
+    // lines currently assembled with a zero page operand
+    zero_page_lines: std::collections::HashSet<usize>,
+    // address of each line, from the last pass
+    line_addr: Vec<u16>,
+    // bytes emitted by each line in the final pass
+    line_bytes: Vec<Vec<u8>>,
+    // origin of each line, for diagnostics
+    locations: Vec<SourceLine>,
+    diagnostics: Vec<Diagnostic>,
+    allow_unofficial: bool,
+    layout: Option<MemoryLayout>,
+    segments: Vec<Segment>,
+    // the segment being assembled, whose counters are held in
+    // `addr`, `size` and `bytes`
+    segment: usize,
+    addr: u16,
+    size: u32,
+    bytes: Vec<u8>,
+}
+
+impl Assembler {
+    pub fn new(start_addr: u16) -> Self {
+        Self {
+            label_addr: std::collections::HashMap::new(),
+            constants: std::collections::HashMap::new(),
+            zero_page_lines: std::collections::HashSet::new(),
+            line_addr: Vec::new(),
+            line_bytes: Vec::new(),
+            locations: Vec::new(),
+            diagnostics: Vec::new(),
+            allow_unofficial: false,
+            layout: None,
+            segments: vec![Segment::new("CODE", start_addr)],
+            segment: 0,
+            addr: start_addr,
+            size: 0,
+            bytes: Vec::new(),
+        }
+    }
+
+    // An assembler that places `.segment`s according to `layout` and
+    // links them into a .nes file.
+    pub fn with_layout(layout: MemoryLayout) -> Self {
+        let mut assembler = Assembler::new(0);
+        assembler.segments = layout.new_segments();
+        assembler.segment = layout.default_segment();
+        assembler.layout = Some(layout);
+        assembler
+    }
+
+    // Reports errors at the source lines in `locations` rather than at
+    // line numbers of the `AsmLine`s.
+    pub fn with_locations(mut self, locations: &[SourceLine]) -> Self {
+        self.locations = locations.to_vec();
+        self
+    }
+
+    // Accepts undocumented opcodes, which are rejected by default.
+    pub fn allow_unofficial(mut self) -> Self {
+        self.allow_unofficial = true;
+        self
+    }
+
+    fn report(&mut self, report: &Report<NesError>, idx: usize) {
+        let diagnostic = Diagnostic::new(report, idx, self.locations.get(idx));
+        self.diagnostics.push(diagnostic);
+    }
+
+    fn check(&mut self) -> Result<(), NesError> {
+        match self.diagnostics.is_empty() {
+            true => Ok(()),
+            false => Err(into_report(std::mem::take(&mut self.diagnostics))),
+        }
+    }
+
+    fn advance(&mut self, size: u16) {
+        self.addr = self.addr.wrapping_add(size);
+        self.size += size as u32;
+    }
+
+    fn save_segment(&mut self) {
+        let segment = &mut self.segments[self.segment];
+        segment.addr = self.addr;
+        segment.size = self.size;
+        segment.bytes = std::mem::take(&mut self.bytes);
+    }
+
+    fn load_segment(&mut self, idx: usize) {
+        self.segment = idx;
+        let segment = &mut self.segments[idx];
+        self.addr = segment.addr;
+        self.size = segment.size;
+        self.bytes = std::mem::take(&mut segment.bytes);
+    }
+
+    fn switch_segment(&mut self, name: &str) -> Result<(), NesError> {
+        if self.layout.is_none() {
+            bail!(NesError::AssemblerFailure(format!("segment {} needs a memory layout", name)));
+        }
+        let Some(idx) = self.segments.iter().position(|s| s.name == name) else {
+            bail!(NesError::AssemblerFailure(format!("unknown segment: {}", name)));
+        };
+        self.save_segment();
+        self.load_segment(idx);
+        Ok(())
+    }
+
+    // Rewinds every segment to its start before a pass.
+    fn reset_segments(&mut self) {
+        for segment in &mut self.segments {
+            segment.addr = segment.start;
+            segment.size = 0;
+            segment.bytes.clear();
+            segment.data = false;
+        }
+        let default = self.layout.as_ref().map_or(0, |layout| layout.default_segment());
+        self.load_segment(default);
+    }
+
+    // The opcode for `name` in `mode`; `name` may select an opcode
+    // explicitly as in `NOP.1A`.
+    fn find_factory(&self, name: &str, mode: AddressingMode) -> Result<&'static InstFactory, NesError> {
+        let error = |message: String| Err(Report::new(NesError::InstNotFound(message)).attach(Subject(name.to_string())));
+        let factory = match split_opcode_suffix(name) {
+            Some((_, opcode)) => &INST_FACTORIES_BY_OP_CODE[&opcode],
+            None => match INST_FACTORIES_BY_NAME_MODE.get(&(name.to_string(), mode)) {
+                Some(factory) => factory,
+                None if INST_FACTORIES_BY_NAME_MODE.keys().any(|(n, _)| n == name) => {
+                    return error(format!("{} does not support {:?} addressing", name, mode));
+                },
+                None => return error(format!("unknown mnemonic: {}", name)),
+            },
+        };
+        if !self.allow_unofficial && !is_official_opcode(factory.opcode) {
+            return error(format!("{} (${:02X}) is an unofficial opcode", name, factory.opcode));
+        }
+        Ok(factory)
+    }
+
+    fn handle(&mut self, name: &str, mode: AddressingMode, operand: Option<u16>) -> Result<(), NesError> {
+        let factory = self.find_factory(name, mode)?;
+        let inst = factory.make2(operand);
+        let bs = inst.to_bytes();
+        self.bytes.extend_from_slice(&bs);
+        self.advance(mode.get_inst_size());
+        Ok(())
+    }
+
+    fn parse_int8(&self, s: &str) -> Option<u8> {
+        if s.starts_with("$") {
+            return u8::from_str_radix(&s[1..], 16).ok()
+        }
+        s.parse().ok()
+    }
+
+    fn lookup_symbol(&self, name: &str, depth: usize) -> Result<i64, NesError> {
+        if let Some(addr) = self.label_addr.get(name) {
+            return Ok(*addr as i64);
+        }
+        if let Some((expr, pc)) = self.constants.get(name) {
+            if depth > MAX_CONSTANT_DEPTH {
+                bail!(NesError::AssemblerFailure(format!("recursive constant: {}", name)));
+            }
+            return expr.eval(&|name: &str| self.lookup_symbol(name, depth + 1), *pc);
+        }
+        Err(Report::new(NesError::AssemblerFailure(format!("undefined symbol: {}", name)))
+            .attach(Subject(name.to_string())))
+    }
+
+    fn eval(&self, s: &str) -> Result<i64, NesError> {
+        Expr::parse(s)?.eval(&|name: &str| self.lookup_symbol(name, 0), self.addr)
+    }
+
+    fn get_byte(&self, s: &str) -> Result<u8, NesError> {
+        let value = self.eval(s)?;
+        if !(-128..=0xff).contains(&value) {
+            return Err(Report::new(NesError::AssemblerFailure(format!("byte value out of range: {} = {}", s, value)))
+                .attach(Subject(s.to_string())));
+        }
+        Ok(value as u8)
+    }
+
+    fn get_word(&self, s: &str) -> Result<u16, NesError> {
+        let value = self.eval(s)?;
+        if !(-0x8000..=0xffff).contains(&value) {
+            return Err(Report::new(NesError::AssemblerFailure(format!("word value out of range: {} = {}", s, value)))
+                .attach(Subject(s.to_string())));
+        }
+        Ok(value as u16)
+    }
+
+    fn get_operand_value(&self, mode: AddressingMode, operand: &str) -> Result<u16, NesError>  {
+        match mode {
+            AddressingMode::Implied |
+            AddressingMode::Accumulator => bail!(NesError::AssemblerFailure(format!("unexpected operand: {}", operand))),
+            AddressingMode::Immediate|
+            AddressingMode::ZeroPage  |
+            AddressingMode::ZeroPageX |
+            AddressingMode::ZeroPageY |
+            AddressingMode::IndexedIndirect |
+            AddressingMode::IndirectIndexed => {
+                Ok(self.get_byte(operand)? as u16)
+            },
+            AddressingMode::Relative => {
+                // a plain number is the raw branch offset
+                if let Some(operand) = self.parse_int8(operand) {
+                    return Ok(operand as u16);
+                }
+                let target_addr = self.get_word(operand)?;
+                let current_addr = self.addr.wrapping_add(2);
+                let diff = target_addr.wrapping_sub(current_addr) as i16;
+                if !(-128..=127).contains(&diff) {
+                    let message = format!("branch target {} is {} bytes away, beyond -128..127", operand, diff);
+                    return Err(Report::new(NesError::AssemblerFailure(message)).attach(Subject(operand.to_string())));
+                }
+                Ok(diff as u8 as u16)
+            }
+            AddressingMode::Absolute |
+            AddressingMode::AbsoluteX |
+            AddressingMode::AbsoluteY |
+            AddressingMode::Indirect => {
+                Ok(self.get_word(operand)?)
+            },
+        }
+    }
+
+    // The mode a line is assembled with: operands without an explicit
+    // size use the zero page form while their value is known to fit.
+    fn get_mode(&self, idx: usize, name: &str, mode: AddressingMode) -> AddressingMode {
+        if self.zero_page_lines.contains(&idx) {
+            zero_page_mode(name, mode).unwrap_or(mode)
+        } else {
+            mode
+        }
+    }
+
+    fn get_line_size(&self, idx: usize, line: &AsmLine) -> u16 {
+        match line {
+            AsmLine::Inst2{name, mode, ..} => self.get_mode(idx, name, *mode).get_inst_size(),
+            _ => line.get_inst_size(),
+        }
+    }
+
+    // Switches operands whose value turned out not to fit in the zero page
+    // to the absolute form. Returns whether any line changed size.
+    fn widen_operands(&mut self, lines: &[AsmLine]) -> bool {
+        let mut changed = false;
+        for (idx, line) in lines.iter().enumerate() {
+            if let AsmLine::Inst2{operand, ..} = line {
+                if !self.zero_page_lines.contains(&idx) {
+                    continue;
+                }
+                self.addr = self.line_addr[idx];
+                let fits = matches!(self.eval(operand), Ok(value) if (0..=0xff).contains(&value));
+                if !fits {
+                    self.zero_page_lines.remove(&idx);
+                    changed = true;
+                }
+            }
+        }
+        changed
+    }
+
+    fn emit_bytes(&mut self, values: &[String]) -> Result<(), NesError> {
+        for value in values {
+            if let Some(text) = parse_string_literal(value) {
+                self.bytes.extend_from_slice(text.as_bytes());
+                self.advance(text.len() as u16);
+                continue;
+            }
+            let v = self.get_byte(value)?;
+            self.bytes.push(v);
+            self.advance(1);
+        }
+        Ok(())
+    }
+
+    fn emit_words(&mut self, values: &[String]) -> Result<(), NesError> {
+        for value in values {
+            let v = self.get_word(value)?;
+            self.bytes.extend_from_slice(&v.to_le_bytes());
+            self.advance(2);
+        }
+        Ok(())
+    }
+
+    fn fill(&mut self, count: u16, value: u8) {
+        self.bytes.extend(std::iter::repeat_n(value, count as usize));
+        self.advance(count);
+    }
+
+    // `.org` can only move forward, the gap is filled with zeros.
+    fn get_org_gap(&self, addr: &str) -> Result<u16, NesError> {
+        let target = self.get_word(addr)?;
+        if target < self.addr {
+            bail!(NesError::AssemblerFailure(format!(".org {} is before the current address ${:04X}", addr, self.addr)));
+        }
+        Ok(target - self.addr)
+    }
+
+    fn get_res_size(&self, count: &str, fill: &Option<String>) -> Result<(u16, u8), NesError> {
+        let count = self.get_word(count)?;
+        let fill = match fill {
+            Some(fill) => self.get_byte(fill)?,
+            None => 0,
+        };
+        Ok((count, fill))
+    }
+
+    fn assemble_line(&mut self, idx: usize, line: &AsmLine) -> Result<(), NesError> {
+        match line {
+            AsmLine::Empty |
+            AsmLine::Label{name: _} |
+            AsmLine::Const{..} => return Ok(()),
+            AsmLine::Inst1{name, mode} => {
+                self.handle(name, *mode, None)?;
+            },
+            AsmLine::Inst2{name, mode, operand, ..} => {
+                let mode = self.get_mode(idx, name, *mode);
+                self.find_factory(name, mode)?;
+                let operand_value = self.get_operand_value(mode, operand)?;
+                self.handle(name, mode, Some(operand_value))?;
+            },
+            AsmLine::Org{addr} => {
+                let gap = self.get_org_gap(addr)?;
+                self.fill(gap, 0);
+            },
+            AsmLine::Byte{values} => self.emit_bytes(values)?,
+            AsmLine::Word{values} => self.emit_words(values)?,
+            AsmLine::Res{count, fill} => {
+                let (count, fill) = self.get_res_size(count, fill)?;
+                self.fill(count, fill);
+            },
+            AsmLine::Data{bytes} => {
+                self.bytes.extend_from_slice(bytes);
+                self.advance(bytes.len() as u16);
+            },
+            AsmLine::Segment{name} => self.switch_segment(name)?,
+            AsmLine::Include{path} |
+            AsmLine::IncBin{path} => {
+                bail!(NesError::AssemblerFailure(format!("unresolved file: {}, use read_source to load it", path)));
+            },
+        }
+        Ok(())
+    }
+
+    fn define_symbol(&self, name: &str) -> Result<(), NesError> {
+        if self.label_addr.contains_key(name) || self.constants.contains_key(name) {
+            bail!(NesError::AssemblerFailure(format!("duplicate symbol: {}", name)));
+        }
+        Ok(())
+    }
+
+    fn build_label_addr(&mut self, lines: &[AsmLine]) -> Result<(), NesError> {
+        self.label_addr.clear();
+        self.constants.clear();
+        self.reset_segments();
+        self.line_addr.clear();
+        self.diagnostics.clear();
+        for (idx, line) in lines.iter().enumerate() {
+            self.line_addr.push(self.addr);
+            if let Err(report) = self.place_line(idx, line) {
+                self.report(&report, idx);
+            }
+        }
+        self.save_segment();
+        Ok(())
+    }
+
+    // Defines the line's symbols and moves past it.
+    fn place_line(&mut self, idx: usize, line: &AsmLine) -> Result<(), NesError> {
+        match line {
+            AsmLine::Label{name} => {
+                self.define_symbol(name)?;
+                self.label_addr.insert(name.to_string(), self.addr);
+            },
+            AsmLine::Const{name, value} => {
+                self.define_symbol(name)?;
+                self.constants.insert(name.to_string(), (Expr::parse(value)?, self.addr));
+            },
+            AsmLine::Org{addr} => {
+                let gap = self.get_org_gap(addr)?;
+                self.advance(gap);
+            },
+            AsmLine::Res{count, fill} => {
+                let size = self.get_res_size(count, fill)?.0;
+                self.advance(size);
+            },
+            AsmLine::Segment{name} => self.switch_segment(name)?,
+            _ => {
+                self.advance(self.get_line_size(idx, line));
+            }
+        }
+        Ok(())
+    }
+
+    fn place_segments(&mut self) -> Result<bool, NesError> {
+        match &self.layout {
+            Some(layout) => layout.place(&mut self.segments),
+            None => Ok(false),
+        }
+    }
+
+    fn assemble_segments(&mut self, lines: &[AsmLine]) -> Result<(), NesError> {
+        let lines = &resolve_local_labels(lines)?;
+        // Start with every operand that may be zero page in its zero page
+        // form and widen until the label and segment addresses settle.
+        self.zero_page_lines = lines.iter().enumerate().filter_map(|(idx, line)| match line {
+            AsmLine::Inst2{name, mode, size: OperandSize::Auto, ..} if zero_page_mode(name, *mode).is_some() => Some(idx),
+            _ => None,
+        }).collect();
+        let mut passes = 0;
+        loop {
+            self.build_label_addr(lines)?;
+            let widened = self.widen_operands(lines);
+            let moved = self.place_segments()?;
+            if !widened && !moved {
+                break;
+            }
+            passes += 1;
+            if passes >= MAX_PASSES {
+                bail!(NesError::AssemblerFailure("addresses do not settle".to_string()));
+            }
+        }
+        self.check()?;
+        self.reset_segments();
+        self.line_addr.clear();
+        self.line_bytes.clear();
+        for (idx, line) in lines.iter().enumerate() {
+            self.line_addr.push(self.addr);
+            let len = self.bytes.len();
+            if let Err(report) = self.assemble_line(idx, line) {
+                self.report(&report, idx);
+                // keep the following lines at their addresses from the
+                // label pass
+                let size = self.get_line_size(idx, line) as usize;
+                let emitted = self.bytes.len().saturating_sub(len);
+                self.fill(size.saturating_sub(emitted) as u16, 0);
+            }
+            // a segment switch starts over in another buffer
+            let bytes = self.bytes.get(len..).unwrap_or_default().to_vec();
+            if !bytes.is_empty() && !matches!(line, AsmLine::Org{..} | AsmLine::Res{..}) {
+                self.segments[self.segment].data = true;
+            }
+            self.line_bytes.push(bytes);
+        }
+        self.save_segment();
+        self.check()
+    }
+
+    // The address and the bytes of every line from the last assembly.
+    pub fn line_output(&self) -> impl Iterator<Item = (u16, &[u8])> {
+        self.line_addr.iter().copied().zip(self.line_bytes.iter().map(|b| b.as_slice()))
+    }
+
+    // Labels and constants with their values, sorted by value. Names
+    // generated for anonymous labels and macro locals are left out.
+    pub fn symbols(&self) -> Vec<(String, i64)> {
+        let mut symbols = self.label_addr.iter()
+            .map(|(name, addr)| (name.clone(), *addr as i64))
+            .chain(self.constants.keys().filter_map(|name| {
+                self.lookup_symbol(name, 0).ok().map(|value| (name.clone(), value))
+            }))
+            .filter(|(name, _)| !name.starts_with("__") && !name.contains("@__"))
+            .collect::<Vec<_>>();
+        symbols.sort_by(|a, b| a.1.cmp(&b.1).then_with(|| a.0.cmp(&b.0)));
+        symbols
+    }
+
+    pub fn assemble(&mut self, lines: &[AsmLine]) -> Result<&[u8], NesError> {
+        if self.layout.is_some() {
+            bail!(NesError::AssemblerFailure("use link to assemble with a memory layout".to_string()));
+        }
+        self.assemble_segments(lines)?;
+        Ok(self.segments[0].bytes.as_slice())
+    }
+
+    // Assembles `lines` into a .nes file according to the memory layout.
+    pub fn link(&mut self, lines: &[AsmLine]) -> Result<Vec<u8>, NesError> {
+        self.assemble_segments(lines)?;
+        match &self.layout {
+            Some(layout) => layout.build_image(&self.segments),
+            None => bail!(NesError::AssemblerFailure("link needs a memory layout".to_string())),
+        }
+    }
+}
+
+// Operand size requested in the source: `z:` forces zero page, `a:`
+// forces absolute, anything else is decided from the operand's value.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum OperandSize {
+    Auto,
+    ZeroPage,
+    Absolute,
+}
+
+#[derive(Debug, Clone)]
+pub enum AsmLine {
+    Empty,
+    Label{name: String},
+    Const{name: String, value: String},
+    Inst1{name: String, mode: AddressingMode},
+    Inst2{name: String, mode: AddressingMode, operand: String, size: OperandSize},
+    Org{addr: String},
+    Byte{values: Vec<String>},
+    Word{values: Vec<String>},
+    Res{count: String, fill: Option<String>},
+    Data{bytes: Vec<u8>},
+    Include{path: String},
+    IncBin{path: String},
+    Segment{name: String},
+}
+
+impl AsmLine {
+    fn get_inst_size(&self) -> u16 {
+        match self {
+            AsmLine::Empty => 0,
+            AsmLine::Label{name: _} => 0,
+            AsmLine::Const{..} => 0,
+            AsmLine::Inst1{name: _, mode} => mode.get_inst_size(),
+            AsmLine::Inst2{name: _, mode, ..} => mode.get_inst_size(),
+            AsmLine::Byte{values} => values.iter().map(|v| match parse_string_literal(v) {
+                Some(text) => text.len() as u16,
+                None => 1,
+            }).sum(),
+            AsmLine::Word{values} => values.len() as u16 * 2,
+            AsmLine::Data{bytes} => bytes.len() as u16,
+            // sizes of these depend on the assembler state
+            AsmLine::Org{..} |
+            AsmLine::Res{..} |
+            AsmLine::Include{..} |
+            AsmLine::IncBin{..} |
+            AsmLine::Segment{..} => 0,
+        }
+    }
+
+    // Applies `f` to every expression in the line.
+    fn map_exprs<F>(&self, f: F) -> Result<AsmLine, NesError>
+    where
+        F: Fn(&str) -> Result<String, NesError>,
+    {
+        let map_all = |values: &[String]| values.iter().map(|v| f(v)).collect::<Result<Vec<_>, _>>();
+        Ok(match self {
+            AsmLine::Const{name, value} => AsmLine::Const{name: name.clone(), value: f(value)?},
+            AsmLine::Inst2{name, mode, operand, size} => AsmLine::Inst2{
+                name: name.clone(),
+                mode: *mode,
+                operand: f(operand)?,
+                size: *size,
+            },
+            AsmLine::Org{addr} => AsmLine::Org{addr: f(addr)?},
+            AsmLine::Byte{values} => AsmLine::Byte{values: map_all(values)?},
+            AsmLine::Word{values} => AsmLine::Word{values: map_all(values)?},
+            AsmLine::Res{count, fill} => AsmLine::Res{
+                count: f(count)?,
+                fill: fill.as_deref().map(&f).transpose()?,
+            },
+            line => line.clone(),
+        })
+    }
+
+    fn parse_directive(s: &str) -> std::result::Result<Self, NesError> {
+        let (name, args) = match s.find(char::is_whitespace) {
+            Some(i) => (&s[..i], s[i..].trim()),
+            None => (s, ""),
+        };
+        let values = split_operands(args);
+        let illegal = || NesError::AssemblerFailure(format!("Illegal directive: {}", s));
+        match name.to_lowercase().as_str() {
+            ".org" | ".pad" if values.len() == 1 => Ok(AsmLine::Org{addr: values[0].clone()}),
+            ".byte" | ".byt" | ".db" if !values.is_empty() => Ok(AsmLine::Byte{values}),
+            ".word" | ".addr" | ".dw" if !values.is_empty() => Ok(AsmLine::Word{values}),
+            ".res" | ".dsb" if values.len() == 1 || values.len() == 2 => Ok(AsmLine::Res{
+                count: values[0].clone(),
+                fill: values.get(1).cloned(),
+            }),
+            ".dsw" if values.len() == 1 => Ok(AsmLine::Res{
+                count: format!("({}) * 2", values[0]),
+                fill: None,
+            }),
+            ".hex" => Ok(AsmLine::Data{bytes: parse_hex(args).ok_or_else(illegal)?}),
+            ".include" if values.len() == 1 => Ok(AsmLine::Include{
+                path: parse_string_literal(&values[0]).ok_or_else(illegal)?,
+            }),
+            ".incbin" if values.len() == 1 => Ok(AsmLine::IncBin{
+                path: parse_string_literal(&values[0]).ok_or_else(illegal)?,
+            }),
+            ".segment" if values.len() == 1 => Ok(AsmLine::Segment{
+                name: parse_string_literal(&values[0]).unwrap_or_else(|| values[0].clone()),
+            }),
+            ".code" | ".rodata" | ".data" | ".bss" | ".zeropage" if values.is_empty() => Ok(AsmLine::Segment{
+                name: name[1..].to_uppercase(),
+            }),
+            // sources are assembled as a whole, there is nothing to link
+            // against
+            ".import" | ".importzp" | ".export" | ".exportzp" | ".global" | ".globalzp" => Ok(AsmLine::Empty),
+            _ => Err(illegal()),
+        }
+    }
+
+    // `NOP.1A`, `SBC.EB #1`: the mnemonic with the opcode to use, for
+    // instructions that have more than one opcode for a mode.
+    fn parse_opcode_variant(name: &str, operand: &str, s: &str) -> std::result::Result<Self, NesError> {
+        let illegal = |reason: &str| Err(NesError::AssemblerFailure(format!("{}: {}", reason, s)));
+        let Some((base, opcode)) = split_opcode_suffix(name) else {
+            return illegal("illegal opcode suffix");
+        };
+        let Some(factory) = INST_FACTORIES_BY_OP_CODE.get(&opcode) else {
+            return illegal("unknown opcode");
+        };
+        if factory.name != base {
+            return illegal(&format!("opcode ${:02X} is {}", opcode, factory.name));
+        }
+        let parsed = if operand.is_empty() {
+            AsmLine::Inst1{name: base.to_string(), mode: AddressingMode::Implied}
+        } else {
+            AsmLine::parse_instruction(&format!("{} {}", base, operand))?
+        };
+        match parsed {
+            AsmLine::Inst1{mode, ..} if mode == factory.mode => Ok(AsmLine::Inst1{name: name.to_string(), mode}),
+            AsmLine::Inst2{mode, operand, ..} if mode == factory.mode || to_zero_page(mode) == Some(factory.mode) => {
+                // the opcode fixes the operand size
+                let size = match to_zero_page(mode) == Some(factory.mode) {
+                    true => OperandSize::ZeroPage,
+                    false => OperandSize::Absolute,
+                };
+                Ok(AsmLine::Inst2{name: name.to_string(), mode: factory.mode, operand, size})
+            },
+            _ => illegal(&format!("operand does not match the {:?} addressing of ${:02X}", factory.mode, opcode)),
+        }
+    }
+
+    fn parse_instruction(s: &str) -> std::result::Result<Self, NesError> {
+        let (name, operand) = match s.find(char::is_whitespace) {
+            Some(i) => (&s[..i], s[i..].trim()),
+            None => (s, ""),
+        };
+        let mut name = name.to_uppercase();
+        let base = name.split('.').next().unwrap_or_default();
+        if let Some((alias, official)) = MNEMONIC_ALIASES.iter().find(|(alias, _)| *alias == base) {
+            name = name.replacen(alias, official, 1);
+        }
+        if name.contains('.') {
+            return AsmLine::parse_opcode_variant(&name, operand, s);
+        }
+        if operand.is_empty() {
+            if name == "ASL" || name == "ROL" || name == "LSR" || name == "ROR" {
+                return Ok(AsmLine::Inst1 { name, mode: AddressingMode::Accumulator});
+            }
+            return Ok(AsmLine::Inst1 { name, mode: AddressingMode::Implied});
+        }
+        let inst2 = |mode, operand: &str| Ok(AsmLine::Inst2 { name: name.clone(), mode, operand: operand.to_string(), size: OperandSize::Auto});
+        if BRANCHES.contains(&name.as_str()) {
+            return inst2(AddressingMode::Relative, operand);
+        }
+        if operand.eq_ignore_ascii_case("A") {
+            return Ok(AsmLine::Inst1 { name, mode: AddressingMode::Accumulator});
+        }
+        if let Some(operand) = operand.strip_prefix('#') {
+            return inst2(AddressingMode::Immediate, operand.trim());
+        }
+        if operand.starts_with('(') {
+            if let Some(close) = find_closing_paren(operand) {
+                let inner = operand[1..close].trim();
+                let rest = operand[close + 1..].trim();
+                if rest.is_empty() {
+                    if let Some(operand) = strip_index(inner, "X") {
+                        return inst2(AddressingMode::IndexedIndirect, operand);
+                    }
+                    if name == "JMP" {
+                        return inst2(AddressingMode::Indirect, inner);
+                    }
+                } else if let Some(index) = rest.strip_prefix(',') {
+                    if index.trim().eq_ignore_ascii_case("Y") {
+                        return inst2(AddressingMode::IndirectIndexed, inner);
+                    }
+                }
+            }
+        }
+        let (mode, operand) = if let Some(operand) = strip_index(operand, "X") {
+            (AddressingMode::AbsoluteX, operand)
+        } else if let Some(operand) = strip_index(operand, "Y") {
+            (AddressingMode::AbsoluteY, operand)
+        } else {
+            (AddressingMode::Absolute, operand)
+        };
+        let (size, operand) = split_operand_size(operand);
+        let mode = match size {
+            OperandSize::ZeroPage => match to_zero_page(mode) {
+                Some(mode) => mode,
+                None => return Err(NesError::AssemblerFailure(format!("no zero page form: {}", s))),
+            },
+            _ => mode,
+        };
+        Ok(AsmLine::Inst2 { name, mode, operand: operand.to_string(), size })
+    }
+}
+
+fn anonymous_label(index: usize) -> String {
+    format!("__anon_{}", index)
+}
+
+// Renames the local and anonymous label references in an expression.
+// `anon_before` is the number of anonymous labels defined above it.
+fn resolve_local_refs(s: &str, scope: &str, anon_before: usize, anon_total: usize) -> Result<String, NesError> {
+    let chars = s.chars().collect::<Vec<_>>();
+    let mut res = String::new();
+    let mut quote = None;
+    let mut i = 0;
+    while i < chars.len() {
+        let c = chars[i];
+        match quote {
+            Some(q) => {
+                if c == q {
+                    quote = None;
+                }
+                res.push(c);
+                i += 1;
+            }
+            None if c == '"' || c == '\'' => {
+                quote = Some(c);
+                res.push(c);
+                i += 1;
+            }
+            None if c == ':' => {
+                let dir = chars.get(i + 1).copied().filter(|&c| c == '+' || c == '-');
+                let Some(dir) = dir else {
+                    bail!(NesError::AssemblerFailure(format!("illegal anonymous label reference: {}", s)));
+                };
+                let count = chars[i + 1..].iter().take_while(|&&c| c == dir).count();
+                let index = match dir {
+                    '-' => anon_before.checked_sub(count),
+                    _ => Some(anon_before + count - 1).filter(|&index| index < anon_total),
+                };
+                match index {
+                    Some(index) => res.push_str(&anonymous_label(index)),
+                    None => bail!(NesError::AssemblerFailure(format!("no anonymous label for: {}", s))),
+                }
+                i += 1 + count;
+            }
+            None if c == '@' && !chars[..i].last().is_some_and(|&c| c.is_ascii_alphanumeric() || c == '_' || c == '.' || c == '@') => {
+                res.push_str(scope);
+                res.push(c);
+                i += 1;
+            }
+            None => {
+                res.push(c);
+                i += 1;
+            }
+        }
+    }
+    Ok(res)
+}
+
+// Gives local and anonymous labels unique names before assembling:
+// `@loop` after the label `main:` becomes `main@loop`, and the n-th `:`
+// label becomes `__anon_n`, with `:-`/`:+` references pointing to the
+// previous/next one (`:--`/`:++` to the one before/after that).
+fn resolve_local_labels(lines: &[AsmLine]) -> Result<Vec<AsmLine>, NesError> {
+    let anon_total = lines.iter().filter(|line| matches!(line, AsmLine::Label{name} if name.is_empty())).count();
+    let mut anon_before = 0;
+    let mut scope = String::new();
+    let mut res = vec![];
+    for line in lines {
+        res.push(match line {
+            AsmLine::Label{name} if name.is_empty() => {
+                anon_before += 1;
+                AsmLine::Label{name: anonymous_label(anon_before - 1)}
+            },
+            AsmLine::Label{name} if name.starts_with('@') => AsmLine::Label{name: format!("{}{}", scope, name)},
+            AsmLine::Label{name} => {
+                scope = name.clone();
+                line.clone()
+            },
+            line => line.map_exprs(|s| resolve_local_refs(s, &scope, anon_before, anon_total))?,
+        });
+    }
+    Ok(res)
+}
+
+fn is_symbol(s: &str) -> bool {
+    let mut chars = s.chars();
+    match chars.next() {
+        Some(c) if c.is_ascii_alphabetic() || c == '_' => chars.all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '.'),
+        _ => false,
+    }
+}
+
+// Splits the `z:` / `a:` size override off an operand.
+fn split_operand_size(s: &str) -> (OperandSize, &str) {
+    if let Some((prefix, rest)) = s.split_once(':') {
+        match prefix.trim() {
+            "z" | "Z" => return (OperandSize::ZeroPage, rest.trim()),
+            "a" | "A" => return (OperandSize::Absolute, rest.trim()),
+            _ => {}
+        }
+    }
+    (OperandSize::Auto, s)
+}
+
+// Whether `name` is an instruction, in any case and with or without an
+// opcode suffix.
+pub fn is_mnemonic(name: &str) -> bool {
+    let name = name.to_uppercase();
+    let base = name.split('.').next().unwrap_or_default();
+    MNEMONIC_ALIASES.iter().any(|(alias, _)| *alias == base)
+        || INST_FACTORIES_BY_NAME_MODE.keys().any(|(n, _)| n == base)
+}
+
+// Splits `NOP.1A` into the mnemonic and the opcode.
+fn split_opcode_suffix(name: &str) -> Option<(&str, u8)> {
+    let (base, opcode) = name.split_once('.')?;
+    let opcode = u8::from_str_radix(opcode, 16).ok()?;
+    Some((base, opcode))
+}
+
+fn to_zero_page(mode: AddressingMode) -> Option<AddressingMode> {
+    match mode {
+        AddressingMode::Absolute => Some(AddressingMode::ZeroPage),
+        AddressingMode::AbsoluteX => Some(AddressingMode::ZeroPageX),
+        AddressingMode::AbsoluteY => Some(AddressingMode::ZeroPageY),
+        _ => None,
+    }
+}
+
+// The zero page form of an absolute instruction, if the cpu has one.
+// `LDA abs,Y` for example only exists as absolute.
+fn zero_page_mode(name: &str, mode: AddressingMode) -> Option<AddressingMode> {
+    let zero_page = to_zero_page(mode)?;
+    INST_FACTORIES_BY_NAME_MODE
+        .contains_key(&(name.to_string(), zero_page))
+        .then_some(zero_page)
+}
+
+// Splits `expr,X` into `expr` when the trailing index register matches,
+// ignoring commas inside parentheses and character literals.
+fn strip_index<'a>(s: &'a str, register: &str) -> Option<&'a str> {
+    let mut depth = 0;
+    let mut in_char = false;
+    let mut split = None;
+    for (i, c) in s.char_indices() {
+        match c {
+            '\'' => in_char = !in_char,
+            '(' if !in_char => depth += 1,
+            ')' if !in_char => depth -= 1,
+            ',' if !in_char && depth == 0 => split = Some(i),
+            _ => {}
+        }
+    }
+    let i = split?;
+    if s[i + 1..].trim().eq_ignore_ascii_case(register) {
+        Some(s[..i].trim())
+    } else {
+        None
+    }
+}
+
+// Returns the index of the parenthesis closing the one at the start of `s`.
+fn find_closing_paren(s: &str) -> Option<usize> {
+    let mut depth = 0;
+    for (i, c) in s.char_indices() {
+        match c {
+            '(' => depth += 1,
+            ')' => {
+                depth -= 1;
+                if depth == 0 {
+                    return Some(i);
+                }
+            }
+            _ => {}
+        }
+    }
+    None
+}
+
+fn parse_string_literal(s: &str) -> Option<String> {
+    if s.len() >= 2 && s.starts_with('"') && s.ends_with('"') {
+        Some(s[1..s.len() - 1].to_string())
+    } else {
+        None
+    }
+}
+
+// The bytes of an asm6 `.hex 0a0b 0c` directive.
+fn parse_hex(s: &str) -> Option<Vec<u8>> {
+    let digits = s.split_whitespace().collect::<String>();
+    if digits.is_empty() || digits.len() % 2 != 0 || !digits.is_ascii() {
+        return None;
+    }
+    (0..digits.len()).step_by(2).map(|i| u8::from_str_radix(&digits[i..i + 2], 16).ok()).collect()
+}
+
+// Splits a comma separated operand list, keeping commas inside string literals.
+pub(super) fn split_operands(s: &str) -> Vec<String> {
+    let mut values = vec![];
+    let mut cur = String::new();
+    let mut in_string = false;
+    for c in s.chars() {
+        match c {
+            '"' => {
+                in_string = !in_string;
+                cur.push(c);
+            }
+            ',' if !in_string => {
+                values.push(cur.trim().to_string());
+                cur.clear();
+            }
+            _ => cur.push(c),
+        }
+    }
+    if !cur.trim().is_empty() || !values.is_empty() {
+        values.push(cur.trim().to_string());
+    }
+    values
+}
+
+// Finds the comment start, ignoring semicolons inside string literals.
+pub(super) fn find_comment(s: &str) -> Option<usize> {
+    let mut in_string = false;
+    for (i, c) in s.char_indices() {
+        match c {
+            '"' => in_string = !in_string,
+            ';' if !in_string => return Some(i),
+            _ => {}
+        }
+    }
+    None
+}
+
+impl FromStr for AsmLine {
+    type Err = NesError;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        let mut s: &str = s;
+        if let Some(i) = find_comment(s) {
+            s = s[..i].trim();
+        } else {
+            s = s.trim();
+        }
+        if s.is_empty() {
+            return Ok(AsmLine::Empty);
+        }
+        if s.starts_with('.') {
+            return AsmLine::parse_directive(s);
+        }
+        if s.ends_with(":") {
+            let label = s[..s.len() - 1].to_string();
+            return Ok(AsmLine::Label{name: label});
+        }
+        if let Some(i) = s.find('=') {
+            let name = s[..i].trim();
+            if is_symbol(name) {
+                return Ok(AsmLine::Const{name: name.to_string(), value: s[i + 1..].trim().to_string()});
+            }
+        }
+        AsmLine::parse_instruction(s)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::assembler::MemoryLayout;
+
+    fn assemble(start_addr: u16, source: &str) -> Vec<u8> {
+        let lines = source
+            .lines()
+            .map(|s| s.parse::<AsmLine>())
+            .collect::<std::result::Result<Vec<AsmLine>, NesError>>()
+            .unwrap();
+        Assembler::new(start_addr).assemble(&lines).unwrap().to_vec()
+    }
+
+    #[test]
+    fn test_data_directives() {
+        let bytes = assemble(0x8000, "
+            .byte $01, 2, \"a;b\" ; comment
+            .db 3
+            .word $1234, table
+            .dw 5
+            table:
+            .res 2
+            .res 2, $ff
+        ");
+        assert_eq!(bytes, vec![1, 2, b'a', b';', b'b', 3, 0x34, 0x12, 0x0c, 0x80, 5, 0, 0, 0, 0xff, 0xff]);
+    }
+
+    #[test]
+    fn test_org() {
+        let bytes = assemble(0x8000, "
+            jmp reset
+            .org $8005
+            reset:
+            rts
+        ");
+        assert_eq!(bytes, vec![0x4c, 0x05, 0x80, 0, 0, 0x60]);
+        let lines = vec![".org $7000".parse::<AsmLine>().unwrap()];
+        assert!(Assembler::new(0x8000).assemble(&lines).is_err());
+    }
+
+    #[test]
+    fn test_constants_and_expressions() {
+        let bytes = assemble(0x8000, "
+            PPUCTRL = $2000
+            FLAGS = %10000000 >> 7
+            OFFSET = table - *
+            lda #<table
+            ldx #>table
+            sta PPUCTRL + 1
+            lda table+1,x
+            lda (ptr),y
+            lda (ptr, x)
+            ldy #'A' | $80
+            .byte OFFSET, -1, FLAGS
+            .word table * 2 - $8000
+            table:
+            ptr = $10
+        ");
+        assert_eq!(bytes, vec![
+            0xa9, 0x15, 0xa2, 0x80, 0x8d, 0x01, 0x20, 0xbd, 0x16, 0x80, 0xb1, 0x10, 0xa1, 0x10,
+            0xa0, 0xc1, 0x15, 0xff, 0x01, 0x2a, 0x80,
+        ]);
+    }
+
+    #[test]
+    fn test_operand_size() {
+        let bytes = assemble(0x8000, "
+            lda var
+            ldx var,y
+            lda var,y
+            lda a:var
+            sta z:$0300 & $ff,x
+            lda table,x
+            lda (var),y
+            var = $10
+            table:
+        ");
+        assert_eq!(bytes, vec![
+            0xa5, 0x10, 0xb6, 0x10, 0xb9, 0x10, 0x00, 0xad, 0x10, 0x00, 0x95, 0x00, 0xbd, 0x11,
+            0x80, 0xb1, 0x10,
+        ]);
+        // forward labels that end up in the zero page
+        assert_eq!(assemble(0x0000, "lda later\nlater:\n.byte 1"), vec![0xa5, 0x02, 0x01]);
+        // a label that only leaves the zero page once its reference is widened
+        let bytes = assemble(0x00fd, "lda later\nlater:\n.byte 1");
+        assert_eq!(bytes, vec![0xa5, 0xff, 0x01]);
+        let bytes = assemble(0x00fe, "lda later\nlater:\n.byte 1");
+        assert_eq!(bytes, vec![0xad, 0x01, 0x01, 0x01]);
+    }
+
+    #[test]
+    fn test_local_labels() {
+        let bytes = assemble(0x8000, "
+            first:
+                ldx #2
+            @loop:
+                dex
+                bne @loop
+                jmp second
+            second:
+                ldy #2
+            @loop:
+                dey
+                bne @loop
+                jmp first@loop
+        ");
+        assert_eq!(bytes, vec![
+            0xa2, 0x02, 0xca, 0xd0, 0xfd, 0x4c, 0x08, 0x80,
+            0xa0, 0x02, 0x88, 0xd0, 0xfd, 0x4c, 0x02, 0x80,
+        ]);
+        // `@` labels must be unique within their scope only
+        let lines = vec!["a:".parse::<AsmLine>().unwrap(), "@x:".parse().unwrap(), "@x:".parse().unwrap()];
+        assert!(Assembler::new(0x8000).assemble(&lines).is_err());
+    }
+
+    #[test]
+    fn test_anonymous_labels() {
+        let bytes = assemble(0x8000, "
+            :
+                dex
+                bne :-
+                beq :++
+            :
+                nop
+            :
+                jmp :--
+        ");
+        assert_eq!(bytes, vec![0xca, 0xd0, 0xfd, 0xf0, 0x01, 0xea, 0x4c, 0x05, 0x80]);
+        let lines = vec!["bne :-".parse::<AsmLine>().unwrap()];
+        assert!(Assembler::new(0x8000).assemble(&lines).is_err());
+        let lines = vec![":".parse::<AsmLine>().unwrap(), "bne :+".parse().unwrap()];
+        assert!(Assembler::new(0x8000).assemble(&lines).is_err());
+    }
+
+    #[test]
+    fn test_link() {
+        let lines = "
+            .segment \"ZEROPAGE\"
+            counter:
+            .res 1
+            .segment \"RODATA\"
+            message:
+            .byte \"HI\"
+            .segment \"CODE\"
+            reset:
+                inc counter
+                lda message
+            nmi:
+                rti
+            .segment \"VECTORS\"
+            .word nmi, reset, nmi
+            .segment \"CHARS\"
+            .byte $11
+        ".lines().map(|s| s.parse::<AsmLine>()).collect::<std::result::Result<Vec<AsmLine>, NesError>>().unwrap();
+        let image = Assembler::with_layout(MemoryLayout::default()).link(&lines).unwrap();
+        let path = std::env::temp_dir().join("nes_assembler_test_link.nes");
+        std::fs::write(&path, &image).unwrap();
+        let nes = crate::nes_format::read_nes_file(&path.to_string_lossy()).unwrap();
+        assert_eq!(nes.header.prg_rom_size, 2);
+        assert_eq!(nes.header.chr_rom_size, 1);
+        assert_eq!(nes.prg_rom[0..6], [0xe6, 0x00, 0xad, 0x06, 0x80, 0x40]);
+        assert_eq!(nes.prg_rom[6..9], [b'H', b'I', 0xff]);
+        assert_eq!(nes.prg_rom[0x7ffa..], [0x05, 0x80, 0x00, 0x80, 0x05, 0x80]);
+        assert_eq!(nes.chr_rom[0..2], [0x11, 0x00]);
+        assert!(Assembler::new(0x8000).assemble(&lines).is_err());
+        let lines = vec![".segment \"NOPE\"".parse::<AsmLine>().unwrap()];
+        assert!(Assembler::with_layout(MemoryLayout::default()).link(&lines).is_err());
+        let lines = vec![".segment \"VECTORS\"".parse::<AsmLine>().unwrap(), ".res 7".parse().unwrap()];
+        assert!(Assembler::with_layout(MemoryLayout::default()).link(&lines).is_err());
+        // only space can be reserved in RAM
+        let lines = vec![".segment \"BSS\"".parse::<AsmLine>().unwrap(), ".byte 1".parse().unwrap()];
+        let report = Assembler::with_layout(MemoryLayout::default()).link(&lines).unwrap_err();
+        assert!(report.to_string().contains("segment BSS is in area RAM"));
+    }
+
+    #[test]
+    fn test_diagnostics() {
+        let source = ["start:", "  lda missing", "  foo #1", "  jmp (start),y", "  bne far", "  .res 200", "far:"];
+        let lines = source.iter().map(|s| s.parse::<AsmLine>().unwrap()).collect::<Vec<_>>();
+        let locations = source.iter().enumerate().map(|(i, text)| SourceLine {
+            file: "main.asm".to_string(),
+            line: i + 1,
+            text: text.to_string(),
+        }).collect::<Vec<_>>();
+        let report = Assembler::new(0x8000).with_locations(&locations).assemble(&lines).unwrap_err();
+        let NesError::AssemblerDiagnostics(diagnostics) = report.current_context() else {
+            panic!("expected diagnostics: {:?}", report);
+        };
+        let diagnostics = diagnostics.iter().map(|d| d.to_string()).collect::<Vec<_>>();
+        assert_eq!(diagnostics, vec![
+            "main.asm:2:7: undefined symbol: missing",
+            "main.asm:3:3: unknown mnemonic: FOO",
+            "main.asm:4:3: JMP does not support IndirectIndexed addressing",
+            "main.asm:5:7: branch target far is 200 bytes away, beyond -128..127",
+        ]);
+        let lines = vec!["x:".parse::<AsmLine>().unwrap(), "x:".parse().unwrap(), ".org 0".parse().unwrap()];
+        let report = Assembler::new(0x8000).assemble(&lines).unwrap_err();
+        assert!(matches!(report.current_context(), NesError::AssemblerDiagnostics(d) if d.len() == 2 && d[0].line == 2));
+    }
+
+    #[test]
+    fn test_unofficial_opcodes() {
+        let source = "
+            slo $10
+            isc $1234,x
+            dcm ($10),y
+            las $1234,y
+            sbx #1
+            lax var
+            nop.1a
+            sbc.eb #1
+            dop.04 var
+            nop.80 #1
+            var = $20
+        ";
+        let lines = source.lines().map(|s| s.parse::<AsmLine>().unwrap()).collect::<Vec<_>>();
+        let bytes = Assembler::new(0x8000).allow_unofficial().assemble(&lines).unwrap().to_vec();
+        assert_eq!(bytes, vec![
+            0x07, 0x10, 0xff, 0x34, 0x12, 0xd3, 0x10, 0xbb, 0x34, 0x12, 0xcb, 0x01, 0xa7, 0x20,
+            0x1a, 0xeb, 0x01, 0x04, 0x20, 0x80, 0x01,
+        ]);
+        let report = Assembler::new(0x8000).assemble(&lines).unwrap_err();
+        assert!(matches!(report.current_context(), NesError::AssemblerDiagnostics(d) if d.len() == 10));
+        // official opcodes are always accepted
+        assert_eq!(assemble(0x8000, "nop.ea\nsbc #1\nnop"), vec![0xea, 0xe9, 0x01, 0xea]);
+        assert!("nop.a9".parse::<AsmLine>().is_err());
+        assert!("nop.80".parse::<AsmLine>().is_err());
+        assert!("nop.1a #1".parse::<AsmLine>().is_err());
+        assert!("nop.zz".parse::<AsmLine>().is_err());
+    }
+
+    #[test]
+    fn test_expression_errors() {
+        let lines = vec!["lda #$100".parse::<AsmLine>().unwrap()];
+        assert!(Assembler::new(0x8000).assemble(&lines).is_err());
+        let lines = vec!["lda missing".parse::<AsmLine>().unwrap()];
+        assert!(Assembler::new(0x8000).assemble(&lines).is_err());
+        let lines = vec!["A = B".parse::<AsmLine>().unwrap(), "B = A".parse::<AsmLine>().unwrap(), ".byte A".parse::<AsmLine>().unwrap()];
+        assert!(Assembler::new(0x8000).assemble(&lines).is_err());
+    }
+}
@@ -0,0 +1,170 @@
+// Syntax of ca65 and asm6 sources that maps directly onto ours, applied to
+// every line before the preprocessor looks at it.
+
+use super::assemble::is_mnemonic;
+
+// The dialect of a source. ca65 syntax doesn't clash with ours and is
+// always accepted, asm6 directives without a dot, labels without a colon
+// and `EQU` only in asm6 sources, where they can't be taken for a symbol.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum Syntax {
+    #[default]
+    Native,
+    Asm6,
+}
+
+// asm6 accepts its directives without the leading dot.
+const DOTLESS_DIRECTIVES: &[&str] = &[
+    "org", "pad", "db", "dw", "byte", "word", "dsb", "dsw", "hex", "incbin", "include", "enum",
+    "ende", "if", "ifdef", "ifndef", "else", "endif", "macro", "endm", "rept", "endr",
+];
+
+fn is_symbol_char(c: char) -> bool {
+    c.is_ascii_alphanumeric() || c == '_' || c == '.' || c == '@'
+}
+
+fn is_label(s: &str) -> bool {
+    s.chars()
+        .next()
+        .is_some_and(|c| c.is_ascii_alphabetic() || c == '_' || c == '@')
+        && s.chars().all(is_symbol_char)
+}
+
+fn is_directive(word: &str) -> bool {
+    let word = word.to_lowercase();
+    match word.strip_prefix('.') {
+        Some(name) => name.starts_with(|c: char| c.is_ascii_alphabetic()),
+        None => DOTLESS_DIRECTIVES.contains(&word.as_str()),
+    }
+}
+
+// ca65 scoped names: `outer::inner` is `outer.inner` and the leading `::`
+// of a global reference is dropped.
+fn replace_scope_refs(line: &str) -> String {
+    let mut res = String::new();
+    let mut quote = None;
+    let mut chars = line.chars().peekable();
+    while let Some(c) = chars.next() {
+        match quote {
+            Some(q) if c == q => quote = None,
+            Some(_) => {}
+            None if c == '"' || c == '\'' => quote = Some(c),
+            None if c == ':' && chars.peek() == Some(&':') => {
+                chars.next();
+                if res.ends_with(is_symbol_char) {
+                    res.push('.');
+                }
+                continue;
+            }
+            None => {}
+        }
+        res.push(c);
+    }
+    res
+}
+
+// Splits the label off a line that also holds a statement: `loop: dex`,
+// and asm6 labels without a colon before a directive or an instruction,
+// `buttons .dsb 1` and `loop dex`. A macro call after a label without a
+// colon is not recognised.
+fn split_label(line: &str, syntax: Syntax) -> (Option<&str>, &str) {
+    let (word, rest) = match line.find(char::is_whitespace) {
+        Some(i) => (&line[..i], line[i..].trim()),
+        None => (line, ""),
+    };
+    if let Some(label) = word.strip_suffix(':') {
+        if label.is_empty() || is_label(label) {
+            return (Some(label), rest);
+        }
+    }
+    if syntax != Syntax::Asm6 {
+        return (None, line);
+    }
+    let next = rest.split_whitespace().next().unwrap_or("");
+    let statement = is_directive(next) || is_mnemonic(next);
+    if is_label(word) && !is_directive(word) && !is_mnemonic(word) && statement {
+        return (Some(word), rest);
+    }
+    (None, line)
+}
+
+/**
+Rewrites a line without its comment into the label it defines, if any,
+and the statement that follows in our syntax:
+
+```text
+loop: dex           =>  loop:   dex
+buttons dsb 1       =>  buttons .dsb 1
+SPEED EQU 2         =>          SPEED = 2
+jsr Player::update  =>          jsr Player.update
+```
+*/
+pub fn normalize(line: &str, syntax: Syntax) -> (Option<String>, String) {
+    let line = replace_scope_refs(line);
+    let mut words = line.split_whitespace();
+    if let (Syntax::Asm6, Some(name), Some(equ)) = (syntax, words.next(), words.next()) {
+        if equ.eq_ignore_ascii_case("equ") && is_label(name) {
+            let value = line.trim_start()[name.len()..].trim_start()[equ.len()..].trim();
+            return (None, format!("{} = {}", name, value));
+        }
+    }
+    let (label, statement) = split_label(&line, syntax);
+    let mut words = statement.split_whitespace();
+    let first = words.next().unwrap_or("");
+    // `pad = 4` defines a symbol that happens to be named like a directive
+    let defines = words.next() == Some("=");
+    let dotless = DOTLESS_DIRECTIVES.contains(&first.to_lowercase().as_str());
+    let statement = match syntax == Syntax::Asm6 && dotless && !defines {
+        true => format!(".{}", statement),
+        false => statement.to_string(),
+    };
+    (label.map(str::to_string), statement)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    // `label|statement`, with `-` for no label
+    fn normalized(line: &str, syntax: Syntax) -> String {
+        let (label, statement) = normalize(line, syntax);
+        format!("{}|{}", label.as_deref().unwrap_or("-"), statement)
+    }
+
+    #[test]
+    fn test_normalize() {
+        let native = |line: &str| normalized(line, Syntax::Native);
+        assert_eq!(native("loop:"), "loop|");
+        assert_eq!(native("loop: dex"), "loop|dex");
+        assert_eq!(native(": bne :-"), "|bne :-");
+        assert_eq!(native("jsr Player::update"), "-|jsr Player.update");
+        assert_eq!(native("lda ::global"), "-|lda global");
+        assert_eq!(native(".byte \"a::b\""), "-|.byte \"a::b\"");
+        assert_eq!(native("lda a:$10"), "-|lda a:$10");
+        assert_eq!(native(".segment \"CODE\""), "-|.segment \"CODE\"");
+        assert_eq!(native("x = 1"), "-|x = 1");
+        // asm6 syntax is left alone
+        assert_eq!(native("pad = 4"), "-|pad = 4");
+        assert_eq!(native("org: lda org"), "org|lda org");
+        assert_eq!(native("loop lda #0"), "-|loop lda #0");
+        assert_eq!(native("SPEED equ 2"), "-|SPEED equ 2");
+    }
+
+    #[test]
+    fn test_normalize_asm6() {
+        let asm6 = |line: &str| normalized(line, Syntax::Asm6);
+        assert_eq!(asm6("loop: dex"), "loop|dex");
+        assert_eq!(asm6("buttons .res 1"), "buttons|.res 1");
+        assert_eq!(asm6("buttons dsb 1"), "buttons|.dsb 1");
+        assert_eq!(asm6("loop lda #0"), "loop|lda #0");
+        assert_eq!(asm6("wait BIT $2002"), "wait|BIT $2002");
+        assert_eq!(asm6("lda loop"), "-|lda loop");
+        assert_eq!(asm6("asl a"), "-|asl a");
+        assert_eq!(asm6("draw sprite, 1"), "-|draw sprite, 1");
+        assert_eq!(asm6("ENUM $0300"), "-|.ENUM $0300");
+        assert_eq!(asm6("SPEED equ 2 + 1"), "-|SPEED = 2 + 1");
+        assert_eq!(asm6("pad = 4"), "-|pad = 4");
+        assert_eq!(asm6("org equ 4"), "-|org = 4");
+        assert_eq!(asm6("hex: db hex"), "hex|.db hex");
+    }
+}
@@ -0,0 +1,117 @@
+use std::fmt;
+
+use error_stack::{AttachmentKind, FrameKind, Report};
+
+use super::preprocessor::SourceLine;
+use crate::error::NesError;
+
+// The part of a source line an error is about, attached to assembler
+// errors so that diagnostics can point at its column.
+#[derive(Debug)]
+pub struct Subject(pub String);
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Diagnostic {
+    pub file: String,
+    pub line: usize,
+    pub column: usize,
+    pub message: String,
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}:{}:{}: {}",
+            self.file, self.line, self.column, self.message
+        )
+    }
+}
+
+// The reason without the error's generic prefix, followed by any
+// printable attachments such as the file that could not be read.
+fn reason(report: &Report<NesError>) -> String {
+    let mut reason = match report.current_context() {
+        NesError::AssemblerFailure(message) | NesError::InstNotFound(message) => message.clone(),
+        error => error.to_string(),
+    };
+    for frame in report.frames() {
+        if let FrameKind::Attachment(AttachmentKind::Printable(attachment)) = frame.kind() {
+            reason.push_str(&format!(": {}", attachment));
+        }
+    }
+    reason
+}
+
+fn column(text: &str, subject: Option<&str>) -> usize {
+    let found = subject.and_then(|subject| text.to_lowercase().find(&subject.to_lowercase()));
+    match found {
+        Some(i) => i + 1,
+        None => text.len() - text.trim_start().len() + 1,
+    }
+}
+
+impl Diagnostic {
+    // A diagnostic for `report` raised on line `idx` of the program, at
+    // `location` when the line's origin is known.
+    pub fn new(report: &Report<NesError>, idx: usize, location: Option<&SourceLine>) -> Self {
+        let subject = report
+            .frames()
+            .filter_map(|frame| frame.downcast_ref::<Subject>())
+            .last()
+            .map(|subject| subject.0.as_str());
+        match location {
+            Some(location) => Diagnostic {
+                file: location.file.clone(),
+                line: location.line,
+                column: column(&location.text, subject),
+                message: reason(report),
+            },
+            None => Diagnostic {
+                file: "<input>".to_string(),
+                line: idx + 1,
+                column: 1,
+                message: reason(report),
+            },
+        }
+    }
+}
+
+// Turns collected diagnostics into the error returned to the caller.
+pub fn into_report(diagnostics: Vec<Diagnostic>) -> Report<NesError> {
+    Report::new(NesError::AssemblerDiagnostics(diagnostics))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_diagnostic() {
+        let location = SourceLine {
+            file: "main.asm".to_string(),
+            line: 3,
+            text: "    lda Missing,x".to_string(),
+        };
+        let report = Report::new(NesError::AssemblerFailure(
+            "undefined symbol: missing".to_string(),
+        ))
+        .attach(Subject("missing".to_string()));
+        let diagnostic = Diagnostic::new(&report, 2, Some(&location));
+        assert_eq!(
+            diagnostic.to_string(),
+            "main.asm:3:9: undefined symbol: missing"
+        );
+        let report = Report::new(NesError::Io).attach_printable("cannot read x.bin");
+        let diagnostic = Diagnostic::new(&report, 2, Some(&location));
+        assert_eq!(
+            diagnostic.to_string(),
+            "main.asm:3:5: IO error: cannot read x.bin"
+        );
+        let diagnostic = Diagnostic::new(&report, 2, None);
+        assert_eq!(
+            diagnostic.to_string(),
+            "<input>:3:1: IO error: cannot read x.bin"
+        );
+    }
+}
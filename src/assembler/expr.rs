@@ -0,0 +1,490 @@
+use error_stack::{bail, Result};
+
+use crate::error::NesError;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum UnaryOp {
+    Neg,
+    Not,
+    LogicalNot,
+    LowByte,
+    HighByte,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum BinaryOp {
+    Mul,
+    Div,
+    Mod,
+    Add,
+    Sub,
+    Shl,
+    Shr,
+    And,
+    Xor,
+    Or,
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+    LogicalAnd,
+    LogicalOr,
+}
+
+impl BinaryOp {
+    fn precedence(&self) -> u8 {
+        match self {
+            BinaryOp::Mul | BinaryOp::Div | BinaryOp::Mod => 8,
+            BinaryOp::Add | BinaryOp::Sub => 7,
+            BinaryOp::Shl | BinaryOp::Shr => 6,
+            BinaryOp::And => 5,
+            BinaryOp::Xor => 4,
+            BinaryOp::Or => 3,
+            BinaryOp::Eq
+            | BinaryOp::Ne
+            | BinaryOp::Lt
+            | BinaryOp::Le
+            | BinaryOp::Gt
+            | BinaryOp::Ge => 2,
+            BinaryOp::LogicalAnd => 1,
+            BinaryOp::LogicalOr => 0,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Expr {
+    Number(i64),
+    Symbol(String),
+    // `*`, the address of the current line
+    CurrentAddr,
+    Unary(UnaryOp, Box<Expr>),
+    Binary(BinaryOp, Box<Expr>, Box<Expr>),
+    // `[addr]`, the byte at an address, only meaningful in the debugger
+    Memory(Box<Expr>),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Number(i64),
+    Symbol(String),
+    Op(&'static str),
+    LParen,
+    RParen,
+    LBracket,
+    RBracket,
+}
+
+fn is_symbol_start(c: char) -> bool {
+    c.is_ascii_alphabetic() || c == '_' || c == '.' || c == '@'
+}
+
+fn is_symbol_char(c: char) -> bool {
+    c.is_ascii_alphanumeric() || c == '_' || c == '.' || c == '@'
+}
+
+fn parse_number(digits: &str, radix: u32, s: &str) -> Result<i64, NesError> {
+    match i64::from_str_radix(digits, radix) {
+        Ok(n) => Ok(n),
+        Err(_) => bail!(NesError::AssemblerFailure(format!(
+            "illegal number in expression: {}",
+            s
+        ))),
+    }
+}
+
+fn tokenize(s: &str) -> Result<Vec<Token>, NesError> {
+    let chars = s.chars().collect::<Vec<_>>();
+    let mut tokens = vec![];
+    let mut i = 0;
+    let take_while = |i: &mut usize, f: fn(char) -> bool| {
+        let start = *i;
+        while *i < chars.len() && f(chars[*i]) {
+            *i += 1;
+        }
+        chars[start..*i].iter().collect::<String>()
+    };
+    while i < chars.len() {
+        let c = chars[i];
+        match c {
+            ' ' | '\t' => i += 1,
+            '$' => {
+                i += 1;
+                let digits = take_while(&mut i, |c| c.is_ascii_hexdigit());
+                tokens.push(Token::Number(parse_number(&digits, 16, s)?));
+            }
+            '%' if i + 1 < chars.len()
+                && matches!(chars[i + 1], '0' | '1')
+                && !matches!(
+                    tokens.last(),
+                    Some(Token::Number(_) | Token::Symbol(_) | Token::RParen | Token::RBracket)
+                ) =>
+            {
+                i += 1;
+                let digits = take_while(&mut i, |c| c == '0' || c == '1');
+                tokens.push(Token::Number(parse_number(&digits, 2, s)?));
+            }
+            '0'..='9' => {
+                let digits = take_while(&mut i, |c| c.is_ascii_digit());
+                tokens.push(Token::Number(parse_number(&digits, 10, s)?));
+            }
+            '\'' => {
+                if i + 2 < chars.len() && chars[i + 2] == '\'' {
+                    tokens.push(Token::Number(chars[i + 1] as i64));
+                    i += 3;
+                } else {
+                    bail!(NesError::AssemblerFailure(format!(
+                        "illegal character literal: {}",
+                        s
+                    )));
+                }
+            }
+            '(' => {
+                tokens.push(Token::LParen);
+                i += 1;
+            }
+            ')' => {
+                tokens.push(Token::RParen);
+                i += 1;
+            }
+            '[' => {
+                tokens.push(Token::LBracket);
+                i += 1;
+            }
+            ']' => {
+                tokens.push(Token::RBracket);
+                i += 1;
+            }
+            c if is_symbol_start(c) => {
+                let name = take_while(&mut i, is_symbol_char);
+                tokens.push(Token::Symbol(name));
+            }
+            _ => {
+                let rest = chars[i..].iter().collect::<String>();
+                let op = [
+                    "<<", ">>", "==", "!=", "<>", "<=", ">=", "&&", "||", "+", "-", "*", "/", "%",
+                    "&", "|", "^", "~", "!", "<", ">", "=",
+                ]
+                .into_iter()
+                .find(|op| rest.starts_with(op));
+                match op {
+                    Some(op) => {
+                        tokens.push(Token::Op(op));
+                        i += op.len();
+                    }
+                    None => bail!(NesError::AssemblerFailure(format!(
+                        "unexpected '{}' in expression: {}",
+                        c, s
+                    ))),
+                }
+            }
+        }
+    }
+    Ok(tokens)
+}
+
+struct Parser<'a> {
+    tokens: Vec<Token>,
+    pos: usize,
+    source: &'a str,
+}
+
+impl<'a> Parser<'a> {
+    fn error<T>(&self) -> Result<T, NesError> {
+        bail!(NesError::AssemblerFailure(format!(
+            "illegal expression: {}",
+            self.source
+        )))
+    }
+
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.pos)
+    }
+
+    fn next(&mut self) -> Option<Token> {
+        let token = self.tokens.get(self.pos).cloned();
+        self.pos += 1;
+        token
+    }
+
+    fn peek_binary_op(&self) -> Option<BinaryOp> {
+        match self.peek()? {
+            Token::Op(op) => Some(match *op {
+                "*" => BinaryOp::Mul,
+                "/" => BinaryOp::Div,
+                "%" => BinaryOp::Mod,
+                "+" => BinaryOp::Add,
+                "-" => BinaryOp::Sub,
+                "<<" => BinaryOp::Shl,
+                ">>" => BinaryOp::Shr,
+                "&" => BinaryOp::And,
+                "^" => BinaryOp::Xor,
+                "|" => BinaryOp::Or,
+                "==" | "=" => BinaryOp::Eq,
+                "!=" | "<>" => BinaryOp::Ne,
+                // `<` and `>` after an operand compare, before one they
+                // select a byte
+                "<" => BinaryOp::Lt,
+                "<=" => BinaryOp::Le,
+                ">" => BinaryOp::Gt,
+                ">=" => BinaryOp::Ge,
+                "&&" => BinaryOp::LogicalAnd,
+                "||" => BinaryOp::LogicalOr,
+                _ => return None,
+            }),
+            _ => None,
+        }
+    }
+
+    // precedence climbing
+    fn parse_binary(&mut self, min_precedence: u8) -> Result<Expr, NesError> {
+        let mut lhs = self.parse_unary()?;
+        while let Some(op) = self.peek_binary_op() {
+            if op.precedence() < min_precedence {
+                break;
+            }
+            self.pos += 1;
+            let rhs = self.parse_binary(op.precedence() + 1)?;
+            lhs = Expr::Binary(op, Box::new(lhs), Box::new(rhs));
+        }
+        Ok(lhs)
+    }
+
+    fn parse_unary(&mut self) -> Result<Expr, NesError> {
+        let op = match self.peek() {
+            Some(Token::Op("-")) => UnaryOp::Neg,
+            Some(Token::Op("~")) => UnaryOp::Not,
+            Some(Token::Op("!")) => UnaryOp::LogicalNot,
+            Some(Token::Op("<")) => UnaryOp::LowByte,
+            Some(Token::Op(">")) => UnaryOp::HighByte,
+            Some(Token::Op("+")) => {
+                self.pos += 1;
+                return self.parse_unary();
+            }
+            _ => return self.parse_primary(),
+        };
+        self.pos += 1;
+        Ok(Expr::Unary(op, Box::new(self.parse_unary()?)))
+    }
+
+    fn parse_primary(&mut self) -> Result<Expr, NesError> {
+        match self.next() {
+            Some(Token::Number(n)) => Ok(Expr::Number(n)),
+            Some(Token::Symbol(name)) => Ok(Expr::Symbol(name)),
+            Some(Token::Op("*")) => Ok(Expr::CurrentAddr),
+            Some(Token::LParen) => {
+                let expr = self.parse_binary(0)?;
+                match self.next() {
+                    Some(Token::RParen) => Ok(expr),
+                    _ => self.error(),
+                }
+            }
+            Some(Token::LBracket) => {
+                let expr = self.parse_binary(0)?;
+                match self.next() {
+                    Some(Token::RBracket) => Ok(Expr::Memory(Box::new(expr))),
+                    _ => self.error(),
+                }
+            }
+            _ => self.error(),
+        }
+    }
+}
+
+impl Expr {
+    pub fn parse(s: &str) -> Result<Expr, NesError> {
+        let mut parser = Parser {
+            tokens: tokenize(s)?,
+            pos: 0,
+            source: s,
+        };
+        let expr = parser.parse_binary(0)?;
+        if parser.pos != parser.tokens.len() {
+            return parser.error();
+        }
+        Ok(expr)
+    }
+
+    // The names of the symbols the expression refers to.
+    pub fn symbols(&self) -> Vec<&str> {
+        match self {
+            Expr::Number(_) | Expr::CurrentAddr => vec![],
+            Expr::Symbol(name) => vec![name.as_str()],
+            Expr::Unary(_, e) | Expr::Memory(e) => e.symbols(),
+            Expr::Binary(_, lhs, rhs) => [lhs.symbols(), rhs.symbols()].concat(),
+        }
+    }
+
+    // `lookup` resolves symbols, `pc` is the value of `*`.
+    pub fn eval<F>(&self, lookup: &F, pc: u16) -> Result<i64, NesError>
+    where
+        F: Fn(&str) -> Result<i64, NesError>,
+    {
+        self.eval_with_memory(lookup, pc, &|_| None)
+    }
+
+    // Like `eval`, with `read` giving the byte at an address for `[addr]`,
+    // or `None` where memory can't be read.
+    pub fn eval_with_memory<F, R>(&self, lookup: &F, pc: u16, read: &R) -> Result<i64, NesError>
+    where
+        F: Fn(&str) -> Result<i64, NesError>,
+        R: Fn(u16) -> Option<u8>,
+    {
+        Ok(match self {
+            Expr::Number(n) => *n,
+            Expr::Symbol(name) => lookup(name)?,
+            Expr::CurrentAddr => pc as i64,
+            Expr::Memory(e) => match read(e.eval_with_memory(lookup, pc, read)? as u16) {
+                Some(value) => value as i64,
+                None => bail!(NesError::AssemblerFailure(
+                    "memory can't be read in this expression".to_string()
+                )),
+            },
+            Expr::Unary(op, e) => {
+                let v = e.eval_with_memory(lookup, pc, read)?;
+                match op {
+                    UnaryOp::Neg => v.wrapping_neg(),
+                    UnaryOp::Not => !v,
+                    UnaryOp::LogicalNot => (v == 0) as i64,
+                    UnaryOp::LowByte => v & 0xff,
+                    UnaryOp::HighByte => (v >> 8) & 0xff,
+                }
+            }
+            Expr::Binary(op, lhs, rhs) => {
+                let l = lhs.eval_with_memory(lookup, pc, read)?;
+                let r = rhs.eval_with_memory(lookup, pc, read)?;
+                match op {
+                    BinaryOp::Mul => l.wrapping_mul(r),
+                    BinaryOp::Div | BinaryOp::Mod if r == 0 => {
+                        bail!(NesError::AssemblerFailure("division by zero".to_string()))
+                    }
+                    // only i64::MIN / -1 overflows
+                    BinaryOp::Div | BinaryOp::Mod => {
+                        let value = match op {
+                            BinaryOp::Div => l.checked_div(r),
+                            _ => l.checked_rem(r),
+                        };
+                        match value {
+                            Some(value) => value,
+                            None => {
+                                bail!(NesError::AssemblerFailure("division overflow".to_string()))
+                            }
+                        }
+                    }
+                    BinaryOp::Add => l.wrapping_add(r),
+                    BinaryOp::Sub => l.wrapping_sub(r),
+                    BinaryOp::Shl => l.wrapping_shl(r as u32),
+                    BinaryOp::Shr => l.wrapping_shr(r as u32),
+                    BinaryOp::And => l & r,
+                    BinaryOp::Xor => l ^ r,
+                    BinaryOp::Or => l | r,
+                    BinaryOp::Eq => (l == r) as i64,
+                    BinaryOp::Ne => (l != r) as i64,
+                    BinaryOp::Lt => (l < r) as i64,
+                    BinaryOp::Le => (l <= r) as i64,
+                    BinaryOp::Gt => (l > r) as i64,
+                    BinaryOp::Ge => (l >= r) as i64,
+                    BinaryOp::LogicalAnd => (l != 0 && r != 0) as i64,
+                    BinaryOp::LogicalOr => (l != 0 || r != 0) as i64,
+                }
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn eval(s: &str) -> i64 {
+        let lookup = |name: &str| match name {
+            "label" => Ok(0x1234),
+            "five" => Ok(5),
+            _ => bail!(NesError::AssemblerFailure(name.to_string())),
+        };
+        Expr::parse(s).unwrap().eval(&lookup, 0x8000).unwrap()
+    }
+
+    #[test]
+    fn test_literals() {
+        assert_eq!(eval("$1F"), 0x1f);
+        assert_eq!(eval("%1010"), 10);
+        assert_eq!(eval("42"), 42);
+        assert_eq!(eval("'A'"), 65);
+        assert_eq!(eval("*"), 0x8000);
+    }
+
+    #[test]
+    fn test_operators() {
+        assert_eq!(eval("1 + 2 * 3"), 7);
+        assert_eq!(eval("(1 + 2) * 3"), 9);
+        assert_eq!(eval("label+1"), 0x1235);
+        assert_eq!(eval("<label"), 0x34);
+        assert_eq!(eval(">label"), 0x12);
+        assert_eq!(eval(">(label + $100)"), 0x13);
+        assert_eq!(eval("1 << 4 | 1"), 17);
+        assert_eq!(eval("$FF & ~five"), 0xfa);
+        assert_eq!(eval("five % 3 ^ 3"), 1);
+        assert_eq!(eval("-five"), -5);
+        assert_eq!(eval("* + 3"), 0x8003);
+        assert_eq!(eval("2*label"), 0x2468);
+    }
+
+    #[test]
+    fn test_comparisons() {
+        assert_eq!(eval("five > 3"), 1);
+        assert_eq!(eval("five < 3"), 0);
+        assert_eq!(eval("five >= 5 && five <= 5"), 1);
+        assert_eq!(eval("five == 4 || five != 4"), 1);
+        assert_eq!(eval("five = 5"), 1);
+        assert_eq!(eval("!five"), 0);
+        assert_eq!(eval("1 + 1 == 2 & 3"), 1);
+        assert_eq!(eval(">label > 3"), 1);
+    }
+
+    #[test]
+    fn test_memory() {
+        let lookup = |_: &str| Ok(0);
+        let read = |addr: u16| Some((addr & 0xff) as u8);
+        let eval = |s: &str| {
+            Expr::parse(s)
+                .unwrap()
+                .eval_with_memory(&lookup, 0, &read)
+                .unwrap()
+        };
+        assert_eq!(eval("[$0312]"), 0x12);
+        assert_eq!(eval("[$0300 + 5] > 3 && [[$0301]] == 1"), 1);
+        assert_eq!(eval("[$10]%2"), 0);
+        assert_eq!(
+            Expr::parse("[ptr + 1] & mask == -x").unwrap().symbols(),
+            vec!["ptr", "mask", "x"]
+        );
+    }
+
+    #[test]
+    fn test_errors() {
+        assert!(Expr::parse("1 +").is_err());
+        assert!(Expr::parse("(1").is_err());
+        assert!(Expr::parse("1 2").is_err());
+        assert!(Expr::parse("#1").is_err());
+        let lookup = |_: &str| Ok(0);
+        assert!(Expr::parse("1/0").unwrap().eval(&lookup, 0).is_err());
+        assert!(Expr::parse("(1<<63)/-1").unwrap().eval(&lookup, 0).is_err());
+        assert!(Expr::parse("(1<<63)%-1").unwrap().eval(&lookup, 0).is_err());
+        assert_eq!(
+            Expr::parse("-(1<<63)").unwrap().eval(&lookup, 0).unwrap(),
+            i64::MIN
+        );
+        assert!(Expr::parse("[$10").is_err());
+        assert!(Expr::parse("[$10]").unwrap().eval(&lookup, 0).is_err());
+        assert!(Expr::parse("nope")
+            .unwrap()
+            .eval(
+                &|name: &str| bail!(NesError::AssemblerFailure(name.to_string())),
+                0
+            )
+            .is_err());
+    }
+}
@@ -0,0 +1,430 @@
+use error_stack::{bail, Result, ResultExt};
+
+use super::expr::Expr;
+use crate::error::NesError;
+use crate::io::read_file_lines;
+
+const PRG_BANK_SIZE: usize = 0x4000;
+const CHR_BANK_SIZE: usize = 0x2000;
+
+// An NROM-256 cartridge: 32KB PRG at $8000 with the vectors at $FFFA,
+// 8KB CHR, and the header generated from those sizes unless the HEADER
+// segment provides one.
+const NROM_LAYOUT: &str = "
+area ZP     start=$0000 size=$0100
+area RAM    start=$0200 size=$0600
+area HDR    start=$0000 size=$0010 out=header
+area PRG    start=$8000 size=$8000 fill=$FF out=prg
+area CHR    start=$0000 size=$2000 out=chr
+segment ZEROPAGE area=ZP
+segment BSS      area=RAM
+segment HEADER   area=HDR
+segment CODE     area=PRG
+segment RODATA   area=PRG
+segment VECTORS  area=PRG start=$FFFA
+segment CHARS    area=CHR
+";
+
+// Where the contents of a memory area go in the .nes file.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum AreaOutput {
+    None,
+    Header,
+    Prg,
+    Chr,
+}
+
+#[derive(Debug)]
+pub struct MemoryArea {
+    pub name: String,
+    pub start: u16,
+    pub size: u32,
+    pub fill: u8,
+    pub output: AreaOutput,
+}
+
+#[derive(Debug)]
+pub struct SegmentLayout {
+    pub name: String,
+    // index into `MemoryLayout::areas`
+    pub area: usize,
+    // fixed start address, otherwise placed after the previous segment
+    pub start: Option<u16>,
+}
+
+/**
+Describes where segments are placed, one declaration per line:
+
+```text
+area PRG start=$8000 size=$8000 fill=$FF out=prg
+segment CODE area=PRG
+segment VECTORS area=PRG start=$FFFA
+```
+
+Segments of an area are laid out in declaration order. Areas with
+`out=prg` / `out=chr` are concatenated into the PRG / CHR ROM of the .nes
+file, areas without `out` only reserve addresses.
+*/
+#[derive(Debug)]
+pub struct MemoryLayout {
+    pub areas: Vec<MemoryArea>,
+    pub segments: Vec<SegmentLayout>,
+}
+
+// Location counter and output of one segment while assembling.
+pub(super) struct Segment {
+    pub name: String,
+    pub start: u16,
+    pub addr: u16,
+    pub size: u32,
+    pub bytes: Vec<u8>,
+    // whether anything but `.res` or `.org` space was emitted
+    pub data: bool,
+}
+
+impl Segment {
+    pub fn new(name: &str, start: u16) -> Self {
+        Segment {
+            name: name.to_string(),
+            start,
+            addr: start,
+            size: 0,
+            bytes: vec![],
+            data: false,
+        }
+    }
+}
+
+fn parse_value(s: &str) -> Result<i64, NesError> {
+    let lookup = |name: &str| {
+        bail!(NesError::AssemblerFailure(format!(
+            "unexpected symbol in layout: {}",
+            name
+        )))
+    };
+    Expr::parse(s)?.eval(&lookup, 0)
+}
+
+fn parse_u16(s: &str) -> Result<u16, NesError> {
+    match parse_value(s)? {
+        v @ 0..=0xffff => Ok(v as u16),
+        _ => bail!(NesError::AssemblerFailure(format!(
+            "address out of range in layout: {}",
+            s
+        ))),
+    }
+}
+
+impl Default for MemoryLayout {
+    fn default() -> Self {
+        MemoryLayout::parse(NROM_LAYOUT).unwrap()
+    }
+}
+
+impl MemoryLayout {
+    pub fn parse(s: &str) -> Result<Self, NesError> {
+        let mut layout = MemoryLayout {
+            areas: vec![],
+            segments: vec![],
+        };
+        for line in s.lines() {
+            let line = match line.find('#') {
+                Some(i) => &line[..i],
+                None => line,
+            };
+            let mut words = line.split_whitespace();
+            let (Some(kind), Some(name)) = (words.next(), words.next()) else {
+                if line.trim().is_empty() {
+                    continue;
+                }
+                bail!(NesError::AssemblerFailure(format!(
+                    "illegal layout line: {}",
+                    line
+                )));
+            };
+            let mut start = None;
+            let mut size = None;
+            let mut fill = 0;
+            let mut output = AreaOutput::None;
+            let mut area = None;
+            for attr in words {
+                let Some((key, value)) = attr.split_once('=') else {
+                    bail!(NesError::AssemblerFailure(format!(
+                        "illegal layout attribute: {}",
+                        attr
+                    )));
+                };
+                match (kind, key) {
+                    (_, "start") => start = Some(parse_u16(value)?),
+                    ("area", "size") => size = Some(parse_value(value)?),
+                    ("area", "fill") => fill = parse_value(value)? as u8,
+                    ("area", "out") => {
+                        output = match value {
+                            "header" => AreaOutput::Header,
+                            "prg" => AreaOutput::Prg,
+                            "chr" => AreaOutput::Chr,
+                            _ => bail!(NesError::AssemblerFailure(format!(
+                                "illegal layout output: {}",
+                                value
+                            ))),
+                        }
+                    }
+                    ("segment", "area") => {
+                        match layout.areas.iter().position(|a| a.name == value) {
+                            Some(i) => area = Some(i),
+                            None => bail!(NesError::AssemblerFailure(format!(
+                                "unknown area: {}",
+                                value
+                            ))),
+                        }
+                    }
+                    _ => bail!(NesError::AssemblerFailure(format!(
+                        "illegal layout attribute: {}",
+                        attr
+                    ))),
+                }
+            }
+            match kind {
+                "area" => {
+                    let (Some(start), Some(size @ 1..=0x10000)) = (start, size) else {
+                        bail!(NesError::AssemblerFailure(format!(
+                            "area needs a start and a size: {}",
+                            line
+                        )));
+                    };
+                    if start as i64 + size > 0x10000 {
+                        bail!(NesError::AssemblerFailure(format!(
+                            "area ends past $FFFF: {}",
+                            line
+                        )));
+                    }
+                    layout.areas.push(MemoryArea {
+                        name: name.to_string(),
+                        start,
+                        size: size as u32,
+                        fill,
+                        output,
+                    });
+                }
+                "segment" => {
+                    let Some(area) = area else {
+                        bail!(NesError::AssemblerFailure(format!(
+                            "segment needs an area: {}",
+                            line
+                        )));
+                    };
+                    if layout.segments.iter().any(|s| s.name == name) {
+                        bail!(NesError::AssemblerFailure(format!(
+                            "duplicate segment: {}",
+                            name
+                        )));
+                    }
+                    layout.segments.push(SegmentLayout {
+                        name: name.to_string(),
+                        area,
+                        start,
+                    });
+                }
+                _ => bail!(NesError::AssemblerFailure(format!(
+                    "illegal layout line: {}",
+                    line
+                ))),
+            }
+        }
+        Ok(layout)
+    }
+
+    pub fn read(path: &str) -> Result<Self, NesError> {
+        let lines = read_file_lines(path)
+            .change_context(NesError::Io)
+            .attach_printable_lazy(|| format!("cannot read {}", path))?;
+        MemoryLayout::parse(&lines.join("\n"))
+    }
+
+    pub(super) fn new_segments(&self) -> Vec<Segment> {
+        self.segments
+            .iter()
+            .map(|s| Segment::new(&s.name, s.start.unwrap_or(self.areas[s.area].start)))
+            .collect()
+    }
+
+    // Lines before the first `.segment` go to CODE.
+    pub(super) fn default_segment(&self) -> usize {
+        self.segments
+            .iter()
+            .position(|s| s.name == "CODE")
+            .unwrap_or(0)
+    }
+
+    // Moves every segment to its address given the current segment sizes.
+    // Returns whether any segment moved.
+    pub(super) fn place(&self, segments: &mut [Segment]) -> Result<bool, NesError> {
+        let mut moved = false;
+        for (i, area) in self.areas.iter().enumerate() {
+            let mut cursor = area.start as u32;
+            let end = area.start as u32 + area.size;
+            for (layout, segment) in self.segments.iter().zip(segments.iter_mut()) {
+                if layout.area != i {
+                    continue;
+                }
+                let start = layout.start.map_or(cursor, |start| start as u32);
+                // an empty segment after the end of the area has no address
+                // and nothing to place, it keeps its start
+                if segment.size == 0 && start == end {
+                    continue;
+                }
+                if start < cursor {
+                    bail!(NesError::AssemblerFailure(format!(
+                        "segment {} overlaps the segment before it",
+                        layout.name
+                    )));
+                }
+                cursor = start + segment.size;
+                if cursor > end {
+                    bail!(NesError::AssemblerFailure(format!(
+                        "segment {} does not fit in area {}",
+                        layout.name, area.name
+                    )));
+                }
+                if segment.start != start as u16 {
+                    segment.start = start as u16;
+                    moved = true;
+                }
+            }
+        }
+        Ok(moved)
+    }
+
+    // Builds the .nes file from the assembled segments.
+    pub(super) fn build_image(&self, segments: &[Segment]) -> Result<Vec<u8>, NesError> {
+        let mut header = vec![];
+        let mut prg = vec![];
+        let mut chr = vec![];
+        for (i, area) in self.areas.iter().enumerate() {
+            let mut data = vec![area.fill; area.size as usize];
+            for (layout, segment) in self.segments.iter().zip(segments) {
+                if layout.area != i {
+                    continue;
+                }
+                // RAM is only reserved, anything else put there would be lost
+                if area.output == AreaOutput::None && segment.data {
+                    bail!(NesError::AssemblerFailure(format!(
+                        "segment {} is in area {} which is not in the image, only .res can reserve space there",
+                        layout.name, area.name)));
+                }
+                if segment.bytes.is_empty() {
+                    continue;
+                }
+                let offset = segment.start.checked_sub(area.start).map(usize::from);
+                let Some(range) = offset.map(|offset| offset..offset + segment.bytes.len()) else {
+                    bail!(NesError::AssemblerFailure(format!(
+                        "segment {} starts before area {}",
+                        layout.name, area.name
+                    )));
+                };
+                match data.get_mut(range) {
+                    Some(data) => data.copy_from_slice(&segment.bytes),
+                    None => bail!(NesError::AssemblerFailure(format!(
+                        "segment {} does not fit in area {}",
+                        layout.name, area.name
+                    ))),
+                }
+            }
+            match area.output {
+                AreaOutput::None => {}
+                AreaOutput::Header => header.extend(data),
+                AreaOutput::Prg => prg.extend(data),
+                AreaOutput::Chr => chr.extend(data),
+            }
+        }
+        if prg.is_empty() || prg.len() % PRG_BANK_SIZE != 0 || chr.len() % CHR_BANK_SIZE != 0 {
+            bail!(NesError::AssemblerFailure(format!(
+                "PRG size must be a non-zero multiple of 16KB and CHR size a multiple of 8KB, got {} and {} bytes",
+                prg.len(), chr.len())));
+        }
+        if header.iter().all(|&b| b == 0) {
+            header = vec![0; 16];
+            header[0..4].copy_from_slice(b"NES\x1a");
+            header[4] = (prg.len() / PRG_BANK_SIZE) as u8;
+            header[5] = (chr.len() / CHR_BANK_SIZE) as u8;
+        }
+        if header.len() != 16 || &header[0..4] != b"NES\x1a" {
+            bail!(NesError::AssemblerFailure(
+                "HEADER is not a 16 byte iNES header".to_string()
+            ));
+        }
+        Ok([header, prg, chr].concat())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        let layout = MemoryLayout::default();
+        assert_eq!(layout.areas.len(), 5);
+        assert_eq!(layout.segments[layout.default_segment()].name, "CODE");
+        let vectors = layout
+            .segments
+            .iter()
+            .find(|s| s.name == "VECTORS")
+            .unwrap();
+        assert_eq!(vectors.start, Some(0xfffa));
+        assert_eq!(layout.areas[vectors.area].output, AreaOutput::Prg);
+        assert!(MemoryLayout::parse("area A start=0").is_err());
+        assert!(MemoryLayout::parse("segment S area=NONE").is_err());
+        assert!(MemoryLayout::parse("area A start=0 size=1 out=rom").is_err());
+        assert!(
+            MemoryLayout::parse("area A start=0 size=1\nsegment S area=A\nsegment S area=A")
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn test_place() {
+        let layout = MemoryLayout::parse(
+            "
+            area PRG start=$8000 size=$100
+            segment CODE area=PRG
+            segment RODATA area=PRG
+            segment VECTORS area=PRG start=$80FA
+        ",
+        )
+        .unwrap();
+        let mut segments = layout.new_segments();
+        segments[0].size = 0x10;
+        assert!(layout.place(&mut segments).unwrap());
+        assert_eq!(segments[1].start, 0x8010);
+        assert!(!layout.place(&mut segments).unwrap());
+        segments[1].size = 0xf0;
+        assert!(layout.place(&mut segments).is_err());
+        segments[1].size = 0x10;
+        segments[2].size = 0x10;
+        assert!(layout.place(&mut segments).is_err());
+    }
+
+    #[test]
+    fn test_trailing_empty_segment() {
+        let layout = MemoryLayout::parse(
+            "
+            area PRG start=$C000 size=$4000 out=prg
+            segment CODE area=PRG
+            segment VECTORS area=PRG start=$FFFA
+            segment EXTRA area=PRG
+        ",
+        )
+        .unwrap();
+        let mut segments = layout.new_segments();
+        segments[1].size = 6;
+        segments[1].bytes = vec![1, 2, 3, 4, 5, 6];
+        layout.place(&mut segments).unwrap();
+        assert_eq!(segments[2].start, 0xc000);
+        let image = layout.build_image(&segments).unwrap();
+        assert_eq!(image[image.len() - 6..], [1, 2, 3, 4, 5, 6]);
+        // but a segment with something in it has to fit
+        segments[2].size = 1;
+        assert!(layout.place(&mut segments).is_err());
+    }
+}
@@ -0,0 +1,103 @@
+use super::assemble::Assembler;
+use super::preprocessor::SourceLine;
+
+const BYTES_PER_LINE: usize = 4;
+
+fn format_bytes(bytes: &[u8]) -> String {
+    bytes
+        .iter()
+        .map(|b| format!("{:02X}", b))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+// Address, emitted bytes and source text of every line, with the file
+// name whenever it changes. Lines emitting more than a few bytes
+// continue on the following lines.
+pub fn format_listing(assembler: &Assembler, locations: &[SourceLine]) -> String {
+    let mut res = String::new();
+    let mut file = None;
+    for ((addr, bytes), location) in assembler.line_output().zip(locations) {
+        if file != Some(&location.file) {
+            file = Some(&location.file);
+            res.push_str(&format!("; {}\n", location.file));
+        }
+        let mut chunks = bytes.chunks(BYTES_PER_LINE);
+        let first = chunks.next().unwrap_or_default();
+        res.push_str(&format!(
+            "{:5}  {:04X}  {:<11}  {}\n",
+            location.line,
+            addr,
+            format_bytes(first),
+            location.text.trim_end()
+        ));
+        for (i, chunk) in chunks.enumerate() {
+            let addr = addr.wrapping_add(((i + 1) * BYTES_PER_LINE) as u16);
+            res.push_str(&format!(
+                "{:5}  {:04X}  {}\n",
+                "",
+                addr,
+                format_bytes(chunk)
+            ));
+        }
+    }
+    res
+}
+
+// One `name = value` line per symbol, which the assembler can include
+// and the disassembler can import.
+pub fn format_symbols(symbols: &[(String, i64)]) -> String {
+    symbols
+        .iter()
+        .map(|(name, value)| match value {
+            0..=0xffff => format!("{} = ${:04X}\n", name, value),
+            _ => format!("{} = {}\n", name, value),
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::assembler::assemble::AsmLine;
+
+    #[test]
+    fn test_listing() {
+        let source = [
+            "start:",
+            "  lda #1 ; load",
+            ".byte 1, 2, 3, 4, 5",
+            "COUNT = -1",
+        ];
+        let lines = source
+            .iter()
+            .map(|s| s.parse::<AsmLine>().unwrap())
+            .collect::<Vec<_>>();
+        let locations = source
+            .iter()
+            .enumerate()
+            .map(|(i, text)| SourceLine {
+                file: "main.asm".to_string(),
+                line: i + 1,
+                text: text.to_string(),
+            })
+            .collect::<Vec<_>>();
+        let mut assembler = Assembler::new(0x8000);
+        assembler.assemble(&lines).unwrap();
+        assert_eq!(
+            format_listing(&assembler, &locations),
+            "\
+; main.asm
+    1  8000               start:
+    2  8000  A9 01          lda #1 ; load
+    3  8002  01 02 03 04  .byte 1, 2, 3, 4, 5
+       8006  05
+    4  8007               COUNT = -1
+"
+        );
+        assert_eq!(
+            format_symbols(&assembler.symbols()),
+            "COUNT = -1\nstart = $8000\n"
+        );
+    }
+}
@@ -0,0 +1,14 @@
+mod assemble;
+mod compat;
+mod diagnostic;
+mod expr;
+mod linker;
+mod listing;
+mod preprocessor;
+pub use assemble::Assembler;
+pub use compat::Syntax;
+pub use diagnostic::Diagnostic;
+pub use expr::Expr;
+pub use linker::MemoryLayout;
+pub use listing::{format_listing, format_symbols};
+pub use preprocessor::{parse_source, read_source};
@@ -0,0 +1,903 @@
+use std::collections::{HashMap, HashSet};
+use std::path::Path;
+
+use error_stack::{bail, Report, Result, ResultExt};
+
+use super::assemble::{find_comment, split_operands, AsmLine};
+use super::compat::{normalize, Syntax};
+use super::diagnostic::{into_report, Diagnostic};
+use super::expr::Expr;
+use crate::error::NesError;
+use crate::io::{read_file, read_file_lines};
+
+const MAX_INCLUDE_DEPTH: usize = 16;
+const MAX_EXPANSION_DEPTH: usize = 64;
+// enough to fill the whole 16 bit address space
+const MAX_REPEAT_COUNT: i64 = 0x10000;
+
+struct Macro {
+    params: Vec<String>,
+    // labels defined in the body, renamed on every expansion
+    locals: Vec<String>,
+    body: Vec<String>,
+}
+
+// Where an assembled line comes from. Lines produced by macros and
+// `.repeat` blocks point at the line that expanded them.
+#[derive(Debug, Clone)]
+pub struct SourceLine {
+    pub file: String,
+    pub line: usize,
+    pub text: String,
+}
+
+// The preprocessed lines of a program with their origin.
+#[derive(Debug, Default)]
+pub struct Source {
+    pub lines: Vec<AsmLine>,
+    pub locations: Vec<SourceLine>,
+}
+
+impl Source {
+    fn push(&mut self, line: AsmLine, location: SourceLine) {
+        self.lines.push(line);
+        self.locations.push(location);
+    }
+}
+
+// One level of `.if` nesting.
+struct Cond {
+    // whether the enclosing block is assembled at all
+    parent_active: bool,
+    active: bool,
+    seen_else: bool,
+}
+
+/**
+Expands `.include`, `.incbin`, macros, `.repeat` blocks and conditional
+assembly, producing the lines the assembler passes work on.
+
+Conditions and repeat counts are evaluated with the constants defined
+above them in the source, as labels have no address yet.
+
+Common ca65 and asm6 syntax is accepted as well: labels and constants
+of a `.proc` block are scoped to it, and labels inside an `.enum` /
+`.ende` block become constants numbered by the storage they reserve.
+asm6 sources are read with `Syntax::Asm6`, see `normalize`.
+*/
+#[derive(Default)]
+pub struct Preprocessor {
+    macros: HashMap<String, Macro>,
+    constants: HashMap<String, i64>,
+    // every constant, label and macro seen so far, for `.ifdef`
+    defined: HashSet<String>,
+    include_depth: usize,
+    expansion_depth: usize,
+    expansions: usize,
+    // the file being read, the current line in it and the expansion
+    // depth its own lines are processed at
+    file: String,
+    line: usize,
+    file_depth: usize,
+    // symbols of the open `.proc` blocks with their scoped names
+    scopes: Vec<HashMap<String, String>>,
+    // the address of the next label inside an asm6 `.enum` block
+    enum_addr: Option<i64>,
+    syntax: Syntax,
+    diagnostics: Vec<Diagnostic>,
+}
+
+fn split_first_word(s: &str) -> (&str, &str) {
+    match s.find(char::is_whitespace) {
+        Some(i) => (&s[..i], s[i..].trim()),
+        None => (s, ""),
+    }
+}
+
+fn strip_comment(s: &str) -> &str {
+    match find_comment(s) {
+        Some(i) => s[..i].trim(),
+        None => s.trim(),
+    }
+}
+
+fn is_symbol_char(c: char) -> bool {
+    c.is_ascii_alphanumeric() || c == '_' || c == '.' || c == '@'
+}
+
+// Replaces whole symbols in `line` according to `names`, leaving string
+// and character literals alone.
+fn substitute(line: &str, names: &HashMap<&str, String>) -> String {
+    let mut res = String::new();
+    let mut word = String::new();
+    let mut quote = None;
+    let flush = |word: &mut String, res: &mut String| {
+        match names.get(word.as_str()) {
+            Some(value) => res.push_str(value),
+            None => res.push_str(word),
+        }
+        word.clear();
+    };
+    for c in line.chars() {
+        match quote {
+            Some(q) => {
+                res.push(c);
+                if c == q {
+                    quote = None;
+                }
+            }
+            None if is_symbol_char(c) => word.push(c),
+            None => {
+                flush(&mut word, &mut res);
+                if c == '"' || c == '\'' {
+                    quote = Some(c);
+                }
+                res.push(c);
+            }
+        }
+    }
+    flush(&mut word, &mut res);
+    res
+}
+
+// Collects the lines of a block up to its matching end directive,
+// starting after the line at `start`. Returns the body and the index of
+// the end directive.
+fn collect_block<'a>(
+    lines: &'a [String],
+    start: usize,
+    open: &[&str],
+    close: &[&str],
+    syntax: Syntax,
+) -> Result<(&'a [String], usize), NesError> {
+    let mut depth = 0;
+    for (i, line) in lines.iter().enumerate().skip(start + 1) {
+        let statement = normalize(strip_comment(line), syntax).1;
+        let directive = split_first_word(&statement).0.to_lowercase();
+        if open.contains(&directive.as_str()) {
+            depth += 1;
+        } else if close.contains(&directive.as_str()) {
+            if depth == 0 {
+                return Ok((&lines[start + 1..i], i));
+            }
+            depth -= 1;
+        }
+    }
+    bail!(NesError::AssemblerFailure(format!(
+        "missing {}: {}",
+        close[0],
+        lines[start].trim()
+    )))
+}
+
+impl Preprocessor {
+    fn eval(&self, s: &str) -> Result<i64, NesError> {
+        let lookup = |name: &str| match self.constants.get(name) {
+            Some(value) => Ok(*value),
+            None => bail!(NesError::AssemblerFailure(format!(
+                "not a constant: {}",
+                name
+            ))),
+        };
+        Expr::parse(s)?.eval(&lookup, 0)
+    }
+
+    fn define_macro(&mut self, header: &str, body: &[String]) -> Result<(), NesError> {
+        let (name, params) = split_first_word(header);
+        if name.is_empty() {
+            bail!(NesError::AssemblerFailure("missing macro name".to_string()));
+        }
+        if self.macros.contains_key(name) {
+            bail!(NesError::AssemblerFailure(format!(
+                "duplicate macro: {}",
+                name
+            )));
+        }
+        let locals = body
+            .iter()
+            .filter_map(|line| normalize(strip_comment(line), self.syntax).0)
+            .filter(|label| !label.is_empty())
+            .collect();
+        self.defined.insert(name.to_string());
+        self.macros.insert(
+            name.to_string(),
+            Macro {
+                params: split_operands(params),
+                locals,
+                body: body.to_vec(),
+            },
+        );
+        Ok(())
+    }
+
+    fn expand_macro(
+        &mut self,
+        name: &str,
+        args: &str,
+        dir: &Path,
+        out: &mut Source,
+    ) -> Result<(), NesError> {
+        let mac = &self.macros[name];
+        let args = split_operands(args);
+        if args.len() > mac.params.len() {
+            bail!(NesError::AssemblerFailure(format!(
+                "too many arguments for macro {}: {}",
+                name,
+                args.join(", ")
+            )));
+        }
+        self.expansions += 1;
+        let mut names = HashMap::new();
+        for (i, param) in mac.params.iter().enumerate() {
+            names.insert(param.as_str(), args.get(i).cloned().unwrap_or_default());
+        }
+        // local labels become `@` labels so that they stay within the
+        // scope of the label before the expansion
+        for local in &mac.locals {
+            let local_name = local.trim_start_matches('@');
+            names.insert(
+                local.as_str(),
+                format!("@__{}_{}_{}", name, local_name, self.expansions),
+            );
+        }
+        let body = mac
+            .body
+            .iter()
+            .map(|line| substitute(line, &names))
+            .collect::<Vec<_>>();
+        self.process_nested(&body, dir, out)
+            .attach_printable_lazy(|| format!("in macro {}", name))
+    }
+
+    fn expand_repeat(
+        &mut self,
+        args: &str,
+        body: &[String],
+        dir: &Path,
+        out: &mut Source,
+    ) -> Result<(), NesError> {
+        let args = split_operands(args);
+        if args.is_empty() || args.len() > 2 {
+            bail!(NesError::AssemblerFailure(format!(
+                "illegal .repeat: {}",
+                args.join(", ")
+            )));
+        }
+        let count = self.eval(&args[0])?;
+        if !(0..=MAX_REPEAT_COUNT).contains(&count) {
+            bail!(NesError::AssemblerFailure(format!(
+                ".repeat count out of range: {}",
+                count
+            )));
+        }
+        for i in 0..count {
+            let mut names = HashMap::new();
+            if let Some(var) = args.get(1) {
+                names.insert(var.as_str(), i.to_string());
+            }
+            let body = body
+                .iter()
+                .map(|line| substitute(line, &names))
+                .collect::<Vec<_>>();
+            self.process_nested(&body, dir, out)?;
+        }
+        Ok(())
+    }
+
+    // Opens the scope of `.proc name` up to its `.endproc`, renaming the
+    // labels and constants defined in it to `name.symbol`.
+    fn open_scope(
+        &mut self,
+        args: &str,
+        lines: &[String],
+        start: usize,
+        text: &str,
+        dir: &Path,
+        out: &mut Source,
+    ) -> Result<(), NesError> {
+        let name = split_first_word(args).0;
+        if name.is_empty() {
+            bail!(NesError::AssemblerFailure("missing .proc name".to_string()));
+        }
+        let (body, _) = collect_block(lines, start, &[".proc"], &[".endproc"], self.syntax)?;
+        let mut names = HashMap::new();
+        let mut depth = 0;
+        for line in body {
+            let (label, statement) = normalize(strip_comment(line), self.syntax);
+            let (word, args) = split_first_word(&statement);
+            let mut symbols = vec![];
+            if depth == 0 {
+                symbols.extend(label.filter(|label| !label.is_empty() && !label.starts_with('@')));
+            }
+            match word.to_lowercase().as_str() {
+                ".proc" => {
+                    if depth == 0 {
+                        symbols.push(split_first_word(args).0.to_string());
+                    }
+                    depth += 1;
+                }
+                ".endproc" => depth -= 1,
+                _ if depth == 0 => {
+                    if let Ok(AsmLine::Const { name, .. }) = statement.parse() {
+                        symbols.push(name);
+                    }
+                }
+                _ => {}
+            }
+            for symbol in symbols {
+                names.insert(symbol.clone(), format!("{}.{}", name, symbol));
+            }
+        }
+        self.handle_line(&format!("{}:", name), text, dir, out)?;
+        self.scopes.push(names);
+        Ok(())
+    }
+
+    fn scoped(&self, line: &str) -> String {
+        let mut names = HashMap::new();
+        for scope in &self.scopes {
+            names.extend(
+                scope
+                    .iter()
+                    .map(|(symbol, scoped)| (symbol.as_str(), scoped.clone())),
+            );
+        }
+        substitute(line, &names)
+    }
+
+    // Handles a line inside `.enum`, where storage directives only advance
+    // the address given to the labels.
+    fn reserve(
+        &mut self,
+        line: &str,
+        text: &str,
+        dir: &Path,
+        out: &mut Source,
+    ) -> Result<(), NesError> {
+        let size = match line.parse::<AsmLine>()? {
+            AsmLine::Empty => 0,
+            AsmLine::Res { count, .. } => self.eval(&count)?,
+            AsmLine::Byte { values } => values.len() as i64,
+            AsmLine::Word { values } => values.len() as i64 * 2,
+            AsmLine::Const { .. } => return self.handle_line(line, text, dir, out),
+            _ => bail!(NesError::AssemblerFailure(format!(
+                "not allowed inside .enum: {}",
+                line
+            ))),
+        };
+        if let Some(addr) = &mut self.enum_addr {
+            *addr += size;
+        }
+        Ok(())
+    }
+
+    fn process_nested(
+        &mut self,
+        lines: &[String],
+        dir: &Path,
+        out: &mut Source,
+    ) -> Result<(), NesError> {
+        if self.expansion_depth >= MAX_EXPANSION_DEPTH {
+            bail!(NesError::AssemblerFailure(
+                "too many nested macro expansions".to_string()
+            ));
+        }
+        self.expansion_depth += 1;
+        self.process_lines(lines, dir, out);
+        self.expansion_depth -= 1;
+        Ok(())
+    }
+
+    fn location(&self, text: &str) -> SourceLine {
+        SourceLine {
+            file: self.file.clone(),
+            line: self.line,
+            text: text.to_string(),
+        }
+    }
+
+    fn handle_line(
+        &mut self,
+        line: &str,
+        text: &str,
+        dir: &Path,
+        out: &mut Source,
+    ) -> Result<(), NesError> {
+        match line.parse::<AsmLine>()? {
+            AsmLine::Include { path } => self.include_file(&dir.join(path), out)?,
+            AsmLine::IncBin { path } => {
+                let path = dir.join(path);
+                let bytes = read_file(&path.to_string_lossy())
+                    .change_context(NesError::Io)
+                    .attach_printable_lazy(|| format!("cannot read {}", path.display()))?;
+                out.push(AsmLine::Data { bytes }, self.location(text));
+            }
+            line => {
+                match &line {
+                    AsmLine::Label { name } => {
+                        self.defined.insert(name.clone());
+                    }
+                    AsmLine::Const { name, value } => {
+                        // constants depending on labels are left to the assembler
+                        if let Ok(value) = self.eval(value) {
+                            self.constants.insert(name.clone(), value);
+                        }
+                        self.defined.insert(name.clone());
+                    }
+                    _ => {}
+                }
+                out.push(line, self.location(text));
+            }
+        }
+        Ok(())
+    }
+
+    // Handles the line at `i`, moving `i` past the blocks it starts.
+    fn process_line(
+        &mut self,
+        lines: &[String],
+        i: &mut usize,
+        conds: &mut Vec<Cond>,
+        dir: &Path,
+        out: &mut Source,
+    ) -> Result<(), NesError> {
+        let text = &lines[*i];
+        let (mut label, mut line) = normalize(strip_comment(text), self.syntax);
+        if !self.scopes.is_empty() {
+            label = label.map(|label| self.scoped(&label));
+            line = self.scoped(&line);
+        }
+        let line = line.as_str();
+        let (word, args) = split_first_word(line);
+        let directive = word.to_lowercase();
+        let active = conds.last().is_none_or(|c| c.active);
+        if let Some(label) = label.filter(|_| active) {
+            match self.enum_addr {
+                Some(addr) => self.handle_line(&format!("{} = {}", label, addr), text, dir, out)?,
+                None => self.handle_line(&format!("{}:", label), text, dir, out)?,
+            }
+            if line.is_empty() {
+                return Ok(());
+            }
+        }
+        // a block without its end directive swallows the rest of the lines
+        let mut take_block = |open: &[&str], close: &[&str]| {
+            let block = collect_block(lines, *i, open, close, self.syntax);
+            *i = match &block {
+                Ok((_, end)) => *end,
+                Err(_) => lines.len() - 1,
+            };
+            block.map(|(body, _)| body)
+        };
+        match directive.as_str() {
+            ".if" | ".ifdef" | ".ifndef" => {
+                let cond = active
+                    && match directive.as_str() {
+                        ".if" => self.eval(args)? != 0,
+                        ".ifdef" => self.defined.contains(args),
+                        _ => !self.defined.contains(args),
+                    };
+                conds.push(Cond {
+                    parent_active: active,
+                    active: cond,
+                    seen_else: false,
+                });
+            }
+            ".else" => match conds.last_mut() {
+                Some(cond) if !cond.seen_else => {
+                    cond.seen_else = true;
+                    cond.active = cond.parent_active && !cond.active;
+                }
+                _ => bail!(NesError::AssemblerFailure(format!("unexpected {}", line))),
+            },
+            ".endif" => {
+                if conds.pop().is_none() {
+                    bail!(NesError::AssemblerFailure(format!("unexpected {}", line)));
+                }
+            }
+            _ if !active => {}
+            ".macro" => {
+                let body = take_block(&[".macro"], &[".endmacro", ".endm"])?;
+                self.define_macro(args, body)?;
+            }
+            ".repeat" | ".rept" => {
+                let body = take_block(&[".repeat", ".rept"], &[".endrepeat", ".endrep", ".endr"])?;
+                self.expand_repeat(args, body, dir, out)?;
+            }
+            ".endmacro" | ".endm" | ".endrepeat" | ".endrep" | ".endr" => {
+                bail!(NesError::AssemblerFailure(format!("unexpected {}", line)));
+            }
+            ".enum" if self.enum_addr.is_none() => self.enum_addr = Some(self.eval(args)?),
+            ".ende" if self.enum_addr.is_some() => self.enum_addr = None,
+            ".enum" | ".ende" => bail!(NesError::AssemblerFailure(format!("unexpected {}", line))),
+            _ if self.enum_addr.is_some() => self.reserve(line, text, dir, out)?,
+            ".proc" => self.open_scope(args, lines, *i, text, dir, out)?,
+            ".endproc" => {
+                if self.scopes.pop().is_none() {
+                    bail!(NesError::AssemblerFailure(format!("unexpected {}", line)));
+                }
+            }
+            _ if self.macros.contains_key(word) => {
+                self.expand_macro(word, args, dir, out)?;
+            }
+            _ => self.handle_line(line, text, dir, out)?,
+        }
+        Ok(())
+    }
+
+    fn report(&mut self, report: &Report<NesError>, text: &str) {
+        let location = self.location(text);
+        self.diagnostics
+            .push(Diagnostic::new(report, 0, Some(&location)));
+    }
+
+    // Processes `lines`, read from a file in `dir`, into `out`. Errors are
+    // collected as diagnostics and processing goes on with the next line.
+    pub fn process_lines(&mut self, lines: &[String], dir: &Path, out: &mut Source) {
+        let mut conds: Vec<Cond> = vec![];
+        let mut i = 0;
+        while i < lines.len() {
+            if self.expansion_depth == self.file_depth {
+                self.line = i + 1;
+            }
+            let start = i;
+            if let Err(report) = self.process_line(lines, &mut i, &mut conds, dir, out) {
+                self.report(&report, &lines[start]);
+            }
+            i += 1;
+        }
+        if !conds.is_empty() {
+            let report = Report::new(NesError::AssemblerFailure("missing .endif".to_string()));
+            self.report(&report, lines.last().map_or("", |line| line.as_str()));
+        }
+        if self.expansion_depth == self.file_depth && self.enum_addr.take().is_some() {
+            let report = Report::new(NesError::AssemblerFailure("missing .ende".to_string()));
+            self.report(&report, lines.last().map_or("", |line| line.as_str()));
+        }
+    }
+
+    // Fails with every diagnostic collected so far.
+    pub fn check(&mut self) -> Result<(), NesError> {
+        match self.diagnostics.is_empty() {
+            true => Ok(()),
+            false => Err(into_report(std::mem::take(&mut self.diagnostics))),
+        }
+    }
+
+    fn include_file(&mut self, path: &Path, out: &mut Source) -> Result<(), NesError> {
+        if self.include_depth >= MAX_INCLUDE_DEPTH {
+            bail!(NesError::AssemblerFailure(format!(
+                "too many nested includes: {}",
+                path.display()
+            )));
+        }
+        let lines = read_file_lines(&path.to_string_lossy())
+            .change_context(NesError::Io)
+            .attach_printable_lazy(|| format!("cannot read {}", path.display()))?;
+        let dir = path.parent().unwrap_or(Path::new(""));
+        let parent = (
+            std::mem::replace(&mut self.file, path.display().to_string()),
+            self.line,
+            self.file_depth,
+        );
+        self.file_depth = self.expansion_depth;
+        self.include_depth += 1;
+        self.process_lines(&lines, dir, out);
+        self.include_depth -= 1;
+        (self.file, self.line, self.file_depth) = parent;
+        Ok(())
+    }
+}
+
+// Reads and preprocesses an assembly file written in `syntax`, resolving
+// `.include` and `.incbin` relative to the file that contains them.
+pub fn read_source(path: &str, syntax: Syntax) -> Result<Source, NesError> {
+    let mut res = Source::default();
+    let mut preprocessor = Preprocessor {
+        syntax,
+        ..Default::default()
+    };
+    preprocessor.include_file(Path::new(path), &mut res)?;
+    preprocessor.check()?;
+    Ok(res)
+}
+
+// Preprocesses assembly text that was not read from a file, resolving
+// `.include` and `.incbin` relative to the working directory.
+pub fn parse_source(text: &str, syntax: Syntax) -> Result<Source, NesError> {
+    let lines = text.lines().map(|s| s.to_string()).collect::<Vec<_>>();
+    let mut res = Source::default();
+    let mut preprocessor = Preprocessor {
+        file: "<input>".to_string(),
+        syntax,
+        ..Default::default()
+    };
+    preprocessor.process_lines(&lines, Path::new(""), &mut res);
+    preprocessor.check()?;
+    Ok(res)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::assembler::Assembler;
+
+    fn assemble_with(source: &str, syntax: Syntax) -> Result<Vec<u8>, NesError> {
+        let source = parse_source(source, syntax)?;
+        Ok(Assembler::new(0x8000).assemble(&source.lines)?.to_vec())
+    }
+
+    fn assemble(source: &str) -> Result<Vec<u8>, NesError> {
+        assemble_with(source, Syntax::Native)
+    }
+
+    #[test]
+    fn test_macros() {
+        let bytes = assemble(
+            "
+            .macro store value, addr
+                lda #value
+                sta addr
+            .endmacro
+            .macro wait count
+                ldx #count
+            loop:
+                dex
+                bne loop
+            .endm
+            store 1, $10
+            store 'x', $0300
+            .macro spin
+            :
+                bne :-
+            .endmacro
+            main:
+            wait 2
+            @next:
+            wait 3
+            spin
+            jmp @next
+        ",
+        )
+        .unwrap();
+        assert_eq!(
+            bytes,
+            vec![
+                0xa9, 0x01, 0x85, 0x10, 0xa9, 0x78, 0x8d, 0x00, 0x03, 0xa2, 0x02, 0xca, 0xd0, 0xfd,
+                0xa2, 0x03, 0xca, 0xd0, 0xfd, 0xd0, 0xfe, 0x4c, 0x0e, 0x80,
+            ]
+        );
+        assert!(assemble(".macro m a\n.endmacro\nm 1, 2").is_err());
+        assert!(assemble(".macro m\nnop").is_err());
+        assert!(assemble(".macro m\n.endmacro\n.macro m\n.endmacro").is_err());
+        assert!(assemble(".macro m\nm\n.endmacro\nm").is_err());
+    }
+
+    #[test]
+    fn test_conditionals() {
+        let bytes = assemble(
+            "
+            DEBUG = 1
+            LEVEL = DEBUG + 1
+            .if LEVEL > 1
+                .byte 1
+                .if DEBUG - 1
+                    .byte 2
+                .else
+                    .byte 3
+                .endif
+            .else
+                .byte 4
+                .if 1
+                    .byte 5
+                .else
+                    .byte 6
+                .endif
+            .endif
+            .ifdef DEBUG
+                .byte 7
+            .endif
+            .ifdef RELEASE
+                .byte 8
+            .else
+                .byte 9
+            .endif
+        ",
+        )
+        .unwrap();
+        assert_eq!(bytes, vec![1, 3, 7, 9]);
+        assert!(assemble(".if 1\nnop").is_err());
+        assert!(assemble(".endif").is_err());
+        assert!(assemble(".if 1\n.else\n.else\n.endif").is_err());
+        assert!(assemble("label:\n.if label\n.endif").is_err());
+    }
+
+    #[test]
+    fn test_repeat() {
+        let bytes = assemble(
+            "
+            COUNT = 3
+            .repeat COUNT, i
+                .byte i * 2
+                .repeat 2
+                    nop
+                .endrepeat
+            .endrep
+        ",
+        )
+        .unwrap();
+        assert_eq!(bytes, vec![0, 0xea, 0xea, 2, 0xea, 0xea, 4, 0xea, 0xea]);
+        assert!(assemble(".repeat 2").is_err());
+        assert!(assemble(".repeat 1 << 40\n nop\n.endrepeat").is_err());
+        assert!(assemble(".repeat -1\n nop\n.endrepeat").is_err());
+        assert!(assemble(".repeat 0\n nop\n.endrepeat").unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_ca65() {
+        let bytes = assemble(
+            "
+            .import unused
+            .export main
+            .ifndef SPEED
+            SPEED = 2
+            .endif
+            .proc main
+                ldx #SPEED
+            loop: dex
+                bne loop
+                jsr clear::reset
+                rts
+            .endproc
+            .proc clear
+                COUNT = 1
+                lda #COUNT
+            reset:
+                rts
+            .endproc
+            .ifndef main
+                .byte $ff
+            .endif
+            loop: jmp ::loop
+        ",
+        )
+        .unwrap();
+        assert_eq!(
+            bytes,
+            vec![
+                0xa2, 0x02, 0xca, 0xd0, 0xfd, 0x20, 0x0b, 0x80, 0x60, 0xa9, 0x01, 0x60, 0x4c, 0x0c,
+                0x80,
+            ]
+        );
+        assert!(assemble(
+            ".proc p
+nop"
+        )
+        .is_err());
+        assert!(assemble(".endproc").is_err());
+    }
+
+    #[test]
+    fn test_asm6() {
+        let assemble_asm6 = |source: &str| assemble_with(source, Syntax::Asm6);
+        let bytes = assemble_asm6(
+            "
+            SPEED EQU 3
+            ENUM $0300
+            buttons .dsb 1
+            timer:  .dsw 2
+            .ende
+            ENUM $10
+            pointer dsb 2
+            ENDE
+            lda buttons
+            sta timer
+            lda (pointer),y
+            table db SPEED, <timer
+            hex 0a0B c0
+        ",
+        )
+        .unwrap();
+        assert_eq!(
+            bytes,
+            vec![0xad, 0x00, 0x03, 0x8d, 0x01, 0x03, 0xb1, 0x10, 0x03, 0x01, 0x0a, 0x0b, 0xc0,]
+        );
+        assert!(assemble_asm6(
+            "enum $10
+nop
+ende"
+        )
+        .is_err());
+        assert!(assemble_asm6("enum $10").is_err());
+        assert!(assemble_asm6("ende").is_err());
+    }
+
+    #[test]
+    fn test_directive_names() {
+        // symbols named like asm6 directives, in sources that aren't asm6
+        let bytes = assemble("pad = 4\norg = pad + 1\nhex:\n.byte pad, org, <hex").unwrap();
+        assert_eq!(bytes, vec![0x04, 0x05, 0x00]);
+        assert_eq!(
+            assemble_with("pad = 4\n.byte pad", Syntax::Asm6).unwrap(),
+            vec![0x04]
+        );
+        assert!(assemble("buttons dsb 1").is_err());
+    }
+
+    // Sources in tests/compat next to the output of the assembler they are
+    // written for, see the command at the top of each.
+    #[test]
+    fn test_reference_output() {
+        let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/compat");
+        let fixtures = [
+            ("ca65.s", Syntax::Native, "ca65.bin"),
+            ("asm6.asm", Syntax::Asm6, "asm6.bin"),
+        ];
+        for (source, syntax, reference) in fixtures {
+            let source = read_source(&dir.join(source).to_string_lossy(), syntax).unwrap();
+            let bytes = Assembler::new(0x8000)
+                .assemble(&source.lines)
+                .unwrap()
+                .to_vec();
+            assert_eq!(
+                bytes,
+                std::fs::read(dir.join(reference)).unwrap(),
+                "{}",
+                reference
+            );
+        }
+    }
+
+    #[test]
+    fn test_diagnostics() {
+        let report =
+            assemble(".macro m\n  .bogus\n.endmacro\nm\n.byte\n.endif\n.if 1").unwrap_err();
+        let NesError::AssemblerDiagnostics(diagnostics) = report.current_context() else {
+            panic!("expected diagnostics: {:?}", report);
+        };
+        let lines = diagnostics
+            .iter()
+            .map(|d| (d.line, d.column))
+            .collect::<Vec<_>>();
+        assert_eq!(lines, vec![(4, 3), (5, 1), (6, 1), (7, 1)]);
+        assert_eq!(diagnostics[2].message, "unexpected .endif");
+    }
+
+    #[test]
+    fn test_read_source() {
+        let dir = std::env::temp_dir().join("nes_assembler_test_read_source");
+        std::fs::create_dir_all(dir.join("inc")).unwrap();
+        std::fs::write(
+            dir.join("main.asm"),
+            ".include \"inc/lib.asm\"\n.incbin \"inc/chr.bin\"\nclear\n",
+        )
+        .unwrap();
+        std::fs::write(
+            dir.join("inc/lib.asm"),
+            "nop\n.incbin \"chr.bin\"\n.macro clear\nclc\n.endmacro\n",
+        )
+        .unwrap();
+        std::fs::write(dir.join("inc/chr.bin"), [1, 2, 3]).unwrap();
+        let source = read_source(&dir.join("main.asm").to_string_lossy(), Syntax::Native).unwrap();
+        let bytes = Assembler::new(0x8000)
+            .assemble(&source.lines)
+            .unwrap()
+            .to_vec();
+        assert_eq!(bytes, vec![0xea, 1, 2, 3, 1, 2, 3, 0x18]);
+        let lines = source
+            .locations
+            .iter()
+            .map(|l| (l.file.rsplit('/').next().unwrap(), l.line))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            lines,
+            vec![
+                ("lib.asm", 1),
+                ("lib.asm", 2),
+                ("main.asm", 2),
+                ("main.asm", 3)
+            ]
+        );
+        assert_eq!(source.locations[3].text, "clc");
+    }
+}
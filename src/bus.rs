@@ -1,9 +1,75 @@
-use crate::{nes_format::NesFile, ppu::PPU};
+use std::cell::RefCell;
+
+use crate::{
+    apu_log::{is_apu_register, ApuLog},
+    nes_format::NesFile,
+    nsf_format::NSF_BANK_SIZE,
+    ppu::PPU,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum AddressSpace {
+    Cpu,
+    // the PPU's own bus, reached through PPUADDR and PPUDATA
+    Ppu,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum AccessKind {
+    Read,
+    Write,
+}
+
+// A data read or write on one of the buses, recorded for watchpoints.
+// Instruction fetches aren't recorded.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Access {
+    pub space: AddressSpace,
+    pub kind: AccessKind,
+    pub addr: u16,
+    pub value: u8,
+}
+
+// Records accesses once started. `read` takes `&self`, hence the RefCell.
+#[derive(Default)]
+pub struct AccessLog(RefCell<Option<Vec<Access>>>);
+
+impl AccessLog {
+    pub fn start(&self) {
+        self.0.replace(Some(vec![]));
+    }
+
+    pub fn record(&self, space: AddressSpace, kind: AccessKind, addr: u16, value: u8) {
+        if let Some(log) = self.0.borrow_mut().as_mut() {
+            log.push(Access {
+                space,
+                kind,
+                addr,
+                value,
+            });
+        }
+    }
+
+    // The accesses since the last call, keeping the log running.
+    pub fn drain(&self) -> Vec<Access> {
+        self.0
+            .borrow_mut()
+            .as_mut()
+            .map(std::mem::take)
+            .unwrap_or_default()
+    }
+}
 
 pub struct Bus {
     ram: [u8; 0x10000],
     ppu: PPU,
     prg_rom: Vec<u8>,
+    cycles: u64,
+    // the length of the instruction being run, see `start_instruction`
+    inst_cycles: u8,
+    apu_log: Option<ApuLog>,
+    access_log: AccessLog,
+    nsf_banks: Vec<u8>,
 }
 
 impl Default for Bus {
@@ -12,6 +78,11 @@ impl Default for Bus {
             ram: [0; 0x10000],
             ppu: PPU::default(),
             prg_rom: vec![],
+            cycles: 0,
+            inst_cycles: 0,
+            apu_log: None,
+            access_log: AccessLog::default(),
+            nsf_banks: vec![],
         }
     }
 }
@@ -22,6 +93,68 @@ impl Bus {
             ram: [0; 0x10000],
             ppu: PPU::new(f.chr_rom.clone(), f.mirroring()),
             prg_rom: f.prg_rom,
+            cycles: 0,
+            inst_cycles: 0,
+            apu_log: None,
+            access_log: AccessLog::default(),
+            nsf_banks: vec![],
+        }
+    }
+
+    pub fn tick(&mut self, cycles: u8) {
+        self.cycles += cycles as u64;
+        self.inst_cycles = 0;
+    }
+
+    pub fn cycles(&self) -> u64 {
+        self.cycles
+    }
+
+    // Tells the bus that an instruction of `cycles` cycles is running, until
+    // the cycles are ticked. Its writes happen on its last cycle, as stores
+    // and read-modify-write instructions do theirs.
+    pub fn start_instruction(&mut self, cycles: u8) {
+        self.inst_cycles = cycles;
+    }
+
+    // The cycle a write happens on.
+    fn write_cycle(&self) -> u64 {
+        self.cycles + self.inst_cycles.saturating_sub(1) as u64
+    }
+
+    pub fn start_apu_log(&mut self) {
+        self.apu_log = Some(ApuLog::new(self.cycles));
+    }
+
+    pub fn take_apu_log(&mut self) -> Option<ApuLog> {
+        self.apu_log.take()
+    }
+
+    // Starts recording reads and writes of both buses, see `take_accesses`.
+    pub fn start_access_log(&self) {
+        self.access_log.start();
+        self.ppu.access_log().start();
+    }
+
+    // The accesses since the last call, CPU bus first.
+    pub fn take_accesses(&self) -> Vec<Access> {
+        let mut accesses = self.access_log.drain();
+        accesses.extend(self.ppu.access_log().drain());
+        accesses
+    }
+
+    // Enables NSF bankswitching: writes to $5FF8-$5FFF map a 4KB bank of
+    // `banks` into $8000-$FFFF.
+    pub fn load_nsf_banks(&mut self, banks: Vec<u8>) {
+        self.nsf_banks = banks;
+    }
+
+    fn switch_nsf_bank(&mut self, slot: u16, bank: u8) {
+        let src = bank as usize * NSF_BANK_SIZE;
+        let dst = 0x8000 + slot as usize * NSF_BANK_SIZE;
+        if src + NSF_BANK_SIZE <= self.nsf_banks.len() {
+            self.ram[dst..dst + NSF_BANK_SIZE]
+                .copy_from_slice(&self.nsf_banks[src..src + NSF_BANK_SIZE]);
         }
     }
 
@@ -34,6 +167,19 @@ impl Bus {
     }
 
     pub fn read(&self, addr: u16) -> u8 {
+        let value = self.fetch(addr);
+        // mirrors are recorded as the address they mirror
+        self.access_log.record(
+            AddressSpace::Cpu,
+            AccessKind::Read,
+            self.get_phisical_addr(addr),
+            value,
+        );
+        value
+    }
+
+    // Reads an instruction byte, which isn't a data access.
+    pub fn fetch(&self, addr: u16) -> u8 {
         let addr = self.get_phisical_addr(addr);
         match addr {
             0x0000..=0x7FF => self.ram[addr as usize],
@@ -42,11 +188,33 @@ impl Bus {
         }
     }
 
+    // Reads `addr` for traces and debuggers, without the side effects of
+    // reading PPU registers, whose value is unknown here. APU and I/O
+    // registers read as $FF, as they do in nestest.log.
+    pub fn peek(&self, addr: u16) -> Option<u8> {
+        match self.get_phisical_addr(addr) {
+            0x2000..=0x2007 => None,
+            0x4000..=0x4017 => Some(0xff),
+            _ => Some(self.fetch(addr)),
+        }
+    }
+
     pub fn write(&mut self, addr: u16, data: u8) {
         let addr = self.get_phisical_addr(addr);
+        self.access_log
+            .record(AddressSpace::Cpu, AccessKind::Write, addr, data);
+        let cycle = self.write_cycle();
+        if let Some(log) = self.apu_log.as_mut() {
+            if is_apu_register(addr) {
+                log.record(cycle, addr, data);
+            }
+        }
         match addr {
             0x0000..=0x7FF => self.ram[addr as usize] = data,
             0x2000..=0x2007 | 0x4014 => self.ppu.set_ram_mapped_register(addr, data),
+            0x5FF8..=0x5FFF if !self.nsf_banks.is_empty() => {
+                self.switch_nsf_bank(addr - 0x5FF8, data)
+            }
             _ => self.ram[addr as usize] = data,
         }
     }
@@ -71,7 +239,7 @@ impl<'a> Iterator for ByteStream<'a> {
     type Item = u8;
 
     fn next(&mut self) -> Option<Self::Item> {
-        let result = self.bus.read(self.addr);
+        let result = self.bus.fetch(self.addr);
         self.addr += 1;
         Some(result)
     }
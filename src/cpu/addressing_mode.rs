@@ -82,6 +82,17 @@ pub fn load_operand(mode: AddressingMode, cpu: &CPU, param: u16) -> u8 {
 }
 
 pub fn load_operand_addr(mode: AddressingMode, cpu: &CPU, param: u16) -> u16 {
+    resolve_operand_addr(mode, cpu, param, |addr| cpu.get_mem(addr))
+}
+
+// The address an operand refers to, reading the pointers of indirect modes
+// with `read`.
+pub fn resolve_operand_addr(
+    mode: AddressingMode,
+    cpu: &CPU,
+    param: u16,
+    read: impl Fn(u16) -> u8,
+) -> u16 {
     match mode {
         AddressingMode::Accumulator
         | AddressingMode::Relative
@@ -93,12 +104,11 @@ pub fn load_operand_addr(mode: AddressingMode, cpu: &CPU, param: u16) -> u16 {
             )
         }
         AddressingMode::Indirect => {
-            println!("param: {:x}", param);
-            let lo = cpu.get_mem(param) as u16;
+            let lo = read(param) as u16;
             let hi = if param & 0xff == 0xff {
-                cpu.get_mem(param & 0xff00) as u16
+                read(param & 0xff00) as u16
             } else {
-                cpu.get_mem(param + 1) as u16
+                read(param + 1) as u16
             };
             lo | (hi << 8)
         }
@@ -120,15 +130,15 @@ pub fn load_operand_addr(mode: AddressingMode, cpu: &CPU, param: u16) -> u16 {
         AddressingMode::IndexedIndirect => {
             assert!(param <= 0xff);
             let addr = cpu.x.wrapping_add(param as u8);
-            let lo = cpu.get_mem(addr as u16) as u16;
-            let hi = cpu.get_mem(addr.wrapping_add(1) as u16) as u16;
+            let lo = read(addr as u16) as u16;
+            let hi = read(addr.wrapping_add(1) as u16) as u16;
             lo | (hi << 8)
         }
         AddressingMode::IndirectIndexed => {
             assert!(param <= 0xff);
             let i = param as u8;
-            let lo = cpu.get_mem(i as u16) as u16;
-            let hi = cpu.get_mem(i.wrapping_add(1) as u16) as u16;
+            let lo = read(i as u16) as u16;
+            let hi = read(i.wrapping_add(1) as u16) as u16;
             let addr = lo | (hi << 8);
             addr.wrapping_add(cpu.y as u16)
         }
@@ -0,0 +1,239 @@
+use crate::symbols::SymbolTable;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FrameKind {
+    Call,
+    Brk,
+    Nmi,
+    Irq,
+}
+
+// A subroutine or interrupt handler that was entered and hasn't returned.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Frame {
+    pub kind: FrameKind,
+    // the JSR or BRK, or the instruction the interrupt came before
+    pub caller: u16,
+    pub entry: u16,
+    // the stack pointer before the return address was pushed
+    pub sp: u8,
+}
+
+impl Frame {
+    // Where the matching RTS or RTI goes.
+    pub fn return_addr(&self) -> u16 {
+        match self.kind {
+            FrameKind::Call => self.caller.wrapping_add(3),
+            FrameKind::Brk => self.caller.wrapping_add(2),
+            FrameKind::Nmi | FrameKind::Irq => self.caller,
+        }
+    }
+}
+
+// An instruction that broke the pairing of calls and returns: a return
+// to somewhere else than the caller, or an instruction that pulled return
+// addresses off the stack, like PLA or TXS.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Mismatch {
+    pub pc: u16,
+    pub name: String,
+    // where the CPU went after it
+    pub to: u16,
+    // the frames it removed, innermost first
+    pub dropped: Vec<Frame>,
+}
+
+fn format_addr(addr: u16, symbols: &SymbolTable) -> String {
+    match symbols.get(addr) {
+        Some(name) => format!("{} (${:04X})", name, addr),
+        None => format!("${:04X}", addr),
+    }
+}
+
+impl Mismatch {
+    // `PLA at $8E0B dropped JumpEngine ($8E04)`
+    pub fn format_with_symbols(&self, symbols: &SymbolTable) -> String {
+        let returned = matches!(self.name.as_str(), "RTS" | "RTI");
+        let dropped = self
+            .dropped
+            .iter()
+            .map(|frame| format_addr(frame.entry, symbols))
+            .collect::<Vec<_>>()
+            .join(", ");
+        match (returned, dropped.is_empty()) {
+            (true, true) => format!(
+                "{} at ${:04X} returned to ${:04X} without a call",
+                self.name, self.pc, self.to
+            ),
+            (true, false) => format!(
+                "{} at ${:04X} returned to ${:04X} out of {}",
+                self.name, self.pc, self.to, dropped
+            ),
+            (false, _) => format!("{} at ${:04X} dropped {}", self.name, self.pc, dropped),
+        }
+    }
+}
+
+/**
+The logical call stack: a frame for each JSR, BRK and interrupt, removed
+when the stack pointer moves back above the return address it pushed,
+normally by the matching RTS or RTI. Any other way of removing a frame,
+and any return without one, is kept as the last mismatch.
+*/
+#[derive(Default, Clone)]
+pub struct CallStack {
+    frames: Vec<Frame>,
+    mismatch: Option<Mismatch>,
+}
+
+impl CallStack {
+    pub fn last_mismatch(&self) -> Option<&Mismatch> {
+        self.mismatch.as_ref()
+    }
+
+    pub fn clear(&mut self) {
+        self.frames.clear();
+        self.mismatch = None;
+    }
+
+    pub fn push(&mut self, frame: Frame) {
+        self.frames.push(frame);
+    }
+
+    // Follows the instruction `name` that ran at `pc`, moving the stack
+    // pointer from `sp` to `sp_after` and going to `to`.
+    pub fn update(&mut self, name: &str, pc: u16, sp: u8, to: u16, sp_after: u8) {
+        let kind = match name {
+            "JSR" => Some(FrameKind::Call),
+            "BRK" => Some(FrameKind::Brk),
+            _ => None,
+        };
+        if let Some(kind) = kind {
+            self.frames.push(Frame {
+                kind,
+                caller: pc,
+                entry: to,
+                sp,
+            });
+            return;
+        }
+        let kept = self
+            .frames
+            .iter()
+            .position(|frame| frame.sp <= sp_after)
+            .unwrap_or(self.frames.len());
+        let mut dropped = self.frames.split_off(kept);
+        dropped.reverse();
+        let returning = matches!(name, "RTS" | "RTI");
+        if dropped.is_empty() && !returning {
+            return;
+        }
+        let paired = match dropped.as_slice() {
+            [frame] if frame.return_addr() == to => match name {
+                "RTS" => frame.kind == FrameKind::Call,
+                "RTI" => frame.kind != FrameKind::Call,
+                _ => false,
+            },
+            _ => false,
+        };
+        if !paired {
+            self.mismatch = Some(Mismatch {
+                pc,
+                name: name.to_string(),
+                to,
+                dropped,
+            });
+        }
+    }
+
+    /**
+    The frames innermost first, from `pc` where the CPU is:
+
+    ```text
+    #0  $0614 in sub ($0610)
+    #1  $C005 in nmi ($C000) [NMI]
+    #2  $8123
+    ```
+    */
+    pub fn backtrace(&self, pc: u16, symbols: &SymbolTable) -> String {
+        let mut lines = vec![];
+        let mut at = pc;
+        for (i, frame) in self.frames.iter().rev().enumerate() {
+            let kind = match frame.kind {
+                FrameKind::Call => "",
+                FrameKind::Brk => " [BRK]",
+                FrameKind::Nmi => " [NMI]",
+                FrameKind::Irq => " [IRQ]",
+            };
+            lines.push(format!(
+                "#{:<2} ${:04X} in {}{}",
+                i,
+                at,
+                format_addr(frame.entry, symbols),
+                kind
+            ));
+            at = frame.caller;
+        }
+        lines.push(format!("#{:<2} ${:04X}", self.frames.len(), at));
+        lines.join("\n")
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_pairing() {
+        let mut stack = CallStack::default();
+        stack.update("JSR", 0x0600, 0xfd, 0x0610, 0xfb);
+        stack.update("BRK", 0x0612, 0xfb, 0x8000, 0xf8);
+        stack.update("PHA", 0x8000, 0xf8, 0x8001, 0xf7);
+        stack.update("PLA", 0x8001, 0xf7, 0x8002, 0xf8);
+        assert_eq!(stack.frames.len(), 2);
+        stack.update("RTI", 0x8002, 0xf8, 0x0614, 0xfb);
+        stack.update("RTS", 0x0614, 0xfb, 0x0603, 0xfd);
+        assert!(stack.frames.is_empty());
+        assert_eq!(stack.last_mismatch(), None);
+
+        // a jump table pulling its return address
+        let mut symbols = SymbolTable::default();
+        symbols.insert(0x8e04, "JumpEngine");
+        stack.update("JSR", 0x8000, 0xfd, 0x8e04, 0xfb);
+        stack.update("PLA", 0x8e05, 0xfb, 0x8e06, 0xfc);
+        stack.update("PLA", 0x8e06, 0xfc, 0x8e07, 0xfd);
+        assert!(stack.frames.is_empty());
+        let mismatch = stack.last_mismatch().unwrap().format_with_symbols(&symbols);
+        assert_eq!(mismatch, "PLA at $8E06 dropped JumpEngine ($8E04)");
+        stack.update("RTS", 0x8e10, 0xfb, 0x9000, 0xfd);
+        let mismatch = stack.last_mismatch().unwrap().format_with_symbols(&symbols);
+        assert_eq!(mismatch, "RTS at $8E10 returned to $9000 without a call");
+    }
+
+    #[test]
+    fn test_backtrace() {
+        let mut stack = CallStack::default();
+        let mut symbols = SymbolTable::default();
+        symbols.insert(0x0610, "sub");
+        stack.update("JSR", 0x8123, 0xfd, 0xc000, 0xfb);
+        stack.push(Frame {
+            kind: FrameKind::Nmi,
+            caller: 0xc005,
+            entry: 0xd000,
+            sp: 0xfb,
+        });
+        stack.update("JSR", 0xd003, 0xf8, 0x0610, 0xf6);
+        assert_eq!(
+            stack.backtrace(0x0614, &symbols),
+            "\
+#0  $0614 in sub ($0610)
+#1  $D003 in $D000 [NMI]
+#2  $C005 in $C000
+#3  $8123"
+        );
+        // an RTS out of the interrupt handler
+        stack.update("RTS", 0xd010, 0xf8, 0x1234, 0xfb);
+        assert_eq!(stack.frames.len(), 1);
+        assert_eq!(stack.last_mismatch().unwrap().dropped.len(), 2);
+    }
+}
@@ -1,13 +1,17 @@
 use std::{fmt::Debug, str::FromStr};
 
+use super::addressing_mode::{resolve_operand_addr, AddressingMode};
+use super::call_stack::{CallStack, Frame, FrameKind};
+use super::cycles::{branch_cycles, cycles_before_run};
 use crate::{
     bus::Bus,
     error::NesError,
     instructions::{Inst, INST_FACTORIES_BY_OP_CODE},
     nes_format::NesFile,
     ppu::PPU,
+    symbols::SymbolTable,
 };
-use error_stack::{bail, Result};
+use error_stack::{bail, Report, Result};
 use thiserror::Error;
 
 /**
@@ -113,6 +117,12 @@ impl Flags {
     }
 }
 
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Interrupt {
+    Nmi,
+    Irq,
+}
+
 pub struct CPU {
     // registers
     pub x: u8,
@@ -123,6 +133,7 @@ pub struct CPU {
     pub flags: Flags,
     pub halt: bool,
     pub bus: Bus,
+    pub call_stack: CallStack,
 }
 
 impl Default for CPU {
@@ -136,6 +147,7 @@ impl Default for CPU {
             flags: Flags::default(),
             halt: false,
             bus: Bus::default(),
+            call_stack: CallStack::default(),
         }
     }
 }
@@ -151,6 +163,7 @@ impl CPU {
             flags: Flags::default(),
             halt: false,
             bus: Bus::new(file),
+            call_stack: CallStack::default(),
         }
     }
 
@@ -165,7 +178,14 @@ impl CPU {
         self.sp = 0xFD;
         self.flags = Flags::default();
         self.halt = false;
+        self.call_stack.clear();
         self.pc = self.get_mem16(0xFFFC);
+        // the reset sequence takes 7 cycles before the first instruction
+        self.bus.tick(7);
+    }
+
+    pub fn cycles(&self) -> u64 {
+        self.bus.cycles()
     }
 
     pub fn set_mem(&mut self, addr: u16, value: u8) {
@@ -189,6 +209,11 @@ impl CPU {
         self.bus.read(addr)
     }
 
+    // Reads memory without side effects, see `Bus::peek`.
+    pub fn peek_mem(&self, addr: u16) -> Option<u8> {
+        self.bus.peek(addr)
+    }
+
     pub fn get_mem16(&self, addr: u16) -> u16 {
         let lsb = self.get_mem(addr) as u16;
         let msb = self.get_mem(addr.wrapping_add(1)) as u16;
@@ -213,8 +238,9 @@ impl CPU {
         self.sp = 0xff;
     }
 
-    fn decode(&self) -> Result<Inst, NesError> {
-        let op = self.bus.read(self.pc);
+    // The instruction at `pc`.
+    pub fn decode(&self) -> Result<Inst, NesError> {
+        let op = self.bus.fetch(self.pc);
         if let Some(factory) = INST_FACTORIES_BY_OP_CODE.get(&op) {
             return Ok(factory.make(self.bus.get_byte_stream(self.pc + 1)));
         }
@@ -230,10 +256,46 @@ impl CPU {
         }
         let ins = self.decode()?;
         tracing::debug!("[pc={:04x}] running {:?}", self.pc, ins);
+        let (pc, sp) = (self.pc, self.sp);
+        let cycles = cycles_before_run(&ins, self);
+        self.bus.start_instruction(cycles);
         ins.run(self);
+        self.bus.tick(cycles + branch_cycles(&ins, pc, self.pc));
+        self.call_stack.update(&ins.name, pc, sp, self.pc, self.sp);
+        if self.halt {
+            // jammed on KIL
+            return Err(Report::new(NesError::HaltError)
+                .attach_printable(format!("jammed on {} at ${:04X}", ins.name, pc)));
+        }
         Ok(())
     }
 
+    // Enters the handler of an interrupt, between two instructions. An IRQ
+    // is ignored while the I flag is set. Returns whether it was taken.
+    pub fn interrupt(&mut self, interrupt: Interrupt) -> bool {
+        if interrupt == Interrupt::Irq && self.flags.i() {
+            return false;
+        }
+        let (pc, sp) = (self.pc, self.sp);
+        self.push16(pc);
+        self.flags.set_b(false);
+        self.push8(self.flags.get());
+        self.flags.set_i(true);
+        let (kind, vector) = match interrupt {
+            Interrupt::Nmi => (FrameKind::Nmi, 0xFFFA),
+            Interrupt::Irq => (FrameKind::Irq, 0xFFFE),
+        };
+        self.pc = self.get_mem16(vector);
+        self.bus.tick(7);
+        self.call_stack.push(Frame {
+            kind,
+            caller: pc,
+            entry: self.pc,
+            sp,
+        });
+        true
+    }
+
     pub fn run_with_callback<F>(&mut self, mut f: F) -> Result<(), NesError>
     where
         F: FnMut(&mut CPU) -> Result<(), NesError>,
@@ -251,7 +313,7 @@ impl CPU {
     pub fn push8(&mut self, value: u8) {
         let addr = self.get_stack_top_addr();
         self.bus.write(addr, value);
-        self.sp -= 1;
+        self.sp = self.sp.wrapping_sub(1);
     }
 
     pub fn push16(&mut self, value: u16) {
@@ -262,7 +324,7 @@ impl CPU {
     }
 
     pub fn pop8(&mut self) -> u8 {
-        self.sp += 1;
+        self.sp = self.sp.wrapping_add(1);
         self.bus.read(self.get_stack_top_addr())
     }
 
@@ -281,10 +343,72 @@ impl CPU {
             sp: self.sp,
             pc: self.pc,
             flags: self.flags.clone(),
+            inst_details: self.inst_details(&inst),
             inst,
-            inst_details: None,
         })
     }
+
+    /**
+    The effective address of the operand of `inst` in the current state
+    and the value there, as nestest.log shows them:
+
+    ```text
+    STX $00 = 00
+    LDA $0300,Y @ 0300 = 89
+    LDA ($80,X) @ 80 = 0200 = 5A
+    LDA ($89),Y = 0300 @ 0300 = 89
+    JMP ($0200) = DB7E
+    ```
+
+    The value is left out for PPU registers, which can't be read without
+    side effects.
+    */
+    pub fn inst_details(&self, inst: &Inst) -> Option<String> {
+        let param = inst.param?;
+        let read = |addr| self.peek_mem(addr).unwrap_or(0);
+        let addr = match (inst.mode, inst.name.as_str()) {
+            (
+                AddressingMode::Implied
+                | AddressingMode::Accumulator
+                | AddressingMode::Immediate
+                | AddressingMode::Relative,
+                _,
+            )
+            | (AddressingMode::Absolute, "JMP" | "JSR") => return None,
+            (mode, _) => resolve_operand_addr(mode, self, param, read),
+        };
+        let value = self
+            .peek_mem(addr)
+            .map(|value| format!(" = {:02X}", value))
+            .unwrap_or_default();
+        let details = match inst.mode {
+            AddressingMode::ZeroPage | AddressingMode::Absolute => value.trim_start().to_string(),
+            AddressingMode::ZeroPageX | AddressingMode::ZeroPageY => {
+                format!("@ {:02X}{}", addr, value)
+            }
+            AddressingMode::AbsoluteX | AddressingMode::AbsoluteY => {
+                format!("@ {:04X}{}", addr, value)
+            }
+            AddressingMode::IndexedIndirect => {
+                format!(
+                    "@ {:02X} = {:04X}{}",
+                    (param as u8).wrapping_add(self.x),
+                    addr,
+                    value
+                )
+            }
+            AddressingMode::IndirectIndexed => {
+                format!(
+                    "= {:04X} @ {:04X}{}",
+                    addr.wrapping_sub(self.y as u16),
+                    addr,
+                    value
+                )
+            }
+            _ => format!("= {:04X}", addr),
+        };
+        Some(details).filter(|details| !details.is_empty())
+    }
 }
 
 #[derive(Clone, PartialEq)]
@@ -299,8 +423,9 @@ pub struct CpuState {
     inst_details: Option<String>,
 }
 
-impl Debug for CpuState {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+impl CpuState {
+    // The trace line with the addresses in `symbols` written as names.
+    pub fn format_with_symbols(&self, symbols: &SymbolTable) -> String {
         let inst_bytes = self
             .inst
             .to_bytes()
@@ -308,14 +433,17 @@ impl Debug for CpuState {
             .map(|b| format!("{:02X}", b))
             .collect::<Vec<String>>()
             .join(" ");
+        let name = |addr| symbols.get(addr).map(|name| name.to_string());
+        let mut inst = self.inst.to_string_with_names(Some(self.pc), name);
+        if let Some(details) = &self.inst_details {
+            inst = format!("{} {}", inst, details);
+        }
         // D10E  C1 80     CMP ($80,X) @ 80 = 0200 = 80    A:80 X:00 Y:69 P:A5 SP:FB
-        write!(
-            f,
-            "{:04X}  {:<8}  {:<11?}  {:<19} A:{:02X} X:{:02X} Y:{:02X} P:{:02X} SP:{:02X}",
+        format!(
+            "{:04X}  {:<8}  {:<31} A:{:02X} X:{:02X} Y:{:02X} P:{:02X} SP:{:02X}",
             self.pc,
             inst_bytes,
-            self.inst,
-            self.inst_details.clone().unwrap_or_default(),
+            inst,
             self.a,
             self.x,
             self.y,
@@ -325,6 +453,12 @@ impl Debug for CpuState {
     }
 }
 
+impl Debug for CpuState {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.format_with_symbols(&SymbolTable::default()))
+    }
+}
+
 #[derive(Error, Debug)]
 pub enum CpuStateParseError {
     #[error("Illegal input: {0}")]
@@ -392,6 +526,14 @@ impl FromStr for CpuState {
             });
         }
         parts = &parts[inst_str_parts.len()..];
+        // the effective address details end at the first register
+        let details_len = parts
+            .iter()
+            .position(|part| part.contains(':'))
+            .unwrap_or(parts.len());
+        let inst_details =
+            Some(parts[..details_len].join(" ")).filter(|details| !details.is_empty());
+        parts = &parts[details_len..];
         let mut res = CpuState {
             x: 0,
             y: 0,
@@ -400,7 +542,7 @@ impl FromStr for CpuState {
             pc,
             flags: Flags::default(),
             inst,
-            inst_details: None,
+            inst_details,
         };
 
         for part in parts.iter() {
@@ -430,3 +572,57 @@ impl FromStr for CpuState {
         Ok(res)
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn details(cpu: &CPU, opcode: u8, param: u16) -> Option<String> {
+        let inst = INST_FACTORIES_BY_OP_CODE[&opcode].make2(Some(param));
+        cpu.inst_details(&inst)
+    }
+
+    #[test]
+    fn test_inst_details() {
+        let mut cpu = CPU {
+            x: 2,
+            y: 0x10,
+            ..Default::default()
+        };
+        cpu.set_mem(0x80, 0x5a);
+        cpu.set_mem16(0x82, 0x0200);
+        cpu.set_mem(0x0200, 0x12);
+        cpu.set_mem(0x0210, 0x34);
+        cpu.set_mem16(0x0300, 0xdb7e);
+        assert_eq!(details(&cpu, 0x86, 0x80).as_deref(), Some("= 5A"));
+        assert_eq!(details(&cpu, 0xb5, 0x7e).as_deref(), Some("@ 80 = 5A"));
+        assert_eq!(details(&cpu, 0xb9, 0x0200).as_deref(), Some("@ 0210 = 34"));
+        assert_eq!(
+            details(&cpu, 0xa1, 0x80).as_deref(),
+            Some("@ 82 = 0200 = 12")
+        );
+        assert_eq!(
+            details(&cpu, 0xb1, 0x82).as_deref(),
+            Some("= 0200 @ 0210 = 34")
+        );
+        assert_eq!(details(&cpu, 0x6c, 0x0300).as_deref(), Some("= DB7E"));
+        assert_eq!(details(&cpu, 0x4c, 0x0300), None);
+        assert_eq!(details(&cpu, 0xa9, 0x01), None);
+        // PPU registers are not read
+        assert_eq!(details(&cpu, 0xad, 0x2002), None);
+        assert_eq!(details(&cpu, 0xbd, 0x2000).as_deref(), Some("@ 2002"));
+    }
+
+    #[test]
+    fn test_cpu_state() {
+        let line = "D0AA  B1 89     LDA ($89),Y = 0300 @ 0300 = 89  A:00 X:65 Y:00 P:27 SP:FB PPU: 22,139 CYC:2547";
+        let state = line.parse::<CpuState>().unwrap();
+        assert_eq!(state.inst_details.as_deref(), Some("= 0300 @ 0300 = 89"));
+        assert_eq!(format!("{:?}", state), line[..line.find(" PPU").unwrap()]);
+        let line = "C68B  8D 15 40  STA $4015 = FF                  A:02 X:FF Y:15 P:25 SP:FB";
+        let state = line.parse::<CpuState>().unwrap();
+        assert!(state
+            .format_with_symbols(&SymbolTable::hardware())
+            .starts_with("C68B  8D 15 40  STA SND_CHN = FF"));
+    }
+}
@@ -0,0 +1,176 @@
+use crate::cpu::addressing_mode::AddressingMode;
+use crate::cpu::CPU;
+use crate::instructions::Inst;
+
+// https://www.nesdev.org/wiki/6502_cycle_times
+#[rustfmt::skip]
+const BASE_CYCLES: [u8; 256] = [
+    7, 6, 2, 8, 3, 3, 5, 5, 3, 2, 2, 2, 4, 4, 6, 6,
+    2, 5, 2, 8, 4, 4, 6, 6, 2, 4, 2, 7, 4, 4, 7, 7,
+    6, 6, 2, 8, 3, 3, 5, 5, 4, 2, 2, 2, 4, 4, 6, 6,
+    2, 5, 2, 8, 4, 4, 6, 6, 2, 4, 2, 7, 4, 4, 7, 7,
+    6, 6, 2, 8, 3, 3, 5, 5, 3, 2, 2, 2, 3, 4, 6, 6,
+    2, 5, 2, 8, 4, 4, 6, 6, 2, 4, 2, 7, 4, 4, 7, 7,
+    6, 6, 2, 8, 3, 3, 5, 5, 4, 2, 2, 2, 5, 4, 6, 6,
+    2, 5, 2, 8, 4, 4, 6, 6, 2, 4, 2, 7, 4, 4, 7, 7,
+    2, 6, 2, 6, 3, 3, 3, 3, 2, 2, 2, 2, 4, 4, 4, 4,
+    2, 6, 2, 6, 4, 4, 4, 4, 2, 5, 2, 5, 5, 5, 5, 5,
+    2, 6, 2, 6, 3, 3, 3, 3, 2, 2, 2, 2, 4, 4, 4, 4,
+    2, 5, 2, 5, 4, 4, 4, 4, 2, 4, 2, 4, 4, 4, 4, 4,
+    2, 6, 2, 8, 3, 3, 5, 5, 2, 2, 2, 2, 4, 4, 6, 6,
+    2, 5, 2, 8, 4, 4, 6, 6, 2, 4, 2, 7, 4, 4, 7, 7,
+    2, 6, 2, 8, 3, 3, 5, 5, 2, 2, 2, 2, 4, 4, 6, 6,
+    2, 5, 2, 8, 4, 4, 6, 6, 2, 4, 2, 7, 4, 4, 7, 7,
+];
+
+// Read instructions take one extra cycle when the indexed address crosses a page.
+// Branches are handled separately since their penalty depends on the outcome.
+#[rustfmt::skip]
+const PAGE_CROSS_PENALTY: [u8; 256] = [
+    0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0,
+    0, 1, 0, 0, 0, 0, 0, 0, 0, 1, 0, 0, 1, 1, 0, 0,
+    0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0,
+    0, 1, 0, 0, 0, 0, 0, 0, 0, 1, 0, 0, 1, 1, 0, 0,
+    0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0,
+    0, 1, 0, 0, 0, 0, 0, 0, 0, 1, 0, 0, 1, 1, 0, 0,
+    0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0,
+    0, 1, 0, 0, 0, 0, 0, 0, 0, 1, 0, 0, 1, 1, 0, 0,
+    0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0,
+    0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0,
+    0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0,
+    0, 1, 0, 1, 0, 0, 0, 0, 0, 1, 0, 1, 1, 1, 1, 1,
+    0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0,
+    0, 1, 0, 0, 0, 0, 0, 0, 0, 1, 0, 0, 1, 1, 0, 0,
+    0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0,
+    0, 1, 0, 0, 0, 0, 0, 0, 0, 1, 0, 0, 1, 1, 0, 0,
+];
+
+fn page_crossed(a: u16, b: u16) -> bool {
+    a & 0xFF00 != b & 0xFF00
+}
+
+// Cycles known before the instruction runs: the base cost plus the
+// page crossing penalty of indexed reads.
+pub fn cycles_before_run(inst: &Inst, cpu: &CPU) -> u8 {
+    let mut cycles = BASE_CYCLES[inst.opcode as usize];
+    if PAGE_CROSS_PENALTY[inst.opcode as usize] == 0 {
+        return cycles;
+    }
+    let crossed = match inst.mode {
+        AddressingMode::AbsoluteX => {
+            let base = inst.param.unwrap();
+            page_crossed(base, base.wrapping_add(cpu.x as u16))
+        }
+        AddressingMode::AbsoluteY => {
+            let base = inst.param.unwrap();
+            page_crossed(base, base.wrapping_add(cpu.y as u16))
+        }
+        AddressingMode::IndirectIndexed => {
+            let zp = inst.param.unwrap() as u8;
+            // peeked, the instruction itself reads the pointer
+            let lo = cpu.peek_mem(zp as u16).unwrap_or(0) as u16;
+            let hi = cpu.peek_mem(zp.wrapping_add(1) as u16).unwrap_or(0) as u16;
+            let base = lo | (hi << 8);
+            page_crossed(base, base.wrapping_add(cpu.y as u16))
+        }
+        _ => false,
+    };
+    if crossed {
+        cycles += 1;
+    }
+    cycles
+}
+
+// Branches cost one more cycle when taken and another one when the
+// target is on a different page than the next instruction.
+pub fn branch_cycles(inst: &Inst, pc_before: u16, pc_after: u16) -> u8 {
+    if inst.mode != AddressingMode::Relative {
+        return 0;
+    }
+    let next = pc_before.wrapping_add(inst.len());
+    if pc_after == next {
+        0
+    } else if page_crossed(next, pc_after) {
+        2
+    } else {
+        1
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::cpu::CPU;
+    use crate::nes_format::read_nes_file;
+
+    fn run(cpu: &mut CPU, program: &[u8]) -> u64 {
+        cpu.load_program(program, 0x8000);
+        let before = cpu.cycles();
+        cpu.run_once().unwrap();
+        cpu.cycles() - before
+    }
+
+    #[test]
+    fn test_base_cycles() {
+        let mut cpu = CPU::default();
+        assert_eq!(run(&mut cpu, &[0xA9, 0x01]), 2);
+        assert_eq!(run(&mut cpu, &[0x8D, 0x00, 0x02]), 4);
+        assert_eq!(run(&mut cpu, &[0x20, 0x00, 0x90]), 6);
+    }
+
+    #[test]
+    fn test_page_cross() {
+        let mut cpu = CPU {
+            x: 0x01,
+            ..CPU::default()
+        };
+        assert_eq!(run(&mut cpu, &[0xBD, 0x00, 0x02]), 4);
+        assert_eq!(run(&mut cpu, &[0xBD, 0xFF, 0x02]), 5);
+        // stores always take the extra cycle
+        assert_eq!(run(&mut cpu, &[0x9D, 0x00, 0x02]), 5);
+        assert_eq!(run(&mut cpu, &[0x9D, 0xFF, 0x02]), 5);
+    }
+
+    #[test]
+    fn test_branch() {
+        let mut cpu = CPU::default();
+        cpu.flags.set_z(false);
+        assert_eq!(run(&mut cpu, &[0xF0, 0x10]), 2);
+        assert_eq!(run(&mut cpu, &[0xD0, 0x10]), 3);
+        assert_eq!(run(&mut cpu, &[0xD0, 0x80]), 4);
+    }
+
+    #[test]
+    fn test_write_cycle() {
+        let mut cpu = CPU::default();
+        cpu.bus.start_apu_log();
+        // LDA #$01 / STA $4015 / INC $4000
+        run(&mut cpu, &[0xA9, 0x01]);
+        run(&mut cpu, &[0x8D, 0x15, 0x40]);
+        run(&mut cpu, &[0xEE, 0x00, 0x40]);
+        let log = cpu.bus.take_apu_log().unwrap();
+        let cycles = log
+            .writes()
+            .iter()
+            .map(|write| write.cycle)
+            .collect::<Vec<_>>();
+        assert_eq!(cycles, vec![2 + 3, 6 + 5]);
+    }
+
+    // Every line of nestest.log ends with the cycles run before it.
+    #[test]
+    fn test_nestest_cycles() {
+        let dir = env!("CARGO_MANIFEST_DIR");
+        let nes = read_nes_file(&format!("{}/tests/nestest.nes", dir)).unwrap();
+        let log = std::fs::read_to_string(format!("{}/tests/nestest.log", dir)).unwrap();
+        let mut cpu = CPU::default();
+        cpu.load_program(&nes.prg_rom, 0x8000);
+        cpu.load_program(&nes.prg_rom, 0xC000);
+        cpu.reset();
+        cpu.pc = 0xC000;
+        for line in log.lines() {
+            let expected = line.rsplit_once("CYC:").unwrap().1.parse::<u64>().unwrap();
+            assert_eq!(cpu.cycles(), expected, "{}", line);
+            cpu.run_once().unwrap();
+        }
+    }
+}
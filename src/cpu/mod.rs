@@ -1,9 +1,11 @@
+mod call_stack;
 mod cpu;
 pub mod addressing_mode;
 #[cfg(test)]
 pub mod test_util;
-pub use cpu::{CPU, CpuState};
+pub use cpu::{CPU, CpuState, Interrupt};
 mod common;
+mod cycles;
 pub use common::{Mem, Register8, Flag, Setter, Retriever};
 #[cfg(test)]
 pub use common::{Register16, Flags, Stack};
\ No newline at end of file
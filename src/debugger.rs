@@ -0,0 +1,994 @@
+use std::io::{BufRead, Write};
+
+use error_stack::{bail, Result, ResultExt};
+
+use crate::assembler::Expr;
+use crate::bus::{AccessKind, AddressSpace};
+use crate::cpu::{Interrupt, CPU};
+use crate::error::NesError;
+use crate::instructions::{is_official_opcode, Inst, INST_FACTORIES_BY_OP_CODE};
+use crate::symbols::SymbolTable;
+
+// instructions `continue` runs before giving control back, so that a
+// program waiting in a loop doesn't hang the debugger
+const RUN_LIMIT: u64 = 10_000_000;
+// instructions `dis` shows before and after the address
+const DIS_BEFORE: usize = 3;
+const DIS_AFTER: usize = 7;
+const MEM_ROW: usize = 16;
+// the whole address space
+const MAX_DUMP: u32 = 0x10000;
+
+const HELP: &str = "\
+step [N]                (s)  run N instructions, 1 by default
+next                    (n)  run an instruction, a JSR until it returns
+finish                  (f)  run until the current subroutine returns
+continue                (c)  run until a breakpoint or the CPU halts
+break [RANGE [if COND]] (b)  set a breakpoint, or list all of them
+watch RANGE [if COND]        stop after a write to RANGE
+rwatch RANGE [if COND]       stop after a read of RANGE
+awatch RANGE [if COND]       stop after a read or a write of RANGE
+delete [ADDR]           (d)  remove the breakpoints at ADDR, or all of them
+backtrace               (bt) show the call stack
+nmi, irq                     enter the NMI or IRQ handler
+regs                    (r)  show the registers and flags
+set REG VALUE                set a, x, y, sp, pc, p or a flag: n v b d i z c
+mem ADDR [LEN]          (x)  dump memory, 64 bytes by default
+poke ADDR BYTE...       (w)  write bytes to memory
+dis [ADDR]              (l)  disassemble around an address, the PC by default
+quit                    (q)  leave the debugger
+An address is a symbol, $hex, 0xhex or decimal. A range is an address
+or ADDR-END, prefixed with ppu: for the PPU bus. A condition is an
+expression over the registers a x y sp pc p, the flags n v b d i z c,
+[ADDR] for the byte at ADDR, hits, and addr and value of the access, like
+`a == $10 && [$0300] > 3`. An empty line repeats the last command.";
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Trigger {
+    Execute,
+    Read,
+    Write,
+    Access,
+}
+
+impl Trigger {
+    // Whether an access triggers it, `None` for executing an instruction.
+    fn matches(&self, kind: Option<AccessKind>) -> bool {
+        matches!(
+            (self, kind),
+            (Trigger::Execute, None)
+                | (Trigger::Read, Some(AccessKind::Read))
+                | (Trigger::Write, Some(AccessKind::Write))
+                | (Trigger::Access, Some(_))
+        )
+    }
+}
+
+/**
+A breakpoint, or a watchpoint on memory, covering `start..=end` of a bus.
+`hits` counts the times it was triggered, and it stops the CPU when its
+condition holds, or always without one.
+*/
+struct Breakpoint {
+    trigger: Trigger,
+    space: AddressSpace,
+    start: u16,
+    end: u16,
+    condition: Option<(String, Expr)>,
+    hits: u64,
+}
+
+/**
+An interactive debugger for a program loaded in a CPU, driven by one
+command per line, see `HELP`. Addresses are shown and can be given with
+the names of a symbol table.
+
+The CPU stops before an instruction at a breakpoint, after an instruction
+that reads or writes memory under a watchpoint, when it halts on `KIL`,
+or when an opcode can't be decoded.
+*/
+pub struct Debugger {
+    cpu: CPU,
+    symbols: SymbolTable,
+    breakpoints: Vec<Breakpoint>,
+    last_command: String,
+}
+
+// The printable form of a flag register, upper case for the flags that
+// are set: `nv-bdIzc`.
+fn format_flags(p: u8) -> String {
+    "nv-bdizc"
+        .chars()
+        .enumerate()
+        .map(|(i, c)| {
+            if p & (0x80 >> i) != 0 {
+                c.to_ascii_uppercase()
+            } else {
+                c
+            }
+        })
+        .collect()
+}
+
+fn parse_number(s: &str) -> Option<u32> {
+    if let Some(hex) = s.strip_prefix('$').or_else(|| s.strip_prefix("0x")) {
+        return u32::from_str_radix(hex, 16).ok();
+    }
+    s.parse().ok()
+}
+
+impl Debugger {
+    pub fn new(cpu: CPU, symbols: SymbolTable) -> Self {
+        cpu.bus.start_access_log();
+        Debugger {
+            cpu,
+            symbols,
+            breakpoints: vec![],
+            last_command: String::new(),
+        }
+    }
+
+    // Reads commands from stdin until `quit` or the end of the input.
+    pub fn run(&mut self) -> Result<(), NesError> {
+        let stdin = std::io::stdin();
+        let mut stdout = std::io::stdout();
+        println!("{}", self.state());
+        loop {
+            print!("(nes) ");
+            stdout.flush().change_context(NesError::Io)?;
+            let mut line = String::new();
+            if stdin
+                .lock()
+                .read_line(&mut line)
+                .change_context(NesError::Io)?
+                == 0
+            {
+                return Ok(());
+            }
+            let line = line.trim();
+            if matches!(line, "q" | "quit") {
+                return Ok(());
+            }
+            let output = self.execute(line);
+            if !output.is_empty() {
+                println!("{}", output);
+            }
+        }
+    }
+
+    // Runs one command and returns what it prints.
+    pub fn execute(&mut self, line: &str) -> String {
+        let line = match line.trim() {
+            "" => self.last_command.clone(),
+            line => line.to_string(),
+        };
+        self.last_command = line.clone();
+        let words = line.split_whitespace().collect::<Vec<_>>();
+        let Some((&command, args)) = words.split_first() else {
+            return String::new();
+        };
+        let res = match command {
+            "h" | "help" => Ok(HELP.to_string()),
+            "s" | "step" => self.step(args),
+            "n" | "next" => Ok(self.next()),
+            "f" | "finish" => Ok(self.finish()),
+            "c" | "continue" => Ok(self.run_until(|_, _| false)),
+            "b" | "break" => self.set_breakpoint(Trigger::Execute, args),
+            "watch" => self.set_breakpoint(Trigger::Write, args),
+            "rwatch" => self.set_breakpoint(Trigger::Read, args),
+            "awatch" => self.set_breakpoint(Trigger::Access, args),
+            "d" | "delete" => self.delete_breakpoint(args),
+            "bt" | "backtrace" => Ok(self.backtrace()),
+            "nmi" => Ok(self.interrupt(Interrupt::Nmi)),
+            "irq" => Ok(self.interrupt(Interrupt::Irq)),
+            "r" | "regs" => Ok(self.registers()),
+            "set" => self.set_register(args),
+            "x" | "mem" => self.dump(args),
+            "w" | "poke" => self.poke(args),
+            "l" | "dis" => self.disassemble(args),
+            _ => Err(format!("unknown command {}, try help", command)),
+        };
+        res.unwrap_or_else(|message| format!("error: {}", message))
+    }
+
+    fn parse_addr(&self, s: &str) -> std::result::Result<u16, String> {
+        if let Some(addr) = self.symbols.lookup(s) {
+            return Ok(addr);
+        }
+        parse_number(s)
+            .and_then(|n| u16::try_from(n).ok())
+            .ok_or_else(|| format!("bad address {}", s))
+    }
+
+    // `ADDR`, `ADDR-END`, or either prefixed with `ppu:`.
+    fn parse_range(&self, s: &str) -> std::result::Result<(AddressSpace, u16, u16), String> {
+        let (space, range) = match s.strip_prefix("ppu:") {
+            Some(range) => (AddressSpace::Ppu, range),
+            None => (AddressSpace::Cpu, s),
+        };
+        let (start, end) = match range.split_once('-') {
+            Some((start, end)) => (self.parse_addr(start)?, self.parse_addr(end)?),
+            None => (self.parse_addr(range)?, self.parse_addr(range)?),
+        };
+        if end < start {
+            return Err(format!("bad range {}", s));
+        }
+        Ok((space, start, end))
+    }
+
+    fn parse_byte(&self, s: &str) -> std::result::Result<u8, String> {
+        parse_number(s)
+            .and_then(|n| u8::try_from(n).ok())
+            .ok_or_else(|| format!("bad byte {}", s))
+    }
+
+    fn name(&self, addr: u16) -> String {
+        match self.symbols.get(addr) {
+            Some(name) => format!("{} (${:04X})", name, addr),
+            None => format!("${:04X}", addr),
+        }
+    }
+
+    fn format_range(&self, space: AddressSpace, start: u16, end: u16) -> String {
+        match (space, start == end) {
+            (AddressSpace::Cpu, true) => self.name(start),
+            (AddressSpace::Cpu, false) => format!("${:04X}-${:04X}", start, end),
+            (AddressSpace::Ppu, true) => format!("ppu:${:04X}", start),
+            (AddressSpace::Ppu, false) => format!("ppu:${:04X}-${:04X}", start, end),
+        }
+    }
+
+    // `breakpoint at loop ($0605)`, `watchpoint on writes to $0300 if a == 1`.
+    fn describe(&self, breakpoint: &Breakpoint) -> String {
+        let range = self.format_range(breakpoint.space, breakpoint.start, breakpoint.end);
+        let text = match breakpoint.trigger {
+            Trigger::Execute => format!("breakpoint at {}", range),
+            Trigger::Read => format!("watchpoint on reads of {}", range),
+            Trigger::Write => format!("watchpoint on writes to {}", range),
+            Trigger::Access => format!("watchpoint on accesses to {}", range),
+        };
+        match &breakpoint.condition {
+            Some((source, _)) => format!("{} if {}", text, source),
+            None => text,
+        }
+    }
+
+    // The value of `name` in a breakpoint condition: a register, a flag,
+    // `hits`, the `addr` and `value` of the access, or a symbol.
+    fn lookup(&self, name: &str, hits: u64, addr: u16, value: u8) -> Result<i64, NesError> {
+        let cpu = &self.cpu;
+        let flag = |bit: u8| ((cpu.flags.get() >> bit) & 1) as i64;
+        Ok(match name.to_lowercase().as_str() {
+            "a" => cpu.a as i64,
+            "x" => cpu.x as i64,
+            "y" => cpu.y as i64,
+            "sp" => cpu.sp as i64,
+            "pc" => cpu.pc as i64,
+            "p" => cpu.flags.get() as i64,
+            "n" => flag(7),
+            "v" => flag(6),
+            "b" => flag(4),
+            "d" => flag(3),
+            "i" => flag(2),
+            "z" => flag(1),
+            "c" => flag(0),
+            "hits" => hits as i64,
+            "addr" => addr as i64,
+            "value" => value as i64,
+            _ => match self.symbols.lookup(name) {
+                Some(addr) => addr as i64,
+                None => bail!(NesError::DebuggerFailure(format!("unknown name {}", name))),
+            },
+        })
+    }
+
+    // `None` when the condition can't be evaluated, for example because
+    // it reads memory that can't be peeked at.
+    fn eval_condition(&self, condition: &Expr, hits: u64, addr: u16, value: u8) -> Option<bool> {
+        let lookup = |name: &str| self.lookup(name, hits, addr, value);
+        let value = condition
+            .eval_with_memory(&lookup, self.cpu.pc, &|addr| self.cpu.peek_mem(addr))
+            .ok()?;
+        Some(value != 0)
+    }
+
+    /**
+    Counts the hits of the breakpoints triggered by the instruction that
+    just ran at `pc`, from its reads and writes and the PC it left, and
+    returns why the first one whose condition holds stops the CPU:
+
+    ```text
+    watchpoint: $05 written to $0010 by sub ($0612)
+    ```
+    */
+    fn check_breakpoints(&mut self, pc: u16) -> Option<String> {
+        let opcode = self.cpu.peek_mem(self.cpu.pc).unwrap_or(0);
+        let accesses = self
+            .cpu
+            .bus
+            .take_accesses()
+            .into_iter()
+            .map(|access| (Some(access.kind), access.space, access.addr, access.value))
+            .chain(std::iter::once((
+                None,
+                AddressSpace::Cpu,
+                self.cpu.pc,
+                opcode,
+            )));
+        let mut stop = None;
+        for (kind, space, addr, value) in accesses {
+            for i in 0..self.breakpoints.len() {
+                let breakpoint = &mut self.breakpoints[i];
+                if breakpoint.space != space
+                    || !(breakpoint.start..=breakpoint.end).contains(&addr)
+                    || !breakpoint.trigger.matches(kind)
+                {
+                    continue;
+                }
+                breakpoint.hits += 1;
+                let breakpoint = &self.breakpoints[i];
+                let holds = match &breakpoint.condition {
+                    Some((_, condition)) => {
+                        self.eval_condition(condition, breakpoint.hits, addr, value)
+                    }
+                    None => Some(true),
+                };
+                if stop.is_some() || holds == Some(false) {
+                    continue;
+                }
+                let location = self.format_range(space, addr, addr);
+                stop = Some(match (holds, kind) {
+                    (None, _) => format!(
+                        "can't evaluate the condition of the {}",
+                        self.describe(breakpoint)
+                    ),
+                    (_, None) => format!("breakpoint at {}", location),
+                    (_, Some(AccessKind::Read)) => {
+                        format!(
+                            "watchpoint: ${:02X} read from {} by {}",
+                            value,
+                            location,
+                            self.name(pc)
+                        )
+                    }
+                    (_, Some(AccessKind::Write)) => {
+                        format!(
+                            "watchpoint: ${:02X} written to {} by {}",
+                            value,
+                            location,
+                            self.name(pc)
+                        )
+                    }
+                });
+            }
+        }
+        stop
+    }
+
+    // The trace line of the instruction at PC.
+    fn state(&self) -> String {
+        match self.cpu.trace() {
+            Ok(state) => state.format_with_symbols(&self.symbols),
+            Err(_) => format!("{:04X}  ???  {}", self.cpu.pc, self.registers()),
+        }
+    }
+
+    /**
+    Runs instructions until `done` holds after one of them, given the
+    CPU and the instruction, or the CPU stops on its own. Returns why it
+    stopped and the state:
+
+    ```text
+    breakpoint at loop ($0610)
+    0610  CA        DEX                             A:00 X:04 Y:00 P:24 SP:FD
+    ```
+    */
+    fn run_until(&mut self, mut done: impl FnMut(&CPU, &Inst) -> bool) -> String {
+        for _ in 0..RUN_LIMIT {
+            let pc = self.cpu.pc;
+            let inst = match self.cpu.decode() {
+                Ok(inst) if !self.cpu.halt => inst,
+                Ok(_) => return format!("halted at {}\n{}", self.name(pc), self.backtrace()),
+                Err(_) => {
+                    return format!(
+                        "unknown opcode at {}\n{}\n{}",
+                        self.name(pc),
+                        self.registers(),
+                        self.backtrace()
+                    )
+                }
+            };
+            // accesses made by debugger commands don't count
+            self.cpu.bus.take_accesses();
+            if self.cpu.run_once().is_err() {
+                return format!(
+                    "halted at {}\n{}\n{}",
+                    self.name(pc),
+                    self.state(),
+                    self.backtrace()
+                );
+            }
+            if let Some(reason) = self.check_breakpoints(pc) {
+                return format!("{}\n{}", reason, self.state());
+            }
+            if done(&self.cpu, &inst) {
+                return self.state();
+            }
+        }
+        format!("stopped after {} instructions\n{}", RUN_LIMIT, self.state())
+    }
+
+    fn step(&mut self, args: &[&str]) -> std::result::Result<String, String> {
+        let count = match args.first() {
+            Some(count) => count
+                .parse::<u64>()
+                .map_err(|_| format!("bad count {}", count))?,
+            None => 1,
+        };
+        let mut steps = 0;
+        Ok(self.run_until(|_, _| {
+            steps += 1;
+            steps >= count
+        }))
+    }
+
+    fn next(&mut self) -> String {
+        match self.cpu.decode() {
+            Ok(inst) if inst.name == "JSR" => {
+                let (ret, sp) = (self.cpu.pc.wrapping_add(inst.len()), self.cpu.sp);
+                self.run_until(|cpu, _| cpu.pc == ret && cpu.sp == sp)
+            }
+            _ => self.run_until(|_, _| true),
+        }
+    }
+
+    fn finish(&mut self) -> String {
+        let sp = self.cpu.sp;
+        self.run_until(|cpu, inst| matches!(inst.name.as_str(), "RTS" | "RTI") && cpu.sp > sp)
+    }
+
+    fn set_breakpoint(
+        &mut self,
+        trigger: Trigger,
+        args: &[&str],
+    ) -> std::result::Result<String, String> {
+        let Some((range, rest)) = args.split_first() else {
+            if trigger != Trigger::Execute {
+                return Err("usage: watch RANGE [if COND]".to_string());
+            }
+            if self.breakpoints.is_empty() {
+                return Ok("no breakpoints".to_string());
+            }
+            let lines = self
+                .breakpoints
+                .iter()
+                .map(|breakpoint| {
+                    let plural = if breakpoint.hits == 1 { "" } else { "s" };
+                    format!(
+                        "{}, {} hit{}",
+                        self.describe(breakpoint),
+                        breakpoint.hits,
+                        plural
+                    )
+                })
+                .collect::<Vec<_>>();
+            return Ok(lines.join("\n"));
+        };
+        let (space, start, end) = self.parse_range(range)?;
+        if trigger == Trigger::Execute && space == AddressSpace::Ppu {
+            return Err("the PPU doesn't execute code, try watch".to_string());
+        }
+        let condition = match rest.split_first() {
+            None => None,
+            Some((&"if", condition)) if !condition.is_empty() => {
+                let source = condition.join(" ");
+                let expr = Expr::parse(&source).map_err(|_| format!("bad condition {}", source))?;
+                // memory is only read when the breakpoint is hit
+                if let Some(name) = expr
+                    .symbols()
+                    .into_iter()
+                    .find(|name| self.lookup(name, 0, start, 0).is_err())
+                {
+                    return Err(format!("unknown name {} in condition {}", name, source));
+                }
+                Some((source, expr))
+            }
+            _ => return Err("expected if CONDITION after the address".to_string()),
+        };
+        let breakpoint = Breakpoint {
+            trigger,
+            space,
+            start,
+            end,
+            condition,
+            hits: 0,
+        };
+        let text = self.describe(&breakpoint);
+        self.breakpoints.push(breakpoint);
+        Ok(text)
+    }
+
+    fn delete_breakpoint(&mut self, args: &[&str]) -> std::result::Result<String, String> {
+        let Some(arg) = args.first() else {
+            self.breakpoints.clear();
+            return Ok("deleted all breakpoints".to_string());
+        };
+        let (space, addr, _) = self.parse_range(arg)?;
+        let (deleted, kept) = std::mem::take(&mut self.breakpoints)
+            .into_iter()
+            .partition::<Vec<_>, _>(|breakpoint| {
+                breakpoint.space == space && breakpoint.start == addr
+            });
+        self.breakpoints = kept;
+        if deleted.is_empty() {
+            return Err(format!(
+                "no breakpoint at {}",
+                self.format_range(space, addr, addr)
+            ));
+        }
+        let lines = deleted
+            .iter()
+            .map(|breakpoint| format!("deleted {}", self.describe(breakpoint)))
+            .collect::<Vec<_>>();
+        Ok(lines.join("\n"))
+    }
+
+    // The call stack from the PC, and the last return that didn't match a
+    // call.
+    fn backtrace(&self) -> String {
+        let call_stack = &self.cpu.call_stack;
+        let backtrace = call_stack.backtrace(self.cpu.pc, &self.symbols);
+        match call_stack.last_mismatch() {
+            Some(mismatch) => format!(
+                "{}\nlast mismatch: {}",
+                backtrace,
+                mismatch.format_with_symbols(&self.symbols)
+            ),
+            None => backtrace,
+        }
+    }
+
+    fn interrupt(&mut self, interrupt: Interrupt) -> String {
+        match self.cpu.interrupt(interrupt) {
+            true => self.state(),
+            false => "the I flag masks IRQs".to_string(),
+        }
+    }
+
+    fn registers(&self) -> String {
+        let cpu = &self.cpu;
+        format!(
+            "A:{:02X} X:{:02X} Y:{:02X} SP:{:02X} PC:{:04X} P:{:02X} {} CYC:{}",
+            cpu.a,
+            cpu.x,
+            cpu.y,
+            cpu.sp,
+            cpu.pc,
+            cpu.flags.get(),
+            format_flags(cpu.flags.get()),
+            cpu.cycles()
+        )
+    }
+
+    fn set_register(&mut self, args: &[&str]) -> std::result::Result<String, String> {
+        let [reg, value] = args else {
+            return Err("usage: set REG VALUE".to_string());
+        };
+        let reg = reg.to_lowercase();
+        if reg == "pc" {
+            self.cpu.pc = self.parse_addr(value)?;
+            return Ok(self.state());
+        }
+        if let Some(bit) = "nv-bdizc"
+            .find(reg.as_str())
+            .filter(|_| reg.len() == 1 && reg != "-")
+        {
+            let mask = 0x80 >> bit;
+            match *value {
+                "0" => self.cpu.flags.set(self.cpu.flags.get() & !mask),
+                "1" => self.cpu.flags.set(self.cpu.flags.get() | mask),
+                _ => return Err(format!("bad flag value {}, 0 or 1", value)),
+            }
+            return Ok(self.registers());
+        }
+        let value = self.parse_byte(value)?;
+        match reg.as_str() {
+            "a" => self.cpu.a = value,
+            "x" => self.cpu.x = value,
+            "y" => self.cpu.y = value,
+            "sp" => self.cpu.sp = value,
+            "p" => self.cpu.flags.set(value),
+            _ => return Err(format!("unknown register {}", reg)),
+        }
+        Ok(self.registers())
+    }
+
+    /**
+    A hex dump, with `??` for the PPU registers that can't be read without
+    side effects:
+
+    ```text
+    0200  01 02 03 00 00 00 00 00 00 00 00 00 00 00 00 00  ................
+    ```
+    */
+    fn dump(&self, args: &[&str]) -> std::result::Result<String, String> {
+        let Some(addr) = args.first() else {
+            return Err("usage: mem ADDR [LEN]".to_string());
+        };
+        let addr = self.parse_addr(addr)?;
+        let len = match args.get(1) {
+            Some(len) => match parse_number(len) {
+                Some(n) if n <= MAX_DUMP => n as usize,
+                Some(_) => return Err(format!("length {} is more than ${:X}", len, MAX_DUMP)),
+                None => return Err(format!("bad length {}", len)),
+            },
+            None => 64,
+        };
+        let mut rows = vec![];
+        for row in (0..len).step_by(MEM_ROW) {
+            let start = addr.wrapping_add(row as u16);
+            let values = (0..MEM_ROW.min(len - row))
+                .map(|i| self.cpu.peek_mem(start.wrapping_add(i as u16)))
+                .collect::<Vec<_>>();
+            let hex = values
+                .iter()
+                .map(|value| {
+                    value
+                        .map(|b| format!("{:02X}", b))
+                        .unwrap_or("??".to_string())
+                })
+                .collect::<Vec<_>>();
+            let text = values
+                .iter()
+                .map(|value| match value {
+                    Some(b @ 0x20..=0x7e) => *b as char,
+                    _ => '.',
+                })
+                .collect::<String>();
+            rows.push(format!("{:04X}  {:<47}  {}", start, hex.join(" "), text));
+        }
+        Ok(rows.join("\n"))
+    }
+
+    fn poke(&mut self, args: &[&str]) -> std::result::Result<String, String> {
+        let Some((addr, bytes)) = args.split_first().filter(|(_, bytes)| !bytes.is_empty()) else {
+            return Err("usage: poke ADDR BYTE...".to_string());
+        };
+        let addr = self.parse_addr(addr)?;
+        let bytes = bytes
+            .iter()
+            .map(|b| self.parse_byte(b))
+            .collect::<std::result::Result<Vec<_>, _>>()?;
+        for (i, &b) in bytes.iter().enumerate() {
+            self.cpu.bus.write(addr.wrapping_add(i as u16), b);
+        }
+        Ok(format!(
+            "wrote {} bytes at {}",
+            bytes.len(),
+            self.name(addr)
+        ))
+    }
+
+    fn decode_at(&self, addr: u16) -> Option<Inst> {
+        let opcode = self.cpu.peek_mem(addr)?;
+        let factory = INST_FACTORIES_BY_OP_CODE
+            .get(&opcode)
+            .filter(|_| is_official_opcode(opcode))?;
+        let operand = (1..factory.mode.get_inst_size())
+            .map(|i| self.cpu.peek_mem(addr.wrapping_add(i)).unwrap_or(0));
+        Some(factory.make(operand))
+    }
+
+    // The addresses of up to `count` instructions that end right before
+    // `addr`. Code can't be decoded backwards, so this takes the longest
+    // run of instructions from a few bytes back that lands on `addr`.
+    fn insts_before(&self, addr: u16, count: usize) -> Vec<u16> {
+        for back in (1..=count * 3).rev() {
+            let mut pc = addr.wrapping_sub(back as u16);
+            let mut addrs = vec![];
+            while pc != addr && (addr.wrapping_sub(pc) as usize) <= back {
+                let Some(inst) = self.decode_at(pc) else {
+                    break;
+                };
+                addrs.push(pc);
+                pc = pc.wrapping_add(inst.len());
+            }
+            if pc == addr && !addrs.is_empty() {
+                return addrs[addrs.len().saturating_sub(count)..].to_vec();
+            }
+        }
+        vec![]
+    }
+
+    /**
+    The instructions around an address, with the PC marked:
+
+    ```text
+    loop:
+        0610  CA        DEX
+    >   0611  D0 FD     BNE loop
+        0613  60        RTS
+    ```
+    */
+    fn disassemble(&self, args: &[&str]) -> std::result::Result<String, String> {
+        let addr = match args.first() {
+            Some(addr) => self.parse_addr(addr)?,
+            None => self.cpu.pc,
+        };
+        let mut addrs = self.insts_before(addr, DIS_BEFORE);
+        let mut pc = addr;
+        for _ in 0..=DIS_AFTER {
+            addrs.push(pc);
+            pc = pc.wrapping_add(self.decode_at(pc).map(|inst| inst.len()).unwrap_or(1));
+        }
+        let mut lines = vec![];
+        for addr in addrs {
+            if let Some(name) = self.symbols.get(addr) {
+                lines.push(format!("{}:", name));
+            }
+            let marker = if addr == self.cpu.pc { ">" } else { " " };
+            let (bytes, text) = match self.decode_at(addr) {
+                Some(inst) => {
+                    let bytes = inst
+                        .to_bytes()
+                        .iter()
+                        .map(|b| format!("{:02X}", b))
+                        .collect::<Vec<_>>()
+                        .join(" ");
+                    let name = |addr| self.symbols.get(addr).map(|name| name.to_string());
+                    (bytes, inst.to_string_with_names(Some(addr), name))
+                }
+                None => match self.cpu.peek_mem(addr) {
+                    Some(b) => (format!("{:02X}", b), format!(".byte ${:02X}", b)),
+                    None => ("??".to_string(), String::new()),
+                },
+            };
+            lines.push(
+                format!("{}   {:04X}  {:<8}  {}", marker, addr, bytes, text)
+                    .trim_end()
+                    .to_string(),
+            );
+        }
+        Ok(lines.join("\n"))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    // $0600: JSR sub / LDX #$03 / loop: DEX / BNE loop / KIL
+    // $0610: sub: LDA #$05 / STA $10 / RTS
+    fn debugger() -> Debugger {
+        let mut code = vec![0x20, 0x10, 0x06, 0xa2, 0x03, 0xca, 0xd0, 0xfd, 0x02];
+        code.resize(0x10, 0xea);
+        code.extend([0xa9, 0x05, 0x85, 0x10, 0x60]);
+        let mut cpu = CPU::default();
+        cpu.load_program(&code, 0x0600);
+        cpu.reset();
+        let mut symbols = SymbolTable::default();
+        symbols.insert(0x0605, "loop");
+        symbols.insert(0x0610, "sub");
+        Debugger::new(cpu, symbols)
+    }
+
+    #[test]
+    fn test_step() {
+        let mut debugger = debugger();
+        assert!(debugger
+            .execute("step")
+            .starts_with("0610  A9 05     LDA #$05"));
+        assert!(debugger
+            .execute("finish")
+            .starts_with("0603  A2 03     LDX #$03"));
+        assert_eq!(debugger.cpu.peek_mem(0x10), Some(5));
+        debugger.execute("s 2");
+        assert_eq!((debugger.cpu.pc, debugger.cpu.x), (0x0606, 2));
+        // an empty line repeats the command
+        debugger.execute("");
+        assert_eq!((debugger.cpu.pc, debugger.cpu.x), (0x0606, 1));
+        let mut debugger = self::debugger();
+        assert!(debugger.execute("next").starts_with("0603"));
+        assert_eq!(debugger.cpu.a, 5);
+    }
+
+    #[test]
+    fn test_breakpoints() {
+        let mut debugger = debugger();
+        assert_eq!(debugger.execute("break loop"), "breakpoint at loop ($0605)");
+        assert!(debugger
+            .execute("c")
+            .starts_with("breakpoint at loop ($0605)\n0605  CA        DEX"));
+        assert_eq!(debugger.cpu.x, 3);
+        debugger.execute("c");
+        assert_eq!(debugger.cpu.x, 2);
+        assert_eq!(debugger.execute("b"), "breakpoint at loop ($0605), 2 hits");
+        assert_eq!(
+            debugger.execute("delete $0605"),
+            "deleted breakpoint at loop ($0605)"
+        );
+        assert!(debugger.execute("c").starts_with("halted at $0608"));
+        assert!(debugger.execute("d 1").starts_with("error: no breakpoint"));
+    }
+
+    #[test]
+    fn test_watchpoints() {
+        let mut debugger = debugger();
+        assert_eq!(
+            debugger.execute("watch $10"),
+            "watchpoint on writes to $0010"
+        );
+        assert!(debugger
+            .execute("c")
+            .starts_with("watchpoint: $05 written to $0010 by $0612\n0614  60        RTS"));
+        debugger.execute("d $10");
+        // RTS pops the return address, low byte first
+        debugger.execute("rwatch $0100-$01FF");
+        assert!(debugger
+            .execute("c")
+            .starts_with("watchpoint: $02 read from $01FC by $0614\n0603"));
+        debugger.execute("d");
+        assert_eq!(
+            debugger.execute("b loop if x == 1 && [$10] == 5"),
+            "breakpoint at loop ($0605) if x == 1 && [$10] == 5"
+        );
+        assert!(debugger
+            .execute("c")
+            .starts_with("breakpoint at loop ($0605)"));
+        assert_eq!(debugger.cpu.x, 1);
+        assert_eq!(
+            debugger.execute("b"),
+            "breakpoint at loop ($0605) if x == 1 && [$10] == 5, 3 hits"
+        );
+        assert_eq!(
+            debugger.execute("b loop if"),
+            "error: expected if CONDITION after the address"
+        );
+        assert_eq!(
+            debugger.execute("b loop if x =="),
+            "error: bad condition x =="
+        );
+        assert_eq!(
+            debugger.execute("b loop if nope"),
+            "error: unknown name nope in condition nope"
+        );
+        // PPU registers can't be peeked at, which only matters on a hit
+        let mut debugger = self::debugger();
+        assert_eq!(
+            debugger.execute("b loop if [$2002] & $80"),
+            "breakpoint at loop ($0605) if [$2002] & $80"
+        );
+        assert!(debugger
+            .execute("c")
+            .starts_with("can't evaluate the condition of the breakpoint at loop ($0605)"));
+        // and neither can an overflowing division
+        let mut debugger = self::debugger();
+        debugger.execute("b loop if (1<<63)/-1");
+        assert!(debugger
+            .execute("c")
+            .starts_with("can't evaluate the condition of the breakpoint at loop ($0605)"));
+        assert!(debugger.execute("b ppu:$2000").starts_with("error"));
+        assert_eq!(
+            debugger.execute("watch $20-$10"),
+            "error: bad range $20-$10"
+        );
+    }
+
+    #[test]
+    fn test_ppu_watchpoints() {
+        // LDA #$21 / STA $2006 / LDA #$00 / STA $2006 / LDA #$42 / STA $2007 / KIL
+        let code = [
+            0xa9, 0x21, 0x8d, 0x06, 0x20, 0xa9, 0x00, 0x8d, 0x06, 0x20, 0xa9, 0x42, 0x8d, 0x07,
+            0x20, 0x02,
+        ];
+        let mut cpu = CPU::default();
+        cpu.load_program(&code, 0x0600);
+        cpu.reset();
+        let mut debugger = Debugger::new(cpu, SymbolTable::default());
+        assert_eq!(
+            debugger.execute("awatch ppu:$2000-$23FF if value > 1"),
+            "watchpoint on accesses to ppu:$2000-$23FF if value > 1"
+        );
+        assert!(debugger
+            .execute("c")
+            .starts_with("watchpoint: $42 written to ppu:$2100 by $060C"));
+    }
+
+    #[test]
+    fn test_backtrace() {
+        let mut debugger = debugger();
+        debugger.execute("s 2");
+        assert_eq!(
+            debugger.execute("bt"),
+            "#0  $0612 in sub ($0610)\n#1  $0600"
+        );
+        // a BRK into an RTI at $0700
+        debugger.cpu.bus.write(0x0700, 0x40);
+        debugger.cpu.set_mem16(0xfffe, 0x0700);
+        debugger.execute("set i 0");
+        assert!(debugger.execute("irq").starts_with("0700  40        RTI"));
+        assert_eq!(
+            debugger.execute("bt"),
+            "#0  $0700 in $0700 [IRQ]\n#1  $0612 in sub ($0610)\n#2  $0600"
+        );
+        debugger.execute("s");
+        assert_eq!(
+            debugger.execute("bt"),
+            "#0  $0612 in sub ($0610)\n#1  $0600"
+        );
+        debugger.execute("set i 1");
+        assert_eq!(debugger.execute("irq"), "the I flag masks IRQs");
+        // an NMI handler leaving with PLA / RTS
+        debugger.cpu.bus.write(0x0700, 0x68);
+        debugger.cpu.bus.write(0x0701, 0x60);
+        debugger.cpu.set_mem16(0xfffa, 0x0700);
+        debugger.execute("nmi");
+        debugger.execute("s 2");
+        assert!(debugger
+            .execute("bt")
+            .starts_with("#0  $0613 in sub ($0610)\n#1  $0600\n"));
+        assert!(debugger
+            .execute("bt")
+            .ends_with("last mismatch: RTS at $0701 returned to $0613 out of $0700"));
+        let mut debugger = self::debugger();
+        let text = debugger.execute("c");
+        assert!(
+            text.starts_with("halted at $0608\n0608  02        KIL"),
+            "{}",
+            text
+        );
+        assert!(text.ends_with("\n#0  $0608"));
+    }
+
+    #[test]
+    fn test_registers_and_memory() {
+        let mut debugger = debugger();
+        assert_eq!(
+            debugger.execute("set a $42"),
+            "A:42 X:00 Y:00 SP:FD PC:0600 P:24 nv-bdIzc CYC:7"
+        );
+        assert!(debugger.execute("set c 1").contains("P:25 nv-bdIzC"));
+        assert!(debugger
+            .execute("set q 1")
+            .starts_with("error: unknown register"));
+        assert_eq!(
+            debugger.execute("poke $0200 $48 105"),
+            "wrote 2 bytes at $0200"
+        );
+        assert_eq!(
+            debugger.execute("mem $0200 4"),
+            format!("0200  {:<47}  Hi..", "48 69 00 00")
+        );
+        assert!(debugger.execute("mem $2000 1").starts_with("2000  ??"));
+        assert_eq!(debugger.execute("mem $0000 $10000").lines().count(), 0x1000);
+        assert_eq!(
+            debugger.execute("mem 0 $10001"),
+            "error: length $10001 is more than $10000"
+        );
+        debugger.execute("set pc loop");
+        assert_eq!(debugger.cpu.pc, 0x0605);
+        assert_eq!(
+            debugger.execute("frob"),
+            "error: unknown command frob, try help"
+        );
+    }
+
+    #[test]
+    fn test_disassemble() {
+        let mut debugger = debugger();
+        debugger.execute("set pc $0606");
+        let text = debugger.execute("dis");
+        let lines = text.lines().take(5).collect::<Vec<_>>();
+        assert_eq!(
+            lines,
+            vec![
+                "    0600  20 10 06  JSR sub",
+                "    0603  A2 03     LDX #$03",
+                "loop:",
+                "    0605  CA        DEX",
+                ">   0606  D0 FD     BNE loop",
+            ]
+        );
+        assert!(text.contains("    0608  02        .byte $02"));
+    }
+}
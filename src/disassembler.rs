@@ -0,0 +1,679 @@
+use std::collections::BTreeMap;
+
+use error_stack::{bail, Result};
+
+use crate::assembler::{parse_source, Assembler, Syntax};
+use crate::cpu::addressing_mode::AddressingMode;
+use crate::error::NesError;
+use crate::instructions::{is_official_opcode, Inst, INST_FACTORIES_BY_OP_CODE};
+use crate::mapper::PrgLayout;
+use crate::nes_format::NesFile;
+use crate::symbols::SymbolTable;
+
+const NMI_VECTOR: u16 = 0xfffa;
+const RESET_VECTOR: u16 = 0xfffc;
+const IRQ_VECTOR: u16 = 0xfffe;
+// bytes per `.byte` row of data
+const DATA_ROW: usize = 8;
+
+/**
+Code found by following the control flow of a program from its entry
+points, instead of decoding every byte as an instruction.
+
+Branches, `JSR` and `JMP` are followed to their targets, which get
+generated labels. Bytes never reached are data. Only official opcodes are
+decoded, so a path running into an unofficial one stops there.
+
+The bytes are the PRG ROM of a cartridge, split into banks by its mapper.
+Every bank is shown at the addresses of a window it's mapped to, and a
+jump out of that window goes to the bank mapped where it lands, so it is
+only followed into a switchable window once a bank is mapped there. The
+code and labels are kept by offset into the PRG ROM, and when there's more
+than one bank the addresses are written as `bank:address`.
+
+Operands are written with the names of a symbol table when it has them,
+and its names for addresses in the mapped banks replace the generated
+labels.
+*/
+pub struct Disassembly {
+    bytes: Vec<u8>,
+    layout: PrgLayout,
+    // the bank in each window of the layout
+    mapped: Vec<Option<usize>>,
+    code: BTreeMap<usize, Inst>,
+    labels: BTreeMap<usize, String>,
+    symbols: SymbolTable,
+}
+
+impl Disassembly {
+    fn with_layout(bytes: &[u8], layout: PrgLayout) -> Self {
+        let mapped = layout.windows.iter().map(|window| window.fixed).collect();
+        Disassembly {
+            bytes: bytes.to_vec(),
+            layout,
+            mapped,
+            code: BTreeMap::new(),
+            labels: BTreeMap::new(),
+            symbols: SymbolTable::default(),
+        }
+    }
+
+    // Disassembles `bytes` loaded at `origin`, starting at the named entry
+    // points.
+    pub fn new(bytes: &[u8], origin: u16, entries: &[(&str, u16)]) -> Self {
+        let mut disassembly = Disassembly::with_layout(bytes, PrgLayout::flat(origin, bytes.len()));
+        let entries = entries
+            .iter()
+            .filter_map(|&(name, addr)| Some((name, disassembly.offset(addr)?)))
+            .collect::<Vec<_>>();
+        disassembly.trace(&entries);
+        disassembly
+    }
+
+    // Disassembles the PRG ROM of a cartridge from its NMI, reset and IRQ
+    // vectors, with the banks of `banks` mapped to the switchable windows
+    // starting at the given addresses. When no bank holds the vectors,
+    // the last one is mapped there.
+    pub fn from_nes_file(nes: &NesFile, banks: &[(u16, usize)]) -> Result<Self, NesError> {
+        let layout = PrgLayout::new(nes.mapper(), nes.prg_rom.len());
+        let mut disassembly = Disassembly::with_layout(&nes.prg_rom, layout);
+        let count = disassembly.banks();
+        for &(addr, bank) in banks {
+            let windows = &disassembly.layout.windows;
+            let Some(window) = windows.iter().position(|window| window.start == addr) else {
+                bail!(NesError::InvalidBank(format!(
+                    "no bank window starts at ${:04X}",
+                    addr
+                )));
+            };
+            if windows[window].fixed.is_some() {
+                bail!(NesError::InvalidBank(format!(
+                    "the bank at ${:04X} is fixed",
+                    addr
+                )));
+            }
+            if bank >= count {
+                bail!(NesError::InvalidBank(format!(
+                    "bank {} is beyond the {} banks of the PRG ROM",
+                    bank, count
+                )));
+            }
+            disassembly.mapped[window] = Some(bank);
+        }
+        if let Some(window) = disassembly.layout.window(NMI_VECTOR) {
+            disassembly.mapped[window].get_or_insert(count - 1);
+        }
+        let entries = [
+            ("nmi", NMI_VECTOR),
+            ("reset", RESET_VECTOR),
+            ("irq", IRQ_VECTOR),
+        ]
+        .iter()
+        .filter_map(|&(name, vector)| {
+            Some((name, disassembly.offset(disassembly.read_word(vector)?)?))
+        })
+        .collect::<Vec<_>>();
+        disassembly.trace(&entries);
+        Ok(disassembly)
+    }
+
+    fn banks(&self) -> usize {
+        self.bytes.len().div_ceil(self.layout.bank_size).max(1)
+    }
+
+    pub fn is_banked(&self) -> bool {
+        self.banks() > 1
+    }
+
+    // The address of the window a bank is shown at: the last one it is
+    // mapped to, or else the first switchable one.
+    fn home(&self, bank: usize) -> u16 {
+        let windows = &self.layout.windows;
+        let window = (0..windows.len())
+            .rev()
+            .find(|&i| self.mapped[i] == Some(bank))
+            .or_else(|| windows.iter().position(|window| window.fixed.is_none()))
+            .unwrap_or(0);
+        windows[window].start
+    }
+
+    // The offset into the PRG ROM that the CPU sees at `addr`.
+    pub fn offset(&self, addr: u16) -> Option<usize> {
+        let window = self.layout.window(addr)?;
+        let bank = self.mapped[window]?;
+        let offset =
+            bank * self.layout.bank_size + (addr - self.layout.windows[window].start) as usize;
+        (offset < self.bytes.len()).then_some(offset)
+    }
+
+    // The offset that `addr` refers to from the code at `from`, which is in
+    // its own bank when `addr` is in the same window.
+    pub fn resolve(&self, from: usize, addr: u16) -> Option<usize> {
+        let bank = from / self.layout.bank_size;
+        let delta = addr.wrapping_sub(self.home(bank)) as usize;
+        if delta >= self.layout.bank_size {
+            return self.offset(addr);
+        }
+        let offset = bank * self.layout.bank_size + delta;
+        (offset < self.bytes.len()).then_some(offset)
+    }
+
+    // The CPU address an offset is shown at.
+    pub fn addr(&self, offset: usize) -> u16 {
+        self.home(offset / self.layout.bank_size)
+            .wrapping_add((offset % self.layout.bank_size) as u16)
+    }
+
+    // The address of an offset, prefixed with its bank when there's more
+    // than one: `03:8000`.
+    pub fn location(&self, offset: usize) -> String {
+        match self.is_banked() {
+            true => format!(
+                "{:02X}:{:04X}",
+                offset / self.layout.bank_size,
+                self.addr(offset)
+            ),
+            false => format!("{:04X}", self.addr(offset)),
+        }
+    }
+
+    fn read_word(&self, addr: u16) -> Option<u16> {
+        let lo = self.bytes[self.offset(addr)?];
+        let hi = self.bytes[self.offset(addr.wrapping_add(1))?];
+        Some(u16::from_le_bytes([lo, hi]))
+    }
+
+    pub fn reset_vector(&self) -> Option<u16> {
+        self.read_word(RESET_VECTOR)
+    }
+
+    fn decode(&self, offset: usize) -> Option<Inst> {
+        let opcode = self.bytes[offset];
+        if !is_official_opcode(opcode) {
+            return None;
+        }
+        let factory = INST_FACTORIES_BY_OP_CODE.get(&opcode)?;
+        let len = factory.mode.get_inst_size() as usize;
+        // instructions don't run over the end of their bank
+        if offset % self.layout.bank_size + len > self.layout.bank_size
+            || offset + len > self.bytes.len()
+        {
+            return None;
+        }
+        Some(factory.make(self.bytes[offset + 1..].iter().cloned()))
+    }
+
+    // Whether `offset` lies inside an instruction decoded so far.
+    fn is_inside_code(&self, offset: usize) -> bool {
+        self.code
+            .range(..=offset)
+            .next_back()
+            .is_some_and(|(&start, inst)| offset - start < inst.len() as usize)
+    }
+
+    fn add_label(&mut self, offset: usize, name: String) {
+        self.labels.entry(offset).or_insert(name);
+    }
+
+    fn trace(&mut self, entries: &[(&str, usize)]) {
+        let mut pending = entries
+            .iter()
+            .rev()
+            .map(|&(_, offset)| offset)
+            .collect::<Vec<_>>();
+        for &(name, offset) in entries {
+            self.add_label(offset, name.to_string());
+        }
+        while let Some(offset) = pending.pop() {
+            if self.code.contains_key(&offset) || self.is_inside_code(offset) {
+                continue;
+            }
+            let Some(inst) = self.decode(offset) else {
+                continue;
+            };
+            // an instruction overlapping one decoded before means the
+            // path is not code after all
+            let end = offset + inst.len() as usize;
+            if self
+                .code
+                .range(offset..)
+                .next()
+                .is_some_and(|(&next, _)| next < end)
+            {
+                continue;
+            }
+            let next = self.resolve(offset, self.addr(offset).wrapping_add(inst.len()));
+            match (inst.name.as_str(), inst.mode) {
+                ("RTS" | "RTI" | "BRK", _) | ("JMP", AddressingMode::Indirect) => {}
+                ("JMP", _) => pending.extend(self.target(&inst, offset, "loc")),
+                ("JSR", _) => {
+                    pending.extend(next);
+                    pending.extend(self.target(&inst, offset, "sub"));
+                }
+                (_, AddressingMode::Relative) => {
+                    pending.extend(next);
+                    pending.extend(self.target(&inst, offset, "loc"));
+                }
+                _ => pending.extend(next),
+            }
+            self.code.insert(offset, inst);
+        }
+        // labels only for targets that turned out to be instructions
+        let code = &self.code;
+        self.labels.retain(|offset, _| code.contains_key(offset));
+    }
+
+    // Labels the target of a jump or branch when it is in the mapped
+    // banks, returning it to be traced.
+    fn target(&mut self, inst: &Inst, offset: usize, prefix: &str) -> Option<usize> {
+        let target = self.resolve(offset, jump_target(inst, self.addr(offset))?)?;
+        let name = format!("{}_{}", prefix, self.location(target).replace(':', "_"));
+        self.add_label(target, name);
+        Some(target)
+    }
+
+    // Also follows the code from `addr`, which is labelled `name` unless it
+    // already has a label.
+    pub fn with_entry(mut self, name: &str, addr: u16) -> Self {
+        if let Some(offset) = self.offset(addr) {
+            self.trace(&[(name, offset)]);
+        }
+        self
+    }
+
+    pub fn with_symbols(mut self, symbols: SymbolTable) -> Self {
+        for (addr, name) in symbols.iter() {
+            let Some(offset) = self
+                .offset(addr)
+                .filter(|&offset| self.addr(offset) == addr)
+            else {
+                continue;
+            };
+            let at_row = self.code.contains_key(&offset) || !self.is_inside_code(offset);
+            if at_row && !self.labels.values().any(|label| label == name) {
+                self.labels.insert(offset, name.to_string());
+            }
+        }
+        self.symbols = symbols;
+        self
+    }
+
+    // The instruction decoded at `offset`.
+    pub fn inst(&self, offset: usize) -> Option<&Inst> {
+        self.code.get(&offset)
+    }
+
+    pub fn label(&self, offset: usize) -> Option<&str> {
+        self.labels.get(&offset).map(|label| label.as_str())
+    }
+
+    // The name of an address an operand of the code at `from` refers to:
+    // the label at exactly that address, or else its symbol unless a label
+    // has the same name.
+    pub fn operand_name(&self, from: usize, addr: u16) -> Option<&str> {
+        let exact = self
+            .resolve(from, addr)
+            .filter(|&offset| self.addr(offset) == addr);
+        if let Some(label) = exact.and_then(|offset| self.label(offset)) {
+            return Some(label);
+        }
+        self.symbols
+            .get(addr)
+            .filter(|&name| !self.labels.values().any(|label| label == name))
+    }
+
+    // The instruction text with named operands. In source, absolute
+    // operands below $100 are marked with `a:` to keep their encoding.
+    pub fn format_inst(&self, inst: &Inst, offset: usize, source: bool) -> String {
+        let text = inst.to_string_with_names(Some(self.addr(offset)), |addr| {
+            self.operand_name(offset, addr).map(|name| name.to_string())
+        });
+        let absolute = matches!(
+            inst.mode,
+            AddressingMode::Absolute | AddressingMode::AbsoluteX | AddressingMode::AbsoluteY
+        );
+        match source && absolute && inst.param.is_some_and(|param| param < 0x100) {
+            true => text.replacen(' ', " a:", 1),
+            false => text,
+        }
+    }
+
+    // The symbols that operands refer to other than labels, which source
+    // has to define.
+    fn operand_symbols(&self) -> BTreeMap<u16, &str> {
+        let mut symbols = BTreeMap::new();
+        for (&offset, inst) in &self.code {
+            let operand = match inst.mode {
+                AddressingMode::Implied
+                | AddressingMode::Accumulator
+                | AddressingMode::Immediate => None,
+                AddressingMode::Relative => jump_target(inst, self.addr(offset)),
+                _ => inst.param,
+            };
+            let Some(operand) = operand else {
+                continue;
+            };
+            let is_label = self.resolve(offset, operand).is_some_and(|target| {
+                self.addr(target) == operand && self.labels.contains_key(&target)
+            });
+            if let Some(name) = self.operand_name(offset, operand).filter(|_| !is_label) {
+                symbols.insert(operand, name);
+            }
+        }
+        symbols
+    }
+
+    // The offset and length of every instruction and data row.
+    fn rows(&self) -> Vec<(usize, usize)> {
+        let mut rows = vec![];
+        let mut offset = 0;
+        while offset < self.bytes.len() {
+            let len = match self.code.get(&offset) {
+                Some(inst) => inst.len() as usize,
+                None => self.data_len(offset),
+            };
+            rows.push((offset, len));
+            offset += len;
+        }
+        rows
+    }
+
+    fn row_text(&self, offset: usize, len: usize, source: bool) -> String {
+        match self.code.get(&offset) {
+            Some(inst) => self.format_inst(inst, offset, source),
+            None => format_data(&self.bytes[offset..offset + len]),
+        }
+    }
+
+    /**
+    A listing of the code and data with the address and bytes of each line:
+
+    ```text
+    reset:
+      C000  78        SEI
+      C001  D0 FD     BNE loc_C000
+      C003  01 02 03  .byte $01, $02, $03
+    ```
+    */
+    pub fn format_listing(&self) -> String {
+        let mut res = String::new();
+        for (offset, len) in self.rows() {
+            if let Some(label) = self.labels.get(&offset) {
+                res.push_str(&format!("{}:\n", label));
+            }
+            let bytes = self.bytes[offset..offset + len]
+                .iter()
+                .map(|b| format!("{:02X}", b))
+                .collect::<Vec<_>>();
+            res.push_str(&format!(
+                "  {}  {:<8}  {}\n",
+                self.location(offset),
+                bytes.join(" "),
+                self.row_text(offset, len, false)
+            ));
+        }
+        res
+    }
+
+    // Source that assembles back to the disassembled bytes, with our
+    // assembler as well as ca65. Banked ROMs have no single origin and
+    // can only be listed.
+    pub fn format_source(&self) -> Result<String, NesError> {
+        if self.is_banked() {
+            bail!(NesError::DisassemblerFailure(format!(
+                "source of a PRG ROM with {} banks is not supported, use a listing",
+                self.banks()
+            )));
+        }
+        let mut res = String::new();
+        for (addr, name) in self.operand_symbols() {
+            res.push_str(&format!("{} = ${:04X}\n", name, addr));
+        }
+        res.push_str(&format!(".org ${:04X}\n", self.addr(0)));
+        for (offset, len) in self.rows() {
+            if let Some(label) = self.labels.get(&offset) {
+                res.push_str(&format!("{}:\n", label));
+            }
+            res.push_str(&format!("    {}\n", self.row_text(offset, len, true)));
+        }
+        Ok(res)
+    }
+
+    // Assembles `source` and checks that it reproduces the disassembled
+    // bytes.
+    pub fn check_round_trip(&self, source: &str) -> Result<(), NesError> {
+        let source = parse_source(source, Syntax::Native)?;
+        let mut assembler = Assembler::new(self.addr(0));
+        let bytes = assembler.assemble(&source.lines)?;
+        if let Some(offset) =
+            (0..self.bytes.len().max(bytes.len())).find(|&i| bytes.get(i) != self.bytes.get(i))
+        {
+            bail!(NesError::DisassemblerFailure(format!(
+                "reassembled source differs at ${:04X}",
+                self.addr(0).wrapping_add(offset as u16)
+            )));
+        }
+        Ok(())
+    }
+
+    // The length of the data row at `offset`, which ends at the next code,
+    // label or bank.
+    fn data_len(&self, offset: usize) -> usize {
+        let mut len = 1;
+        while len < DATA_ROW && offset + len < self.bytes.len() {
+            let next = offset + len;
+            if self.code.contains_key(&next)
+                || self.labels.contains_key(&next)
+                || next.is_multiple_of(self.layout.bank_size)
+            {
+                break;
+            }
+            len += 1;
+        }
+        len
+    }
+}
+
+// The address a branch, `JSR` or absolute `JMP` at `addr` goes to.
+pub fn jump_target(inst: &Inst, addr: u16) -> Option<u16> {
+    match (inst.name.as_str(), inst.mode) {
+        (_, AddressingMode::Relative) => {
+            let delta = inst.param? as u8 as i8;
+            Some(addr.wrapping_add(2).wrapping_add(delta as u16))
+        }
+        ("JMP" | "JSR", AddressingMode::Absolute) => inst.param,
+        _ => None,
+    }
+}
+
+fn format_data(bytes: &[u8]) -> String {
+    let values = bytes
+        .iter()
+        .map(|b| format!("${:02X}", b))
+        .collect::<Vec<_>>();
+    format!(".byte {}", values.join(", "))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_trace() {
+        let bytes = [
+            0x20, 0x07, 0x80, // $8000 JSR $8007
+            0x4c, 0x00, 0x80, // $8003 JMP $8000
+            0xff, // $8006 data
+            0xa2, 0x02, // $8007 LDX #$02
+            0xca, // $8009 DEX
+            0xd0, 0xfd, // $800A BNE $8009
+            0x60, // $800C RTS
+            0x01, 0x02, // $800D data
+        ];
+        let disassembly = Disassembly::new(&bytes, 0x8000, &[("reset", 0x8000)]);
+        let code = disassembly.code.keys().copied().collect::<Vec<_>>();
+        assert_eq!(code, vec![0, 3, 7, 9, 0xa, 0xc]);
+        assert!(!disassembly.code.contains_key(&6));
+        assert_eq!(disassembly.label(0), Some("reset"));
+        assert_eq!(disassembly.label(7), Some("sub_8007"));
+        assert_eq!(disassembly.label(9), Some("loc_8009"));
+        assert_eq!(
+            disassembly.format_listing(),
+            "\
+reset:
+  8000  20 07 80  JSR sub_8007
+  8003  4C 00 80  JMP reset
+  8006  FF        .byte $FF
+sub_8007:
+  8007  A2 02     LDX #$02
+loc_8009:
+  8009  CA        DEX
+  800A  D0 FD     BNE loc_8009
+  800C  60        RTS
+  800D  01 02     .byte $01, $02
+"
+        );
+    }
+
+    #[test]
+    fn test_source() {
+        let bytes = [
+            0xad, 0x10, 0x00, // $C000 LDA a:$0010
+            0xb5, 0x10, // $C003 LDA $10,X
+            0x0a, // $C005 ASL A
+            0xf0, 0x03, // $C006 BEQ $C00B
+            0x6c, 0x00, 0x02, // $C008 JMP ($0200)
+            0x60, // $C00B RTS
+            0x02, 0xff, // $C00C data, $02 is unofficial
+        ];
+        let disassembly = Disassembly::new(&bytes, 0xc000, &[("reset", 0xc000)]);
+        let source = disassembly.format_source().unwrap();
+        assert_eq!(
+            source,
+            "\
+.org $C000
+reset:
+    LDA a:$0010
+    LDA $10,X
+    ASL A
+    BEQ loc_C00B
+    JMP ($0200)
+loc_C00B:
+    RTS
+    .byte $02, $FF
+"
+        );
+        disassembly.check_round_trip(&source).unwrap();
+        assert!(disassembly
+            .check_round_trip(&source.replace("a:", ""))
+            .is_err());
+    }
+
+    #[test]
+    fn test_symbols() {
+        let bytes = [
+            0x8d, 0x00, 0x20, // $8000 STA PPUCTRL
+            0xad, 0x10, 0x00, // $8003 LDA a:buttons
+            0xa5, 0x10, // $8006 LDA buttons
+            0x20, 0x0c, 0x80, // $8008 JSR update
+            0x60, // $800B RTS
+            0x60, // $800C RTS
+            0x01, 0x02, // $800D table
+        ];
+        let mut symbols = SymbolTable::hardware();
+        symbols.insert(0x0010, "buttons");
+        symbols.insert(0x800c, "update");
+        symbols.insert(0x800d, "table");
+        symbols.insert(0x8001, "inside");
+        let disassembly =
+            Disassembly::new(&bytes, 0x8000, &[("reset", 0x8000)]).with_symbols(symbols);
+        let source = disassembly.format_source().unwrap();
+        assert_eq!(
+            source,
+            "\
+buttons = $0010
+PPUCTRL = $2000
+.org $8000
+reset:
+    STA PPUCTRL
+    LDA a:buttons
+    LDA buttons
+    JSR update
+    RTS
+update:
+    RTS
+table:
+    .byte $01, $02
+"
+        );
+        disassembly.check_round_trip(&source).unwrap();
+        assert!(disassembly
+            .format_listing()
+            .contains("  8000  8D 00 20  STA PPUCTRL\n"));
+    }
+
+    fn nes_file(prg_rom: Vec<u8>, mapper: u8) -> NesFile {
+        NesFile {
+            header: crate::nes_format::NesHeader {
+                prg_rom_size: (prg_rom.len() / 0x4000) as u8,
+                chr_rom_size: 0,
+                flags_6: mapper << 4,
+                flags_7: mapper & 0xf0,
+                prg_ram_size: 0,
+                flags_9: 0,
+                flags_10: 0,
+            },
+            prg_rom,
+            chr_rom: vec![],
+            title: None,
+        }
+    }
+
+    #[test]
+    fn test_from_nes_file() {
+        let mut prg_rom = vec![0; 0x4000];
+        // reset at $C000 jumps through the $8000 mirror, NMI and IRQ at RTI
+        prg_rom[0..3].copy_from_slice(&[0x4c, 0x03, 0x80]);
+        prg_rom[3] = 0x40;
+        prg_rom[0x3ffa..].copy_from_slice(&[0x03, 0xc0, 0x00, 0xc0, 0x03, 0xc0]);
+        let disassembly = Disassembly::from_nes_file(&nes_file(prg_rom, 0), &[]).unwrap();
+        assert_eq!(disassembly.addr(0), 0xc000);
+        assert_eq!(
+            disassembly.code.keys().copied().collect::<Vec<_>>(),
+            vec![0, 3]
+        );
+        assert_eq!(disassembly.label(3), Some("nmi"));
+        assert_eq!(disassembly.offset(0x8003), Some(3));
+        // the mirror keeps its address so that the source reassembles
+        assert!(disassembly
+            .format_listing()
+            .contains("  C000  4C 03 80  JMP $8003\n"));
+    }
+
+    #[test]
+    fn test_banks() {
+        // UxROM with 4 banks, the last fixed at $C000
+        let mut prg_rom = vec![0; 0x10000];
+        // reset calls into the switchable bank, which is bank 1 at $8000
+        prg_rom[0xc000..0xc004].copy_from_slice(&[0x20, 0x00, 0x80, 0x40]);
+        prg_rom[0x4000..0x4003].copy_from_slice(&[0x4c, 0x00, 0xc0]);
+        prg_rom[0xfffa..].copy_from_slice(&[0x03, 0xc0, 0x00, 0xc0, 0x03, 0xc0]);
+        let nes = nes_file(prg_rom, 2);
+        let unmapped = Disassembly::from_nes_file(&nes, &[]).unwrap();
+        assert!(unmapped
+            .format_listing()
+            .contains("  03:C000  20 00 80  JSR $8000\n"));
+        assert!(unmapped
+            .format_listing()
+            .contains("  01:8000  4C 00 C0 00 00 00 00 00  .byte $4C, $00, $C0, $00,"));
+        let disassembly = Disassembly::from_nes_file(&nes, &[(0x8000, 1)]).unwrap();
+        let listing = disassembly.format_listing();
+        assert!(listing.contains("reset:\n  03:C000  20 00 80  JSR sub_01_8000\n"));
+        assert!(listing.contains("sub_01_8000:\n  01:8000  4C 00 C0  JMP reset\n"));
+        assert!(listing.contains("  02:8000  00 00 00 00 00 00 00 00  .byte"));
+        assert!(disassembly.format_source().is_err());
+        assert!(Disassembly::from_nes_file(&nes, &[(0xc000, 1)]).is_err());
+        assert!(Disassembly::from_nes_file(&nes, &[(0x8000, 4)]).is_err());
+        assert!(Disassembly::from_nes_file(&nes, &[(0x9000, 1)]).is_err());
+    }
+}
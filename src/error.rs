@@ -1,5 +1,7 @@
 use thiserror::Error;
 
+use crate::assembler::Diagnostic;
+
 #[derive(Error, Debug)]
 pub enum NesError {
     #[error("IO error")]
@@ -10,8 +12,12 @@ pub enum NesError {
     InstNotFound(String),
     #[error("Failed to assemble instruction: {0}")]
     AssemblerFailure(String),
+    #[error("Failed to assemble:\n{}", .0.iter().map(|d| d.to_string()).collect::<Vec<_>>().join("\n"))]
+    AssemblerDiagnostics(Vec<Diagnostic>),
     #[error("Failed to disassemble instruction: {0}")]
     DisassemblerFailure(String),
+    #[error("Debugger error: {0}")]
+    DebuggerFailure(String),
     #[error("Invalid file extension: {0}")]
     InvalidFileExtension(String),
     #[error("Failed to parse cpu state")]
@@ -24,4 +30,10 @@ pub enum NesError {
     TestFailed(String),
     #[error("Invalid color index: {0}")]
     InvalidColorIndex(u8),
+    #[error("Invalid PRG bank mapping: {0}")]
+    InvalidBank(String),
+    #[error("Invalid song number: {0}")]
+    InvalidSong(u8),
+    #[error("Timed out: {0}")]
+    Timeout(String),
 }
@@ -0,0 +1,364 @@
+use std::collections::{BTreeMap, BTreeSet, HashSet, VecDeque};
+
+use crate::cpu::addressing_mode::AddressingMode;
+use crate::disassembler::{jump_target, Disassembly};
+use crate::instructions::Inst;
+
+// Where control goes from the end of a basic block, by the CPU addresses
+// of the instructions.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Edge {
+    Taken(u16),
+    NotTaken(u16),
+    Jump(u16),
+    // a `JSR`, which returns to the block after it
+    Call(u16),
+    // the next instruction starts another block
+    Next(u16),
+    // `RTS`, `RTI`, `BRK` and indirect `JMP` leave the subroutine
+    Exit,
+}
+
+// The offsets of the instructions of a block in the PRG ROM, and where it
+// goes next.
+#[derive(Debug, PartialEq)]
+pub struct Block {
+    pub insts: Vec<usize>,
+    pub edges: Vec<Edge>,
+}
+
+pub struct Subroutine {
+    pub entry: usize,
+    pub blocks: BTreeMap<usize, Block>,
+}
+
+/**
+The basic blocks of the subroutines reachable from an entry point, over
+the code of a disassembly.
+
+A subroutine is what is reached from its entry by branches and `JMP`,
+which includes the code of tail calls. Every `JSR` is an edge to the graph
+of the subroutine it calls, which is built as well.
+*/
+pub struct FlowGraph<'a> {
+    disassembly: &'a Disassembly,
+    subroutines: Vec<Subroutine>,
+}
+
+// The edges of an instruction that ends a block.
+fn block_end(inst: &Inst, addr: u16) -> Option<Vec<Edge>> {
+    let next = addr.wrapping_add(inst.len());
+    match (inst.name.as_str(), inst.mode) {
+        ("RTS" | "RTI" | "BRK", _) | ("JMP", AddressingMode::Indirect) => Some(vec![Edge::Exit]),
+        ("JMP", _) => Some(vec![Edge::Jump(jump_target(inst, addr)?)]),
+        ("JSR", _) => Some(vec![Edge::Call(jump_target(inst, addr)?), Edge::Next(next)]),
+        (_, AddressingMode::Relative) => Some(vec![
+            Edge::Taken(jump_target(inst, addr)?),
+            Edge::NotTaken(next),
+        ]),
+        _ => None,
+    }
+}
+
+fn escape(s: &str) -> String {
+    s.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+impl<'a> FlowGraph<'a> {
+    pub fn new(disassembly: &'a Disassembly, entry: u16) -> Self {
+        let mut subroutines = vec![];
+        let mut visited = HashSet::new();
+        let mut pending = VecDeque::from_iter(disassembly.offset(entry));
+        while let Some(entry) = pending.pop_front() {
+            if !visited.insert(entry) || disassembly.inst(entry).is_none() {
+                continue;
+            }
+            let subroutine = Self::subroutine(disassembly, entry);
+            for block in subroutine.blocks.values() {
+                let last = *block.insts.last().unwrap();
+                for edge in &block.edges {
+                    if let Edge::Call(target) = edge {
+                        pending.extend(disassembly.resolve(last, *target));
+                    }
+                }
+            }
+            subroutines.push(subroutine);
+        }
+        FlowGraph {
+            disassembly,
+            subroutines,
+        }
+    }
+
+    pub fn subroutines(&self) -> &[Subroutine] {
+        &self.subroutines
+    }
+
+    fn subroutine(disassembly: &Disassembly, entry: usize) -> Subroutine {
+        // the code of the subroutine, and the offsets that start blocks
+        let mut code = BTreeSet::new();
+        let mut leaders = BTreeSet::from([entry]);
+        let mut pending = vec![entry];
+        while let Some(offset) = pending.pop() {
+            let Some(inst) = disassembly.inst(offset) else {
+                continue;
+            };
+            if !code.insert(offset) {
+                continue;
+            }
+            let addr = disassembly.addr(offset);
+            match block_end(inst, addr) {
+                Some(edges) => {
+                    for edge in edges {
+                        if let Edge::Taken(target)
+                        | Edge::NotTaken(target)
+                        | Edge::Jump(target)
+                        | Edge::Next(target) = edge
+                        {
+                            let Some(target) = disassembly.resolve(offset, target) else {
+                                continue;
+                            };
+                            leaders.insert(target);
+                            pending.push(target);
+                        }
+                    }
+                }
+                None => pending.extend(disassembly.resolve(offset, addr.wrapping_add(inst.len()))),
+            }
+        }
+        let mut blocks = BTreeMap::new();
+        for &start in leaders.intersection(&code) {
+            let mut insts = vec![];
+            let mut offset = start;
+            let edges = loop {
+                let inst = disassembly.inst(offset).unwrap();
+                let addr = disassembly.addr(offset);
+                insts.push(offset);
+                if let Some(edges) = block_end(inst, addr) {
+                    break edges;
+                }
+                let next = addr.wrapping_add(inst.len());
+                match disassembly.resolve(offset, next) {
+                    Some(next) if code.contains(&next) && !leaders.contains(&next) => offset = next,
+                    _ => break vec![Edge::Next(next)],
+                }
+            };
+            blocks.insert(start, Block { insts, edges });
+        }
+        Subroutine { entry, blocks }
+    }
+
+    // The name of `addr` as an operand of the code at `from`.
+    fn name(&self, from: usize, addr: u16) -> String {
+        match self.disassembly.operand_name(from, addr) {
+            Some(name) => name.to_string(),
+            None => format!("${:04X}", addr),
+        }
+    }
+
+    /**
+    Graphviz DOT with a `digraph` for every subroutine, one box per block:
+
+    ```text
+    digraph "sub_8007" {
+        node [shape=box, fontname="monospace"];
+        "8007" [label="sub_8007:\l8007  LDX #$02\l"];
+        "8007" -> "8009";
+        "8009" [label="loc_8009:\l8009  DEX\l800A  BNE loc_8009\l"];
+        "8009" -> "8009" [label="taken"];
+        ...
+    }
+    ```
+
+    Blocks are named by their location, `bank:address` in a banked ROM.
+    Called subroutines, exits and targets outside of the subroutine are
+    drawn as ovals.
+    */
+    pub fn to_dot(&self) -> String {
+        let mut res = String::new();
+        for subroutine in &self.subroutines {
+            self.write_subroutine(&mut res, subroutine);
+        }
+        res
+    }
+
+    fn write_subroutine(&self, res: &mut String, subroutine: &Subroutine) {
+        let disassembly = self.disassembly;
+        let title = match disassembly.label(subroutine.entry) {
+            Some(label) => label.to_string(),
+            None => disassembly.location(subroutine.entry),
+        };
+        res.push_str(&format!("digraph \"{}\" {{\n", escape(&title)));
+        res.push_str("    node [shape=box, fontname=\"monospace\"];\n");
+        // the ovals, by their node id
+        let mut others = BTreeMap::new();
+        for (&start, block) in &subroutine.blocks {
+            let mut text = String::new();
+            if let Some(label) = disassembly.label(start) {
+                text.push_str(&format!("{}:\\l", escape(label)));
+            }
+            for &offset in &block.insts {
+                let inst = disassembly.inst(offset).unwrap();
+                let line = format!(
+                    "{}  {}",
+                    disassembly.location(offset),
+                    disassembly.format_inst(inst, offset, false)
+                );
+                text.push_str(&format!("{}\\l", escape(&line)));
+            }
+            let id = disassembly.location(start);
+            res.push_str(&format!("    \"{}\" [label=\"{}\"];\n", id, text));
+            let last = *block.insts.last().unwrap();
+            for edge in &block.edges {
+                let (node, attrs) = match *edge {
+                    Edge::Call(target) => {
+                        let node = match disassembly.resolve(last, target) {
+                            Some(callee) => format!("call_{}", disassembly.location(callee)),
+                            None => format!("call_{:04X}", target),
+                        };
+                        others.insert(node.clone(), self.name(last, target));
+                        (node, "label=\"call\", style=dashed".to_string())
+                    }
+                    Edge::Exit => {
+                        others.insert("exit".to_string(), "exit".to_string());
+                        let name = &disassembly.inst(last).unwrap().name;
+                        ("exit".to_string(), format!("label=\"{}\"", name))
+                    }
+                    Edge::Taken(target)
+                    | Edge::NotTaken(target)
+                    | Edge::Jump(target)
+                    | Edge::Next(target) => {
+                        let node = match disassembly
+                            .resolve(last, target)
+                            .filter(|next| subroutine.blocks.contains_key(next))
+                        {
+                            Some(next) => disassembly.location(next),
+                            None => {
+                                let node = format!("ext_{:04X}", target);
+                                others.insert(node.clone(), self.name(last, target));
+                                node
+                            }
+                        };
+                        let attrs = match edge {
+                            Edge::Taken(_) => "label=\"taken\"",
+                            Edge::NotTaken(_) => "label=\"not taken\"",
+                            Edge::Jump(_) => "label=\"jmp\"",
+                            _ => "",
+                        };
+                        (node, attrs.to_string())
+                    }
+                };
+                match attrs.is_empty() {
+                    true => res.push_str(&format!("    \"{}\" -> \"{}\";\n", id, escape(&node))),
+                    false => res.push_str(&format!(
+                        "    \"{}\" -> \"{}\" [{}];\n",
+                        id,
+                        escape(&node),
+                        attrs
+                    )),
+                }
+            }
+        }
+        for (node, label) in others {
+            res.push_str(&format!(
+                "    \"{}\" [shape=oval, label=\"{}\"];\n",
+                escape(&node),
+                escape(&label)
+            ));
+        }
+        res.push_str("}\n");
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_blocks() {
+        let bytes = [
+            0x20, 0x07, 0x80, // $8000 JSR $8007
+            0x4c, 0x00, 0x80, // $8003 JMP $8000
+            0xff, // $8006 data
+            0xa2, 0x02, // $8007 LDX #$02
+            0xca, // $8009 DEX
+            0xd0, 0xfd, // $800A BNE $8009
+            0x60, // $800C RTS
+        ];
+        let disassembly = Disassembly::new(&bytes, 0x8000, &[("reset", 0x8000)]);
+        let graph = FlowGraph::new(&disassembly, 0x8000);
+        let entries = graph
+            .subroutines()
+            .iter()
+            .map(|sub| sub.entry)
+            .collect::<Vec<_>>();
+        assert_eq!(entries, vec![0, 7]);
+        let reset = &graph.subroutines()[0].blocks;
+        assert_eq!(
+            reset[&0],
+            Block {
+                insts: vec![0],
+                edges: vec![Edge::Call(0x8007), Edge::Next(0x8003)]
+            }
+        );
+        assert_eq!(
+            reset[&3],
+            Block {
+                insts: vec![3],
+                edges: vec![Edge::Jump(0x8000)]
+            }
+        );
+        let sub = &graph.subroutines()[1].blocks;
+        assert_eq!(sub.keys().copied().collect::<Vec<_>>(), vec![7, 9, 0xc]);
+        assert_eq!(
+            sub[&9].edges,
+            vec![Edge::Taken(0x8009), Edge::NotTaken(0x800c)]
+        );
+        assert_eq!(sub[&0xc].edges, vec![Edge::Exit]);
+        assert_eq!(
+            graph.to_dot(),
+            "\
+digraph \"reset\" {
+    node [shape=box, fontname=\"monospace\"];
+    \"8000\" [label=\"reset:\\l8000  JSR sub_8007\\l\"];
+    \"8000\" -> \"call_8007\" [label=\"call\", style=dashed];
+    \"8000\" -> \"8003\";
+    \"8003\" [label=\"8003  JMP reset\\l\"];
+    \"8003\" -> \"8000\" [label=\"jmp\"];
+    \"call_8007\" [shape=oval, label=\"sub_8007\"];
+}
+digraph \"sub_8007\" {
+    node [shape=box, fontname=\"monospace\"];
+    \"8007\" [label=\"sub_8007:\\l8007  LDX #$02\\l\"];
+    \"8007\" -> \"8009\";
+    \"8009\" [label=\"loc_8009:\\l8009  DEX\\l800A  BNE loc_8009\\l\"];
+    \"8009\" -> \"8009\" [label=\"taken\"];
+    \"8009\" -> \"800C\" [label=\"not taken\"];
+    \"800C\" [label=\"800C  RTS\\l\"];
+    \"800C\" -> \"exit\" [label=\"RTS\"];
+    \"exit\" [shape=oval, label=\"exit\"];
+}
+"
+        );
+    }
+
+    #[test]
+    fn test_outside_targets() {
+        let bytes = [
+            0xad, 0x02, 0x20, // $C000 LDA $2002
+            0x10, 0x03, // $C003 BPL $C008
+            0x4c, 0x00, 0x03, // $C005 JMP $0300
+            0x6c, 0x00, 0x02, // $C008 JMP ($0200)
+        ];
+        let disassembly = Disassembly::new(&bytes, 0xc000, &[("reset", 0xc000)]);
+        let graph = FlowGraph::new(&disassembly, 0xc000);
+        let blocks = &graph.subroutines()[0].blocks;
+        assert_eq!(blocks[&0].insts, vec![0, 3]);
+        assert_eq!(blocks[&5].edges, vec![Edge::Jump(0x0300)]);
+        assert_eq!(blocks[&8].edges, vec![Edge::Exit]);
+        let dot = graph.to_dot();
+        assert!(dot.contains("    \"C005\" -> \"ext_0300\" [label=\"jmp\"];\n"));
+        assert!(dot.contains("    \"ext_0300\" [shape=oval, label=\"$0300\"];\n"));
+        assert!(dot.contains("    \"C008\" -> \"exit\" [label=\"JMP\"];\n"));
+    }
+}
@@ -0,0 +1,475 @@
+// https://sourceware.org/gdb/current/onlinedocs/gdb.html/Remote-Protocol.html
+
+use std::collections::BTreeSet;
+use std::io::{BufRead, BufReader, ErrorKind, Read, Write};
+use std::net::{TcpListener, TcpStream};
+
+use error_stack::{Result, ResultExt};
+
+use crate::cpu::CPU;
+use crate::error::NesError;
+
+// instructions run between checks for an interrupt from the debugger
+const RUN_CHUNK: u64 = 10_000;
+
+// signals in stop replies
+const SIGINT: u8 = 2;
+const SIGILL: u8 = 4;
+const SIGTRAP: u8 = 5;
+
+// GDB has no 6502 target, so the registers are described to it. They're
+// sent in this order, a byte each but the little-endian PC.
+const TARGET_XML: &str = r#"<?xml version="1.0"?>
+<!DOCTYPE target SYSTEM "gdb-target.dtd">
+<target version="1.0">
+  <feature name="org.nes.6502">
+    <reg name="a" bitsize="8" regnum="0"/>
+    <reg name="x" bitsize="8"/>
+    <reg name="y" bitsize="8"/>
+    <reg name="sp" bitsize="8"/>
+    <reg name="pc" bitsize="16" type="code_ptr"/>
+    <reg name="p" bitsize="8"/>
+  </feature>
+</target>
+"#;
+
+// The bytes of each register in a `g` packet.
+const REGISTERS: [(usize, usize); 6] = [(0, 1), (1, 2), (2, 3), (3, 4), (4, 6), (6, 7)];
+
+// What a packet asks the stub to do after replying.
+#[derive(Debug, PartialEq)]
+enum Action {
+    Reply(String),
+    Resume { step: bool },
+    Detach,
+    Kill,
+}
+
+enum Input {
+    Packet(String),
+    // Ctrl-C, sent outside of packets
+    Interrupt,
+}
+
+fn checksum(data: &str) -> u8 {
+    data.bytes().fold(0, |sum, b| sum.wrapping_add(b))
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn from_hex(s: &str) -> Option<Vec<u8>> {
+    if !s.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..s.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(s.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+fn parse_hex(s: &str) -> Option<u16> {
+    u16::from_str_radix(s, 16).ok()
+}
+
+fn stop_reply(signal: u8) -> String {
+    format!("S{:02x}", signal)
+}
+
+// A TCP connection to a debugger, which acknowledges each packet.
+struct Connection {
+    reader: BufReader<TcpStream>,
+}
+
+impl Connection {
+    fn read_byte(&mut self) -> std::io::Result<Option<u8>> {
+        let mut byte = [0];
+        match self.reader.read(&mut byte)? {
+            0 => Ok(None),
+            _ => Ok(Some(byte[0])),
+        }
+    }
+
+    // The next packet or interrupt, `None` once the debugger hangs up.
+    fn read(&mut self) -> std::io::Result<Option<Input>> {
+        loop {
+            match self.read_byte()? {
+                None => return Ok(None),
+                Some(0x03) => return Ok(Some(Input::Interrupt)),
+                Some(b'$') => {
+                    let mut data = vec![];
+                    self.reader.read_until(b'#', &mut data)?;
+                    // a hang-up in the middle of the packet
+                    let Some(b'#') = data.pop() else {
+                        return Ok(None);
+                    };
+                    let mut sum = [0; 2];
+                    match self.reader.read_exact(&mut sum) {
+                        Err(error) if error.kind() == ErrorKind::UnexpectedEof => return Ok(None),
+                        result => result?,
+                    }
+                    let data = String::from_utf8_lossy(&data).to_string();
+                    let valid = std::str::from_utf8(&sum)
+                        .ok()
+                        .and_then(|sum| u8::from_str_radix(sum, 16).ok())
+                        .is_some_and(|sum| sum == checksum(&data));
+                    self.reader
+                        .get_mut()
+                        .write_all(if valid { b"+" } else { b"-" })?;
+                    if valid {
+                        tracing::debug!("gdb <- {}", data);
+                        return Ok(Some(Input::Packet(data)));
+                    }
+                }
+                // acknowledgements of our packets
+                Some(_) => {}
+            }
+        }
+    }
+
+    fn send(&mut self, data: &str) -> std::io::Result<()> {
+        tracing::debug!("gdb -> {}", data);
+        let packet = format!("${}#{:02x}", data, checksum(data));
+        self.reader.get_mut().write_all(packet.as_bytes())
+    }
+
+    // Whether the debugger sent Ctrl-C, without waiting for it.
+    fn interrupted(&mut self) -> bool {
+        if self.reader.buffer().is_empty() {
+            let stream = self.reader.get_ref();
+            if stream.set_nonblocking(true).is_ok() {
+                let _ = self.reader.fill_buf();
+                let _ = self.reader.get_ref().set_nonblocking(false);
+            }
+        }
+        // anything else, like an acknowledgement, is dropped
+        while let Some(&b) = self.reader.buffer().first() {
+            self.reader.consume(1);
+            if b == 0x03 {
+                return true;
+            }
+        }
+        false
+    }
+}
+
+/**
+A GDB remote protocol stub for a CPU, so that GDB and other tools using
+the protocol can debug a program over TCP:
+
+- `g`, `G`, `p` and `P` read and write A, X, Y, SP, PC and P, described
+  to the debugger by `target.xml`
+- `m` and `M` read and write memory through the bus, reads stop before
+  the PPU registers
+- `s` and `c` step and continue, `Z0`/`z0` (and `Z1`) set and remove
+  breakpoints, and Ctrl-C interrupts a running program
+
+A stop reply is SIGTRAP after a step or at a breakpoint, SIGINT after an
+interrupt, and SIGILL when the CPU jams on `KIL` or an unknown opcode.
+*/
+pub struct GdbStub {
+    cpu: CPU,
+    breakpoints: BTreeSet<u16>,
+}
+
+impl GdbStub {
+    pub fn new(cpu: CPU) -> Self {
+        GdbStub {
+            cpu,
+            breakpoints: BTreeSet::new(),
+        }
+    }
+
+    // Waits for debuggers on `addr`, one at a time, until one kills the
+    // program.
+    pub fn serve(&mut self, addr: &str) -> Result<(), NesError> {
+        let listener = TcpListener::bind(addr).change_context(NesError::Io)?;
+        loop {
+            println!("waiting for GDB on {}", addr);
+            let (stream, peer) = listener.accept().change_context(NesError::Io)?;
+            println!("GDB connected from {}", peer);
+            let mut connection = Connection {
+                reader: BufReader::new(stream),
+            };
+            if self.session(&mut connection).change_context(NesError::Io)? {
+                return Ok(());
+            }
+        }
+    }
+
+    // Serves a connection until the debugger leaves, returning whether it
+    // killed the program.
+    fn session(&mut self, connection: &mut Connection) -> std::io::Result<bool> {
+        while let Some(input) = connection.read()? {
+            let Input::Packet(packet) = input else {
+                // GDB waits for a stop reply even when the program isn't running
+                connection.send(&stop_reply(SIGINT))?;
+                continue;
+            };
+            match self.handle(&packet) {
+                Action::Reply(reply) => connection.send(&reply)?,
+                Action::Resume { step } => {
+                    let reply = self.resume(step, || connection.interrupted());
+                    connection.send(&reply)?;
+                }
+                Action::Detach => {
+                    connection.send("OK")?;
+                    return Ok(false);
+                }
+                Action::Kill => return Ok(true),
+            }
+        }
+        Ok(false)
+    }
+
+    fn registers(&self) -> [u8; 7] {
+        let cpu = &self.cpu;
+        let [pc_lo, pc_hi] = cpu.pc.to_le_bytes();
+        [cpu.a, cpu.x, cpu.y, cpu.sp, pc_lo, pc_hi, cpu.flags.get()]
+    }
+
+    fn set_registers(&mut self, regs: &[u8]) {
+        let cpu = &mut self.cpu;
+        cpu.a = regs[0];
+        cpu.x = regs[1];
+        cpu.y = regs[2];
+        cpu.sp = regs[3];
+        cpu.pc = u16::from_le_bytes([regs[4], regs[5]]);
+        cpu.flags.set(regs[6]);
+    }
+
+    // Runs until the next instruction is at a breakpoint, the CPU halts or
+    // `interrupted` says so, or for one instruction when stepping, and
+    // returns the stop reply.
+    fn resume(&mut self, step: bool, mut interrupted: impl FnMut() -> bool) -> String {
+        for count in 1.. {
+            if self.cpu.run_once().is_err() || self.cpu.halt {
+                return stop_reply(SIGILL);
+            }
+            if step || self.breakpoints.contains(&self.cpu.pc) {
+                break;
+            }
+            if count % RUN_CHUNK == 0 && interrupted() {
+                return stop_reply(SIGINT);
+            }
+        }
+        stop_reply(SIGTRAP)
+    }
+
+    fn handle(&mut self, packet: &str) -> Action {
+        let Some(command) = packet.chars().next() else {
+            return Action::Reply(String::new());
+        };
+        let args = &packet[command.len_utf8()..];
+        let reply = match command {
+            '?' => Some(stop_reply(SIGTRAP)),
+            'g' => Some(to_hex(&self.registers())),
+            'G' => from_hex(args).filter(|regs| regs.len() == 7).map(|regs| {
+                self.set_registers(&regs);
+                "OK".to_string()
+            }),
+            'p' => usize::from_str_radix(args, 16)
+                .ok()
+                .and_then(|n| REGISTERS.get(n))
+                .map(|&(start, end)| to_hex(&self.registers()[start..end])),
+            'P' => self.write_register(args),
+            'm' => self.read_memory(args),
+            'M' => self.write_memory(args),
+            'c' | 's' => {
+                if let Some(pc) = parse_hex(args) {
+                    self.cpu.pc = pc;
+                }
+                return Action::Resume {
+                    step: command == 's',
+                };
+            }
+            'Z' | 'z' => {
+                let fields = args.split(',').collect::<Vec<_>>();
+                match (
+                    fields.as_slice(),
+                    fields.get(1).and_then(|addr| parse_hex(addr)),
+                ) {
+                    ([kind, ..], Some(addr)) if matches!(*kind, "0" | "1") => {
+                        if command == 'Z' {
+                            self.breakpoints.insert(addr);
+                        } else {
+                            self.breakpoints.remove(&addr);
+                        }
+                        Some("OK".to_string())
+                    }
+                    // watchpoints aren't supported
+                    _ => return Action::Reply(String::new()),
+                }
+            }
+            'D' => return Action::Detach,
+            'k' => return Action::Kill,
+            // a single thread
+            'H' | 'T' => Some("OK".to_string()),
+            'q' => return Action::Reply(self.query(args)),
+            _ => return Action::Reply(String::new()),
+        };
+        Action::Reply(reply.unwrap_or("E01".to_string()))
+    }
+
+    fn query(&self, args: &str) -> String {
+        if let Some(annex) = args.strip_prefix("Xfer:features:read:target.xml:") {
+            let Some((offset, len)) = annex.split_once(',') else {
+                return "E01".to_string();
+            };
+            let (Ok(offset), Ok(len)) = (
+                usize::from_str_radix(offset, 16),
+                usize::from_str_radix(len, 16),
+            ) else {
+                return "E01".to_string();
+            };
+            let offset = offset.min(TARGET_XML.len());
+            let end = (offset + len).min(TARGET_XML.len());
+            let more = if end < TARGET_XML.len() { 'm' } else { 'l' };
+            return format!("{}{}", more, &TARGET_XML[offset..end]);
+        }
+        match args.split(':').next().unwrap_or_default() {
+            "Supported" => "PacketSize=1000;qXfer:features:read+".to_string(),
+            "Attached" => "1".to_string(),
+            "C" => "QC1".to_string(),
+            "fThreadInfo" => "m1".to_string(),
+            "sThreadInfo" => "l".to_string(),
+            _ => String::new(),
+        }
+    }
+
+    fn write_register(&mut self, args: &str) -> Option<String> {
+        let (n, value) = args.split_once('=')?;
+        let &(start, end) = REGISTERS.get(usize::from_str_radix(n, 16).ok()?)?;
+        let value = from_hex(value).filter(|value| value.len() == end - start)?;
+        let mut regs = self.registers();
+        regs[start..end].copy_from_slice(&value);
+        self.set_registers(&regs);
+        Some("OK".to_string())
+    }
+
+    // `addr,len`, stopping at the first byte that can't be read without
+    // side effects.
+    fn read_memory(&self, args: &str) -> Option<String> {
+        let (addr, len) = args.split_once(',')?;
+        let (addr, len) = (parse_hex(addr)?, usize::from_str_radix(len, 16).ok()?);
+        let bytes = (0..len)
+            .map_while(|i| self.cpu.peek_mem(addr.wrapping_add(i as u16)))
+            .collect::<Vec<_>>();
+        if bytes.is_empty() && len > 0 {
+            return None;
+        }
+        Some(to_hex(&bytes))
+    }
+
+    // `addr,len:bytes`
+    fn write_memory(&mut self, args: &str) -> Option<String> {
+        let (range, data) = args.split_once(':')?;
+        let (addr, len) = range.split_once(',')?;
+        let (addr, len) = (parse_hex(addr)?, usize::from_str_radix(len, 16).ok()?);
+        let bytes = from_hex(data).filter(|bytes| bytes.len() == len)?;
+        for (i, &b) in bytes.iter().enumerate() {
+            self.cpu.bus.write(addr.wrapping_add(i as u16), b);
+        }
+        Some("OK".to_string())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    // $0600: LDX #$03 / loop: DEX / BNE loop / KIL
+    fn stub() -> GdbStub {
+        let mut cpu = CPU::default();
+        cpu.load_program(&[0xa2, 0x03, 0xca, 0xd0, 0xfd, 0x02], 0x0600);
+        cpu.reset();
+        GdbStub::new(cpu)
+    }
+
+    fn reply(stub: &mut GdbStub, packet: &str) -> String {
+        match stub.handle(packet) {
+            Action::Reply(reply) => reply,
+            Action::Resume { step } => stub.resume(step, || false),
+            action => panic!("unexpected {:?}", action),
+        }
+    }
+
+    #[test]
+    fn test_registers() {
+        let mut stub = stub();
+        assert_eq!(reply(&mut stub, "g"), "000000fd000624");
+        assert_eq!(reply(&mut stub, "G0102030405060700"), "E01");
+        assert_eq!(reply(&mut stub, "G01020304050607"), "OK");
+        assert_eq!(
+            (stub.cpu.a, stub.cpu.sp, stub.cpu.pc, stub.cpu.flags.get()),
+            (1, 4, 0x0605, 0x27)
+        );
+        assert_eq!(reply(&mut stub, "P4=0206"), "OK");
+        assert_eq!(reply(&mut stub, "p4"), "0206");
+        assert_eq!(reply(&mut stub, "p6"), "E01");
+    }
+
+    #[test]
+    fn test_memory() {
+        let mut stub = stub();
+        assert_eq!(reply(&mut stub, "m600,3"), "a203ca");
+        assert_eq!(reply(&mut stub, "M200,2:4869"), "OK");
+        assert_eq!(reply(&mut stub, "m200,2"), "4869");
+        assert_eq!(reply(&mut stub, "M200,2:48"), "E01");
+        // reads stop at the PPU registers
+        assert_eq!(reply(&mut stub, "m1ffe,4"), "0000");
+        assert_eq!(reply(&mut stub, "m2000,1"), "E01");
+    }
+
+    #[test]
+    fn test_run() {
+        let mut stub = stub();
+        assert_eq!(reply(&mut stub, "s"), "S05");
+        assert_eq!(stub.cpu.pc, 0x0602);
+        assert_eq!(reply(&mut stub, "Z0,603,1"), "OK");
+        assert_eq!(reply(&mut stub, "c"), "S05");
+        assert_eq!((stub.cpu.pc, stub.cpu.x), (0x0603, 2));
+        assert_eq!(reply(&mut stub, "c"), "S05");
+        assert_eq!(stub.cpu.x, 1);
+        assert_eq!(reply(&mut stub, "z0,603,1"), "OK");
+        assert_eq!(reply(&mut stub, "c"), "S04");
+        assert_eq!(reply(&mut stub, "Z2,10,1"), "");
+        assert_eq!(stub.handle("k"), Action::Kill);
+    }
+
+    #[test]
+    fn test_queries() {
+        let mut stub = stub();
+        assert!(
+            reply(&mut stub, "qSupported:multiprocess+;xmlRegisters=i386")
+                .contains("qXfer:features:read+")
+        );
+        let xml = reply(&mut stub, "qXfer:features:read:target.xml:0,20");
+        assert_eq!(xml, format!("m{}", &TARGET_XML[..0x20]));
+        assert!(reply(&mut stub, "qXfer:features:read:target.xml:20,1000").starts_with("l"));
+        assert_eq!(reply(&mut stub, "vMustReplyEmpty"), "");
+        assert_eq!(reply(&mut stub, "\u{fffd}1"), "");
+        assert_eq!(checksum("OK"), 0x9a);
+    }
+
+    #[test]
+    fn test_connection() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut gdb = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let mut connection = Connection {
+            reader: BufReader::new(listener.accept().unwrap().0),
+        };
+        // a bad checksum, the packet again, Ctrl-C and a hang-up mid-packet
+        gdb.write_all(b"$g#00+$g#67\x03$m0,1#4").unwrap();
+        gdb.shutdown(std::net::Shutdown::Write).unwrap();
+        assert!(matches!(connection.read().unwrap(), Some(Input::Packet(packet)) if packet == "g"));
+        assert!(matches!(connection.read().unwrap(), Some(Input::Interrupt)));
+        assert!(connection.read().unwrap().is_none());
+        connection.send("OK").unwrap();
+        drop(connection);
+        let mut sent = String::new();
+        gdb.read_to_string(&mut sent).unwrap();
+        assert_eq!(sent, "-+$OK#9a");
+    }
+}
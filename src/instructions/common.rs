@@ -50,40 +50,43 @@ impl Inst {
     }
 
     pub fn to_string(&self, pc: Option<u16>) -> String {
+        self.to_string_with_names(pc, |_| None)
+    }
+
+    // Like `to_string`, with the addresses `name` knows written as names.
+    pub fn to_string_with_names(
+        &self,
+        pc: Option<u16>,
+        name: impl Fn(u16) -> Option<String>,
+    ) -> String {
+        let addr = |digits: usize| {
+            let param = self.param.unwrap();
+            name(param).unwrap_or_else(|| format!("${:0digits$X}", param, digits = digits))
+        };
         match self.mode {
             AddressingMode::Implied => format!("{}", self.name),
             AddressingMode::Accumulator => format!("{} A", self.name),
             AddressingMode::Relative => {
                 let delta = (self.param.unwrap() as i8).wrapping_add(2);
                 if let Some(addr) = pc {
-                    let target = addr.wrapping_add(delta as u16);
-                    format!("{} ${:04X}", self.name, target)
+                    let offset = self.param.unwrap() as u8 as i8;
+                    let target = addr.wrapping_add(2).wrapping_add(offset as u16);
+                    let target = name(target).unwrap_or_else(|| format!("${:04X}", target));
+                    format!("{} {}", self.name, target)
                 } else {
                     format!("{} *{:02X}", self.name, delta)
                 }
             }
             AddressingMode::Immediate => format!("{} #${:02X}", self.name, self.param.unwrap()),
-            AddressingMode::ZeroPage => format!("{} ${:02X}", self.name, self.param.unwrap()),
-            AddressingMode::ZeroPageX => {
-                format!("{} ${:02X},X", self.name, self.param.unwrap())
-            }
-            AddressingMode::ZeroPageY => {
-                format!("{} ${:02X},Y", self.name, self.param.unwrap())
-            }
-            AddressingMode::Absolute => format!("{} ${:04X}", self.name, self.param.unwrap()),
-            AddressingMode::AbsoluteX => {
-                format!("{} ${:04X},X", self.name, self.param.unwrap())
-            }
-            AddressingMode::AbsoluteY => {
-                format!("{} ${:04X},Y", self.name, self.param.unwrap())
-            }
-            AddressingMode::IndexedIndirect => {
-                format!("{} (${:02X},X)", self.name, self.param.unwrap())
-            }
-            AddressingMode::IndirectIndexed => {
-                format!("{} (${:02X}),Y", self.name, self.param.unwrap())
-            }
-            AddressingMode::Indirect => format!("{} (${:04X})", self.name, self.param.unwrap()),
+            AddressingMode::ZeroPage => format!("{} {}", self.name, addr(2)),
+            AddressingMode::ZeroPageX => format!("{} {},X", self.name, addr(2)),
+            AddressingMode::ZeroPageY => format!("{} {},Y", self.name, addr(2)),
+            AddressingMode::Absolute => format!("{} {}", self.name, addr(4)),
+            AddressingMode::AbsoluteX => format!("{} {},X", self.name, addr(4)),
+            AddressingMode::AbsoluteY => format!("{} {},Y", self.name, addr(4)),
+            AddressingMode::IndexedIndirect => format!("{} ({},X)", self.name, addr(2)),
+            AddressingMode::IndirectIndexed => format!("{} ({}),Y", self.name, addr(2)),
+            AddressingMode::Indirect => format!("{} ({})", self.name, addr(4)),
         }
     }
 }
@@ -524,42 +527,6 @@ macro_rules! define_t_inst {
     };
 }
 
-pub struct InstIter {
-    bytes: Vec<u8>,
-    idx: usize,
-}
-
-impl InstIter {
-    pub fn new(bytes: &[u8]) -> Self {
-        InstIter {
-            bytes: bytes.to_vec(),
-            idx: 0,
-        }
-    }
-}
-
-impl Iterator for InstIter {
-    type Item = Inst;
-
-    fn next(&mut self) -> Option<Self::Item> {
-        if self.idx >= self.bytes.len() {
-            return None;
-        }
-        let op = self.bytes[self.idx];
-        if let Some(factory) = INST_FACTORIES_BY_OP_CODE.get(&op) {
-            let inst = factory.make(self.bytes[(self.idx + 1)..].iter().cloned());
-            self.idx += inst.len() as usize;
-            Some(inst)
-        } else {
-            panic!("unknown op code: {:#x}", op);
-        }
-    }
-}
-
-pub fn disassemble(bytes: &[u8]) -> InstIter {
-    InstIter::new(bytes)
-}
-
 pub fn adc_helper(b: u8, cpu: &mut CPU) {
     let result16 = cpu.a as u16 + b as u16 + cpu.flags.c() as u16;
     let result = result16 as u8;
@@ -637,24 +604,38 @@ lazy_static! {
             HashMap::new();
         for info in INSTRUCTIONS.iter() {
             for (op, mode) in info.opcode_to_addressing_mode {
-                let res = inst_factory_by_name_mode.insert(
-                    (info.name.clone(), mode.clone()),
-                    InstFactory {
+                // NOP and SBC list their unofficial duplicates after the
+                // official opcode, so the first one wins.
+                inst_factory_by_name_mode
+                    .entry((info.name.clone(), *mode))
+                    .or_insert(InstFactory {
                         opcode: *op,
                         mode: *mode,
                         name: info.name.clone(),
                         f: info.f,
-                    },
-                );
-                if res.is_some() {
-                    panic!("duplicate op code: {:#x}", op);
-                }
+                    });
             }
         }
         inst_factory_by_name_mode
     };
 }
 
+// Instructions outside the documented 6502 instruction set.
+pub const UNOFFICIAL_INSTRUCTIONS: [&str; 12] = [
+    "SLO", "SRE", "LAX", "LAR", "KIL", "ISB", "DCP", "AXS", "SAX", "RLA", "RRA", "ANC",
+];
+
+// Whether `opcode` is a documented opcode. Besides the unofficial
+// instructions, every NOP but $EA and the SBC duplicate $EB are undocumented.
+pub fn is_official_opcode(opcode: u8) -> bool {
+    match INST_FACTORIES_BY_OP_CODE.get(&opcode) {
+        Some(factory) if factory.name == "NOP" => opcode == 0xEA,
+        Some(factory) if factory.name == "SBC" => opcode != 0xEB,
+        Some(factory) => !UNOFFICIAL_INSTRUCTIONS.contains(&factory.name.as_str()),
+        None => false,
+    }
+}
+
 pub struct InstFactory {
     pub opcode: u8,
     pub name: String,
@@ -2,7 +2,8 @@ mod common;
 use crate::define_instructions;
 use crate::instructions::common::InstructionInfo;
 pub use common::{
-    disassemble, Inst, InstFun, INST_FACTORIES_BY_NAME_MODE, INST_FACTORIES_BY_OP_CODE,
+    is_official_opcode, Inst, InstFactory, InstFun, INST_FACTORIES_BY_NAME_MODE,
+    INST_FACTORIES_BY_OP_CODE,
 };
 use lazy_static::lazy_static;
 
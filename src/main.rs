@@ -1,32 +1,48 @@
+mod apu_log;
 mod assembler;
 mod bus;
 mod cpu;
+mod debugger;
+mod disassembler;
 mod error;
+mod flow_graph;
+mod gdb_stub;
 mod instructions;
 mod io;
+mod mapper;
 mod nes_format;
+mod nsf_format;
+mod nsf_player;
 mod ppu;
 mod screen;
+mod symbols;
 
 use std::fs::File;
 use std::io::BufRead;
 use std::io::BufReader;
 use std::time::Duration;
 
-use assembler::AsmLine;
+use apu_log::NTSC_CYCLES_PER_FRAME;
 use assembler::Assembler;
-use clap::{arg, Command};
+use assembler::MemoryLayout;
+use assembler::Syntax;
+use assembler::{format_listing, format_symbols, read_source};
+use clap::{arg, ArgAction, Command};
 use cpu::CpuState;
 use cpu::CPU;
+use debugger::Debugger;
+use disassembler::Disassembly;
 use error::NesError;
 use error_stack::bail;
-use error_stack::{Result, ResultExt};
-use instructions::disassemble;
+use error_stack::{Report, Result, ResultExt};
+use flow_graph::FlowGraph;
+use gdb_stub::GdbStub;
 use instructions::INST_FACTORIES_BY_OP_CODE;
 use io::read_file;
-use io::read_file_lines;
 use io::write_file;
 use nes_format::read_nes_file;
+use nsf_format::read_nsf_file;
+use nsf_player::NsfPlayer;
 use ppu::SYSTEM_PALLETE;
 use ppu::TILE_HEIGHT;
 use ppu::TILE_WIDTH;
@@ -37,6 +53,7 @@ use sdl2::keyboard::Keycode;
 use sdl2::pixels::Color;
 use sdl2::pixels::PixelFormatEnum;
 use sdl2::EventPump;
+use symbols::SymbolTable;
 use tracing::Level;
 
 fn handle_user_input(cpu: &mut CPU, event_pump: &mut EventPump) {
@@ -107,7 +124,7 @@ fn read_screen_state(cpu: &CPU, frame: &mut [u8; 32 * 3 * 32]) -> bool {
     update
 }
 
-fn run_code(game_code: Vec<u8>, start_addr: u16) -> Result<(), NesError> {
+fn run_code(game_code: Vec<u8>, start_addr: u16, symbols: &SymbolTable) -> Result<(), NesError> {
     let sdl_context = sdl2::init().unwrap();
     let video_subsystem = sdl_context.video().unwrap();
     let window = video_subsystem
@@ -128,7 +145,7 @@ fn run_code(game_code: Vec<u8>, start_addr: u16) -> Result<(), NesError> {
     cpu.reset();
     let mut screen_state = [0 as u8; 32 * 3 * 32];
     let mut rng = rand::thread_rng();
-    cpu.run_with_callback(|cpu| {
+    let res = cpu.run_with_callback(|cpu| {
         handle_user_input(cpu, &mut event_pump);
         cpu.set_mem(0xfe, rng.gen_range(1..=16));
         if read_screen_state(cpu, &mut screen_state) {
@@ -139,26 +156,253 @@ fn run_code(game_code: Vec<u8>, start_addr: u16) -> Result<(), NesError> {
 
         ::std::thread::sleep(std::time::Duration::new(0, 200_000));
         Ok(())
-    })?;
+    });
+    res.map_err(|report| attach_backtrace(report, &cpu, symbols))
+}
+
+// Adds the call stack to the report of a cpu that jammed, naming its
+// addresses with `symbols`.
+fn attach_backtrace(
+    report: Report<NesError>,
+    cpu: &CPU,
+    symbols: &SymbolTable,
+) -> Report<NesError> {
+    match report.downcast_ref::<NesError>() {
+        Some(NesError::HaltError) => {
+            report.attach_printable(cpu.call_stack.backtrace(cpu.pc, symbols))
+        }
+        _ => report,
+    }
+}
+
+// The hardware register names and the symbols of `symbol_files`.
+fn load_symbols(symbol_files: &[&String], prg_size: usize) -> Result<SymbolTable, NesError> {
+    let mut symbols = SymbolTable::hardware();
+    for path in symbol_files {
+        symbols.load(path, prg_size)?;
+    }
+    Ok(symbols)
+}
+
+fn load_code(file: &str, start_addr: u16) -> Result<Vec<u8>, NesError> {
+    if file.ends_with(".bin") {
+        read_file(file).change_context(NesError::Io)
+    } else if file.ends_with(".asm") {
+        assemble_file(file, start_addr)
+    } else if file.ends_with(".nes") {
+        let nes_file = read_nes_file(file).change_context(NesError::Io)?;
+        Ok(nes_file.prg_rom)
+    } else {
+        bail!(NesError::InvalidFileExtension(file.to_string()));
+    }
+}
+
+// Runs the cpu without any front-end until it halts or `max_cycles` elapse.
+fn run_headless(cpu: &mut CPU, max_cycles: u64) -> Result<(), NesError> {
+    let res = cpu.run_with_callback(|cpu| {
+        if cpu.cycles() >= max_cycles {
+            cpu.halt();
+        }
+        Ok(())
+    });
+    match res {
+        Err(report) => match report.downcast_ref::<NesError>() {
+            Some(NesError::HaltError) => Ok(()),
+            _ => Err(report),
+        },
+        Ok(_) => Ok(()),
+    }
+}
+
+fn record_apu(code: Vec<u8>, start_addr: u16, frames: u64, out: &str) -> Result<(), NesError> {
+    let mut cpu = cpu::CPU::default();
+    cpu.load_program(&code, start_addr);
+    cpu.reset();
+    cpu.bus.start_apu_log();
+    let max_cycles = cpu.cycles() + frames * NTSC_CYCLES_PER_FRAME;
+    run_headless(&mut cpu, max_cycles)?;
+    let log = cpu.bus.take_apu_log().unwrap();
+    tracing::info!("recorded {} APU register writes", log.writes().len());
+    write_file(out, &log.to_vgm(cpu.cycles())).change_context(NesError::Io)?;
+    Ok(())
+}
+
+// Inserts the song number before the extension when several songs are rendered.
+fn song_output_path(out: &str, song: u8, multiple: bool) -> String {
+    if !multiple {
+        return out.to_string();
+    }
+    match out.rfind('.') {
+        Some(i) => format!("{}-{}{}", &out[..i], song, &out[i..]),
+        None => format!("{}-{}", out, song),
+    }
+}
+
+fn play_nsf(file: &str, song: Option<u8>, seconds: u64, out: &str) -> Result<(), NesError> {
+    let nsf = read_nsf_file(file).change_context(NesError::Io)?;
+    let header = &nsf.header;
+    println!(
+        "{} - {} ({}), NSF v{}, {} songs, starting song {}",
+        header.song_name,
+        header.artist,
+        header.copyright,
+        header.version,
+        header.total_songs,
+        header.starting_song
+    );
+    println!(
+        "load={:04X} init={:04X} play={:04X} speed(ntsc/pal)={}/{}us bankswitched={}",
+        header.load_addr,
+        header.init_addr,
+        header.play_addr,
+        header.play_speed_ntsc,
+        header.play_speed_pal,
+        nsf.is_bankswitched()
+    );
+    if header.pal_ntsc_bits & 0b11 == 0b01 {
+        tracing::warn!("PAL only tune, playing at the NTSC rate");
+    }
+    if header.extra_sound_chips != 0 {
+        tracing::warn!(
+            "expansion audio {:#04x} is not supported, its registers are not recorded",
+            header.extra_sound_chips
+        );
+    }
+    let songs = match song {
+        Some(song) => {
+            if song == 0 || song > header.total_songs {
+                bail!(NesError::InvalidSong(song));
+            }
+            song..=song
+        }
+        None => 1..=header.total_songs,
+    };
+    let multiple = song.is_none() && header.total_songs > 1;
+    for song in songs {
+        let mut player = NsfPlayer::new(&nsf);
+        let log = player.play(song, seconds)?;
+        let path = song_output_path(out, song, multiple);
+        write_file(&path, &log.to_vgm(player.cycles())).change_context(NesError::Io)?;
+        println!(
+            "song {}: {} APU writes -> {}",
+            song,
+            log.writes().len(),
+            path
+        );
+    }
     Ok(())
 }
 
-fn disassemble_file(file: &str) -> Result<(), NesError> {
-    let game_code = read_file(file).change_context(NesError::Io)?;
-    for ins in disassemble(&game_code) {
-        println!("{:?}", ins);
+// Disassembles a .nes file from its vectors with `banks` mapped, or a raw
+// binary loaded at `start` from its first byte, naming the hardware
+// registers and the symbols of `symbol_files`. Source output is checked to
+// assemble back to the same bytes.
+fn disassemble_file(
+    file: &str,
+    start: u16,
+    format: &str,
+    symbol_files: &[&String],
+    banks: &[(u16, usize)],
+) -> Result<(), NesError> {
+    let (disassembly, prg_size) = if file.ends_with(".nes") {
+        let nes = read_nes_file(file).change_context(NesError::Io)?;
+        (Disassembly::from_nes_file(&nes, banks)?, nes.prg_rom.len())
+    } else {
+        let game_code = read_file(file).change_context(NesError::Io)?;
+        (Disassembly::new(&game_code, start, &[("start", start)]), 0)
+    };
+    let disassembly = disassembly.with_symbols(load_symbols(symbol_files, prg_size)?);
+    match format {
+        "source" => {
+            let source = disassembly.format_source()?;
+            disassembly.check_round_trip(&source)?;
+            print!("{}", source);
+        }
+        _ => print!("{}", disassembly.format_listing()),
+    }
+    Ok(())
+}
+
+// Writes the control flow graph of the subroutine at `start` in a .nes
+// file, by default its reset routine, and of the subroutines it calls.
+fn flow_graph_file(
+    file: &str,
+    start: Option<u16>,
+    symbol_files: &[&String],
+    banks: &[(u16, usize)],
+    out: &str,
+) -> Result<(), NesError> {
+    if !file.ends_with(".nes") {
+        bail!(NesError::InvalidFileExtension(file.to_string()));
     }
+    let nes = read_nes_file(file).change_context(NesError::Io)?;
+    let mut disassembly = Disassembly::from_nes_file(&nes, banks)?;
+    let entry = match start {
+        Some(start) => {
+            disassembly = disassembly.with_entry("start", start);
+            start
+        }
+        None => disassembly.reset_vector().unwrap_or_default(),
+    };
+    let symbols = load_symbols(symbol_files, nes.prg_rom.len())?;
+    let disassembly = disassembly.with_symbols(symbols);
+    let graph = FlowGraph::new(&disassembly, entry);
+    write_file(out, graph.to_dot().as_bytes()).change_context(NesError::Io)?;
+    println!("{} subroutines -> {}", graph.subroutines().len(), out);
     Ok(())
 }
 
+// Loads a raw binary or an assembled source at `start`, or the PRG ROM of a
+// .nes file at the top of memory, and resets the CPU to it. The addresses
+// are named by the assembler's symbols or by `symbol_files`.
+fn load_debug_target(
+    file: &str,
+    start: u16,
+    symbol_files: &[&String],
+) -> Result<(CPU, SymbolTable), NesError> {
+    let mut symbols = SymbolTable::hardware();
+    let mut prg_size = 0;
+    let mut cpu = if file.ends_with(".nes") {
+        let nes = read_nes_file(file).change_context(NesError::Io)?;
+        prg_size = nes.prg_rom.len();
+        let prg_rom = nes.prg_rom.clone();
+        // the last 32KB, or a 16KB PRG ROM at $C000 and mirrored at $8000
+        let prg = &prg_rom[prg_size.saturating_sub(0x8000)..];
+        let mut cpu = CPU::new(nes);
+        if prg.len() == 0x4000 {
+            cpu.load_program(prg, 0x8000);
+        }
+        cpu.load_program(prg, (0x10000 - prg.len()) as u16);
+        cpu
+    } else if file.ends_with(".asm") {
+        let source = read_source(file, Syntax::Native)?;
+        let mut assembler = Assembler::new(start).with_locations(&source.locations);
+        let code = assembler.assemble(&source.lines)?.to_vec();
+        for (name, value) in assembler.symbols() {
+            if let Ok(addr) = u16::try_from(value) {
+                symbols.insert(addr, &name);
+            }
+        }
+        let mut cpu = CPU::default();
+        cpu.load_program(&code, start);
+        cpu
+    } else {
+        let code = load_code(file, start)?;
+        let mut cpu = CPU::default();
+        cpu.load_program(&code, start);
+        cpu
+    };
+    for path in symbol_files {
+        symbols.load(path, prg_size)?;
+    }
+    cpu.reset();
+    Ok((cpu, symbols))
+}
+
 fn assemble_file(file_path: &str, start_addr: u16) -> Result<Vec<u8>, NesError> {
-    let mut assembler = Assembler::new(start_addr);
-    let lines = read_file_lines(file_path).change_context(NesError::Io)?;
-    let asm_lines = lines
-        .iter()
-        .map(|s| s.parse::<AsmLine>())
-        .collect::<std::result::Result<Vec<AsmLine>, NesError>>()?;
-    let bytes = assembler.assemble(&asm_lines)?;
+    let source = read_source(file_path, Syntax::Native)?;
+    let mut assembler = Assembler::new(start_addr).with_locations(&source.locations);
+    let bytes = assembler.assemble(&source.lines)?;
     Ok(bytes.to_vec())
 }
 
@@ -169,6 +413,22 @@ fn parse_int16(s: &str) -> Result<u16, NesError> {
     s.parse::<u16>().change_context(NesError::ParseInt)
 }
 
+// Parses the `--bank` mappings, `0x8000=3` for bank 3 in the window at
+// $8000.
+fn parse_banks(
+    args: Option<clap::parser::ValuesRef<String>>,
+) -> Result<Vec<(u16, usize)>, NesError> {
+    let mut banks = vec![];
+    for arg in args.unwrap_or_default() {
+        let Some((window, bank)) = arg.split_once('=') else {
+            bail!(NesError::InvalidBank(arg.to_string()));
+        };
+        let bank = bank.parse::<usize>().change_context(NesError::ParseInt)?;
+        banks.push((parse_int16(window)?, bank));
+    }
+    Ok(banks)
+}
+
 struct CpuStateReader {
     reader: BufReader<File>,
 }
@@ -198,23 +458,30 @@ fn test_code(
     code: Vec<u8>,
     start_addr: u16,
     mut state_reader: CpuStateReader,
+    symbols: &SymbolTable,
 ) -> Result<(), NesError> {
     let mut cpu = cpu::CPU::default();
+    // a 16KB PRG ROM is mirrored at $8000, which traces show reads from
+    if code.len() == 0x4000 && start_addr == 0xC000 {
+        cpu.load_program(&code, 0x8000);
+    }
     cpu.load_program(&code, start_addr);
     cpu.reset();
     cpu.pc = start_addr;
+    let mut finished = false;
     let res = cpu.run_with_callback(|cpu| {
         let state = cpu.trace()?;
         match state_reader.next() {
             Ok(expected) => {
                 assert_eq!(state, expected);
-                tracing::info!("passed: {:?}", state);
+                tracing::info!("passed: {}", state.format_with_symbols(symbols));
             }
             Err(e) => {
                 if let Some(e) = e.downcast_ref::<NesError>() {
                     match e {
                         NesError::EndOfFile => {
                             tracing::info!("All tests passed");
+                            finished = true;
                             cpu.halt();
                         }
                         _ => {
@@ -227,17 +494,12 @@ fn test_code(
         }
         Ok(())
     });
-    return match &res {
-        Err(report) => {
-            if let Some(nes_error) = report.downcast_ref::<NesError>() {
-                match nes_error {
-                    NesError::HaltError => Ok(()),
-                    _ => res,
-                }
-            } else {
-                res
-            }
-        }
+    // halting before the end of the trace means the cpu jammed
+    return match res {
+        Err(report) => match report.downcast_ref::<NesError>() {
+            Some(NesError::HaltError) if finished => Ok(()),
+            _ => Err(attach_backtrace(report, &cpu, symbols)),
+        },
         Ok(_) => Ok(()),
     };
 }
@@ -330,22 +592,121 @@ fn main() -> Result<(), NesError> {
                         .default_value("0x0600")
                         .required(false),
                 )
+                .arg(
+                    arg!(--symbols <FILE> "A symbol file to name addresses with if the cpu jams: .nl, .mlb, .dbg or .sym")
+                        .action(ArgAction::Append)
+                        .required(false),
+                )
                 .arg(arg!(<FILE> "The file to run").required(true).index(1)),
         )
+        .subcommand(
+            Command::new("record_apu")
+                .about("Runs the specified file headless and records APU register writes as VGM")
+                .arg(
+                    arg!(--start <ADDRESS> "The start address for assembling")
+                        .default_value("0x0600")
+                        .required(false),
+                )
+                .arg(
+                    arg!(--frames <FRAMES> "The number of frames to record")
+                        .default_value("600")
+                        .required(false),
+                )
+                .arg(arg!(--out <OUT> "The output VGM file").required(true))
+                .arg(arg!(<FILE> "The file to run").required(true).index(1)),
+        )
+        .subcommand(
+            Command::new("play_nsf")
+                .about("Plays an NSF file headless and records each song's APU writes as VGM (no WAV audio is rendered)")
+                .arg(arg!(--song <SONG> "The song to play, starting from 1 (default: all songs)").required(false))
+                .arg(
+                    arg!(--seconds <SECONDS> "The number of seconds to play per song")
+                        .default_value("60")
+                        .required(false),
+                )
+                .arg(arg!(--out <OUT> "The output VGM file; play it back with a VGM player to hear it").required(true))
+                .arg(arg!(<FILE> "The NSF file to play").required(true).index(1)),
+        )
         .subcommand(
             Command::new("disassemble")
-                .about("Disassembles the specified file")
+                .about("Disassembles the specified file, following the code from its entry points")
+                .arg(
+                    arg!(--start <ADDRESS> "The load and entry address of a raw binary")
+                        .default_value("0x0600")
+                        .required(false),
+                )
+                .arg(
+                    arg!(--format <FORMAT> "A listing, or source that reassembles to the same bytes")
+                        .value_parser(["listing", "source"])
+                        .default_value("listing")
+                        .required(false),
+                )
+                .arg(
+                    arg!(--symbols <FILE> "A symbol file to name addresses with: .nl, .mlb, .dbg or .sym")
+                        .action(ArgAction::Append)
+                        .required(false),
+                )
+                .arg(
+                    arg!(--bank <MAPPING> "Maps a PRG bank to a switchable window to follow code into it: 0x8000=3")
+                        .action(ArgAction::Append)
+                        .required(false),
+                )
                 .arg(
                     arg!(<FILE> "The file to disassemble")
                         .required(true)
                         .index(1),
                 ),
         )
+        .subcommand(
+            Command::new("flow_graph")
+                .about("Writes the control flow graphs of a subroutine and its callees as Graphviz DOT")
+                .arg(arg!(--start <ADDRESS> "The address of the subroutine (default: the reset vector)").required(false))
+                .arg(
+                    arg!(--symbols <FILE> "A symbol file to name addresses with: .nl, .mlb, .dbg or .sym")
+                        .action(ArgAction::Append)
+                        .required(false),
+                )
+                .arg(
+                    arg!(--bank <MAPPING> "Maps a PRG bank to a switchable window to follow code into it: 0x8000=3")
+                        .action(ArgAction::Append)
+                        .required(false),
+                )
+                .arg(arg!(--out <OUT> "The output DOT file").required(true))
+                .arg(arg!(<FILE> "The .nes file to read").required(true).index(1)),
+        )
+        .subcommand(
+            Command::new("debug")
+                .about("Runs the specified file in an interactive debugger")
+                .arg(
+                    arg!(--start <ADDRESS> "The start address of a raw binary or source")
+                        .default_value("0x0600")
+                        .required(false),
+                )
+                .arg(
+                    arg!(--symbols <FILE> "A symbol file to name addresses with: .nl, .mlb, .dbg or .sym")
+                        .action(ArgAction::Append)
+                        .required(false),
+                )
+                .arg(
+                    arg!(--gdb <ADDRESS> "Serve the GDB remote protocol on ADDRESS, like localhost:1234, instead of prompting")
+                        .required(false),
+                )
+                .arg(arg!(<FILE> "The file to debug").required(true).index(1)),
+        )
         .subcommand(
             Command::new("assemble")
                 .about("Assembles the specified file")
                 .arg(arg!(--start <ADDRESS> "The start address for assembling").required(false))
-                .arg(arg!(--out <OUT> "The output file").required(true))
+                .arg(arg!(--out <OUT> "The output file, a .nes file is linked from segments").required(true))
+                .arg(arg!(--layout <LAYOUT> "The memory layout for linking a .nes file").required(false))
+                .arg(arg!(--listing <LISTING> "Writes a listing with the address and bytes of every line").required(false))
+                .arg(arg!(--symbols <SYMBOLS> "Writes the labels and constants").required(false))
+                .arg(arg!(--unofficial "Accepts unofficial opcodes"))
+                .arg(
+                    arg!(--syntax <SYNTAX> "asm6 also accepts directives without a dot, labels without a colon and EQU")
+                        .value_parser(["native", "asm6"])
+                        .default_value("native"),
+                )
                 .arg(arg!(<FILE> "The file to assemble").required(true).index(1)),
         )
         .subcommand(
@@ -358,6 +719,11 @@ fn main() -> Result<(), NesError> {
                 .about("Run program in test mode")
                 .arg(arg!(--start <ADDRESS> "The start address for assembling").required(false))
                 .arg(arg!(--out <OUT> "The output for cpu traces").required(true))
+                .arg(
+                    arg!(--symbols <FILE> "A symbol file to name addresses with if the cpu jams: .nl, .mlb, .dbg or .sym")
+                        .action(ArgAction::Append)
+                        .required(false),
+                )
                 .arg(arg!(<FILE> "The file to test").required(true).index(1)),
         )
         .get_matches();
@@ -366,30 +732,128 @@ fn main() -> Result<(), NesError> {
             let file = sub_m.get_one::<String>("FILE").unwrap();
             let start = parse_int16(sub_m.get_one::<String>("start").unwrap())
                 .change_context(NesError::ParseInt)?;
-            if file.ends_with(".bin") {
-                let code = read_file(file).change_context(NesError::Io)?;
-                run_code(code, start)?;
-            } else if file.ends_with(".asm") {
-                let code = assemble_file(file, start)?;
-                run_code(code, start)?;
-            } else if file.ends_with(".nes") {
-                let nes_file = read_nes_file(file).change_context(NesError::Io)?;
-                run_code(nes_file.prg_rom, start)?;
+            let symbol_files = sub_m
+                .get_many::<String>("symbols")
+                .unwrap_or_default()
+                .collect::<Vec<_>>();
+            let code = load_code(file, start)?;
+            let prg_size = if file.ends_with(".nes") {
+                code.len()
             } else {
-                bail!(NesError::InvalidFileExtension(file.to_string()));
-            }
+                0
+            };
+            let symbols = load_symbols(&symbol_files, prg_size)?;
+            run_code(code, start, &symbols)?;
+        }
+        Some(("record_apu", sub_m)) => {
+            let file = sub_m.get_one::<String>("FILE").unwrap();
+            let out = sub_m.get_one::<String>("out").unwrap();
+            let start = parse_int16(sub_m.get_one::<String>("start").unwrap())
+                .change_context(NesError::ParseInt)?;
+            let frames = sub_m
+                .get_one::<String>("frames")
+                .unwrap()
+                .parse::<u64>()
+                .change_context(NesError::ParseInt)?;
+            let code = load_code(file, start)?;
+            record_apu(code, start, frames, out)?;
+        }
+        Some(("play_nsf", sub_m)) => {
+            let file = sub_m.get_one::<String>("FILE").unwrap();
+            let out = sub_m.get_one::<String>("out").unwrap();
+            let song = sub_m
+                .get_one::<String>("song")
+                .map(|s| s.parse::<u8>())
+                .transpose()
+                .change_context(NesError::ParseInt)?;
+            let seconds = sub_m
+                .get_one::<String>("seconds")
+                .unwrap()
+                .parse::<u64>()
+                .change_context(NesError::ParseInt)?;
+            play_nsf(file, song, seconds, out)?;
         }
         Some(("disassemble", sub_m)) => {
             let file = sub_m.get_one::<String>("FILE").unwrap();
-            disassemble_file(file)?;
+            let start = parse_int16(sub_m.get_one::<String>("start").unwrap())
+                .change_context(NesError::ParseInt)?;
+            let format = sub_m.get_one::<String>("format").unwrap();
+            let symbol_files = sub_m
+                .get_many::<String>("symbols")
+                .unwrap_or_default()
+                .collect::<Vec<_>>();
+            let banks = parse_banks(sub_m.get_many::<String>("bank"))?;
+            disassemble_file(file, start, format, &symbol_files, &banks)?;
         }
-        Some(("assemble", sub_m)) => {
+        Some(("flow_graph", sub_m)) => {
+            let file = sub_m.get_one::<String>("FILE").unwrap();
+            let out = sub_m.get_one::<String>("out").unwrap();
+            let start = sub_m
+                .get_one::<String>("start")
+                .map(|s| parse_int16(s))
+                .transpose()
+                .change_context(NesError::ParseInt)?;
+            let symbol_files = sub_m
+                .get_many::<String>("symbols")
+                .unwrap_or_default()
+                .collect::<Vec<_>>();
+            let banks = parse_banks(sub_m.get_many::<String>("bank"))?;
+            flow_graph_file(file, start, &symbol_files, &banks, out)?;
+        }
+        Some(("debug", sub_m)) => {
             let file = sub_m.get_one::<String>("FILE").unwrap();
-            let output_file = sub_m.get_one::<String>("out").unwrap();
             let start = parse_int16(sub_m.get_one::<String>("start").unwrap())
                 .change_context(NesError::ParseInt)?;
-            let bytes = assemble_file(file, start)?;
+            let symbol_files = sub_m
+                .get_many::<String>("symbols")
+                .unwrap_or_default()
+                .collect::<Vec<_>>();
+            let (cpu, symbols) = load_debug_target(file, start, &symbol_files)?;
+            match sub_m.get_one::<String>("gdb") {
+                Some(addr) => GdbStub::new(cpu).serve(addr)?,
+                None => Debugger::new(cpu, symbols).run()?,
+            }
+        }
+        Some(("assemble", sub_m)) => {
+            let file = sub_m.get_one::<String>("FILE").unwrap();
+            let output_file = sub_m.get_one::<String>("out").unwrap();
+            let syntax = match sub_m.get_one::<String>("syntax").unwrap().as_str() {
+                "asm6" => Syntax::Asm6,
+                _ => Syntax::Native,
+            };
+            let source = read_source(file, syntax)?;
+            let unofficial = sub_m.get_flag("unofficial");
+            let (assembler, bytes) = if output_file.ends_with(".nes") {
+                let layout = match sub_m.get_one::<String>("layout") {
+                    Some(layout) => MemoryLayout::read(layout)?,
+                    None => MemoryLayout::default(),
+                };
+                let mut assembler =
+                    Assembler::with_layout(layout).with_locations(&source.locations);
+                if unofficial {
+                    assembler = assembler.allow_unofficial();
+                }
+                let bytes = assembler.link(&source.lines)?;
+                (assembler, bytes)
+            } else {
+                let start = parse_int16(sub_m.get_one::<String>("start").unwrap())
+                    .change_context(NesError::ParseInt)?;
+                let mut assembler = Assembler::new(start).with_locations(&source.locations);
+                if unofficial {
+                    assembler = assembler.allow_unofficial();
+                }
+                let bytes = assembler.assemble(&source.lines)?.to_vec();
+                (assembler, bytes)
+            };
             write_file(output_file, &bytes).change_context(NesError::Io)?;
+            if let Some(listing) = sub_m.get_one::<String>("listing") {
+                let text = format_listing(&assembler, &source.locations);
+                write_file(listing, text.as_bytes()).change_context(NesError::Io)?;
+            }
+            if let Some(symbols) = sub_m.get_one::<String>("symbols") {
+                let text = format_symbols(&assembler.symbols());
+                write_file(symbols, text.as_bytes()).change_context(NesError::Io)?;
+            }
         }
         Some(("show_tiles", sub_m)) => {
             let file = sub_m.get_one::<String>("FILE").unwrap();
@@ -401,12 +865,18 @@ fn main() -> Result<(), NesError> {
             let trace_log_file = sub_m.get_one::<String>("out").unwrap();
             let start = parse_int16(sub_m.get_one::<String>("start").unwrap())
                 .change_context(NesError::ParseInt)?;
+            let symbol_files = sub_m
+                .get_many::<String>("symbols")
+                .unwrap_or_default()
+                .collect::<Vec<_>>();
             if file.ends_with(".nes") {
                 let nes_file = read_nes_file(file).change_context(NesError::Io)?;
+                let symbols = load_symbols(&symbol_files, nes_file.prg_rom.len())?;
                 test_code(
                     nes_file.prg_rom,
                     start,
                     CpuStateReader::new(trace_log_file)?,
+                    &symbols,
                 )?;
                 println!("instruction count: {}", INST_FACTORIES_BY_OP_CODE.len());
                 for i in 0..256 {
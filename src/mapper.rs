@@ -0,0 +1,97 @@
+// https://www.nesdev.org/wiki/Mapper
+
+// A range of CPU addresses that shows one bank of PRG ROM.
+pub struct PrgWindow {
+    pub start: u16,
+    // the bank it always shows, `None` when the program switches it
+    pub fixed: Option<usize>,
+}
+
+/**
+How a mapper divides the PRG ROM into banks of `bank_size` bytes and
+where the CPU sees them:
+
+- NROM and CNROM: one 32KB bank at $8000, or a 16KB bank at both $8000
+  and $C000
+- UxROM, MMC1 and unknown mappers: a switchable 16KB bank at $8000 and
+  the last one fixed at $C000, MMC1's power-on mode on most boards
+- MMC3: switchable 8KB banks at $8000 and $A000, and the last two fixed
+  at $C000 and $E000
+- AxROM: a switchable 32KB bank at $8000
+*/
+pub struct PrgLayout {
+    pub bank_size: usize,
+    pub windows: Vec<PrgWindow>,
+}
+
+impl PrgLayout {
+    pub fn new(mapper: u8, prg_size: usize) -> Self {
+        let last = |bank_size: usize| (prg_size / bank_size).max(1) - 1;
+        let (bank_size, windows) = match mapper {
+            _ if prg_size <= 0x4000 => (0x4000, vec![(0x8000, Some(0)), (0xc000, Some(0))]),
+            0 | 3 if prg_size == 0x8000 => (0x8000, vec![(0x8000, Some(0))]),
+            4 => (
+                0x2000,
+                vec![
+                    (0x8000, None),
+                    (0xa000, None),
+                    (0xc000, Some(last(0x2000) - 1)),
+                    (0xe000, Some(last(0x2000))),
+                ],
+            ),
+            7 => (0x8000, vec![(0x8000, None)]),
+            _ => (0x4000, vec![(0x8000, None), (0xc000, Some(last(0x4000)))]),
+        };
+        let windows = windows
+            .into_iter()
+            .map(|(start, fixed)| PrgWindow { start, fixed })
+            .collect();
+        PrgLayout { bank_size, windows }
+    }
+
+    // A single bank of `size` bytes at `start`, for programs without a
+    // cartridge.
+    pub fn flat(start: u16, size: usize) -> Self {
+        PrgLayout {
+            bank_size: size.max(1),
+            windows: vec![PrgWindow {
+                start,
+                fixed: Some(0),
+            }],
+        }
+    }
+
+    // The index of the window holding `addr`.
+    pub fn window(&self, addr: u16) -> Option<usize> {
+        self.windows.iter().position(|window| {
+            addr >= window.start && ((addr - window.start) as usize) < self.bank_size
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_layout() {
+        let nrom = PrgLayout::new(0, 0x4000);
+        assert_eq!(nrom.window(0x8000), Some(0));
+        assert_eq!(nrom.window(0xffff), Some(1));
+        assert_eq!(nrom.window(0x7fff), None);
+        let uxrom = PrgLayout::new(2, 0x20000);
+        assert_eq!(uxrom.windows[1].fixed, Some(7));
+        let mmc3 = PrgLayout::new(4, 0x20000);
+        assert_eq!(mmc3.window(0xa123), Some(1));
+        assert_eq!(
+            mmc3.windows
+                .iter()
+                .map(|window| window.fixed)
+                .collect::<Vec<_>>(),
+            vec![None, None, Some(14), Some(15)]
+        );
+        let flat = PrgLayout::flat(0x0600, 0x100);
+        assert_eq!(flat.window(0x06ff), Some(0));
+        assert_eq!(flat.window(0x0700), None);
+    }
+}
@@ -24,6 +24,11 @@ pub enum Mirroring {
 }
 
 impl NesFile {
+    // https://www.nesdev.org/wiki/INES#Flags_6
+    pub fn mapper(&self) -> u8 {
+        (self.header.flags_7 & 0xf0) | (self.header.flags_6 >> 4)
+    }
+
     pub fn mirroring(&self) -> Mirroring {
         if self.header.flags_6 & 0b00000001 == 0 {
             Mirroring::Horizontal
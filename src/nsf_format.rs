@@ -0,0 +1,140 @@
+use std::io::{Error, ErrorKind};
+
+use crate::io::read_file;
+
+pub const NSF_BANK_SIZE: usize = 0x1000;
+const NSF_HEADER_SIZE: usize = 0x80;
+
+// https://www.nesdev.org/wiki/NSF
+pub struct NsfFile {
+    pub header: NsfHeader,
+    pub data: Vec<u8>,
+}
+
+pub struct NsfHeader {
+    pub version: u8,
+    pub total_songs: u8,
+    pub starting_song: u8,
+    pub load_addr: u16,
+    pub init_addr: u16,
+    pub play_addr: u16,
+    pub song_name: String,
+    pub artist: String,
+    pub copyright: String,
+    pub play_speed_ntsc: u16,
+    pub bankswitch_init: [u8; 8],
+    pub play_speed_pal: u16,
+    pub pal_ntsc_bits: u8,
+    pub extra_sound_chips: u8,
+}
+
+impl NsfFile {
+    pub fn is_bankswitched(&self) -> bool {
+        self.header.bankswitch_init.iter().any(|&b| b != 0)
+    }
+
+    // The data laid out in 4KB banks: the first bank is padded so that
+    // the load address keeps its offset within the bank.
+    pub fn banks(&self) -> Vec<u8> {
+        let padding = (self.header.load_addr as usize) & (NSF_BANK_SIZE - 1);
+        let mut banks = vec![0; padding];
+        banks.extend_from_slice(&self.data);
+        let len = banks.len().div_ceil(NSF_BANK_SIZE) * NSF_BANK_SIZE;
+        banks.resize(len, 0);
+        banks
+    }
+}
+
+fn read_u16(buffer: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes([buffer[offset], buffer[offset + 1]])
+}
+
+fn read_string(buffer: &[u8]) -> String {
+    buffer
+        .iter()
+        .take_while(|&&c| c != 0)
+        .map(|&c| c as char)
+        .collect()
+}
+
+pub fn parse_nsf(buffer: &[u8]) -> Result<NsfFile, Error> {
+    if buffer.len() < NSF_HEADER_SIZE || &buffer[0..5] != b"NESM\x1a" {
+        return Err(Error::new(ErrorKind::InvalidData, "not an NSF file"));
+    }
+    let mut bankswitch_init = [0; 8];
+    bankswitch_init.copy_from_slice(&buffer[0x70..0x78]);
+    let header = NsfHeader {
+        version: buffer[0x05],
+        total_songs: buffer[0x06],
+        starting_song: buffer[0x07],
+        load_addr: read_u16(buffer, 0x08),
+        init_addr: read_u16(buffer, 0x0A),
+        play_addr: read_u16(buffer, 0x0C),
+        song_name: read_string(&buffer[0x0E..0x2E]),
+        artist: read_string(&buffer[0x2E..0x4E]),
+        copyright: read_string(&buffer[0x4E..0x6E]),
+        play_speed_ntsc: read_u16(buffer, 0x6E),
+        bankswitch_init,
+        play_speed_pal: read_u16(buffer, 0x78),
+        pal_ntsc_bits: buffer[0x7A],
+        extra_sound_chips: buffer[0x7B],
+    };
+    Ok(NsfFile {
+        header,
+        data: buffer[NSF_HEADER_SIZE..].to_vec(),
+    })
+}
+
+pub fn read_nsf_file(file_path: &str) -> Result<NsfFile, Error> {
+    let buffer = read_file(file_path)?;
+    parse_nsf(&buffer)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn header() -> Vec<u8> {
+        let mut buffer = vec![0; NSF_HEADER_SIZE];
+        buffer[0..5].copy_from_slice(b"NESM\x1a");
+        buffer[0x05] = 1;
+        buffer[0x06] = 3;
+        buffer[0x07] = 2;
+        buffer[0x08..0x0A].copy_from_slice(&0x8123u16.to_le_bytes());
+        buffer[0x0A..0x0C].copy_from_slice(&0x8200u16.to_le_bytes());
+        buffer[0x0C..0x0E].copy_from_slice(&0x8300u16.to_le_bytes());
+        buffer[0x0E..0x13].copy_from_slice(b"Title");
+        buffer[0x6E..0x70].copy_from_slice(&16639u16.to_le_bytes());
+        buffer
+    }
+
+    #[test]
+    fn test_parse_nsf() {
+        let mut buffer = header();
+        buffer.extend_from_slice(&[1, 2, 3]);
+        let nsf = parse_nsf(&buffer).unwrap();
+        assert_eq!(nsf.header.total_songs, 3);
+        assert_eq!(nsf.header.starting_song, 2);
+        assert_eq!(nsf.header.load_addr, 0x8123);
+        assert_eq!(nsf.header.init_addr, 0x8200);
+        assert_eq!(nsf.header.play_addr, 0x8300);
+        assert_eq!(nsf.header.song_name, "Title");
+        assert_eq!(nsf.header.play_speed_ntsc, 16639);
+        assert_eq!(nsf.data, vec![1, 2, 3]);
+        assert!(!nsf.is_bankswitched());
+        assert!(parse_nsf(&buffer[1..]).is_err());
+    }
+
+    #[test]
+    fn test_banks() {
+        let mut buffer = header();
+        buffer[0x70] = 1;
+        buffer.extend_from_slice(&[0xAA; NSF_BANK_SIZE]);
+        let nsf = parse_nsf(&buffer).unwrap();
+        assert!(nsf.is_bankswitched());
+        let banks = nsf.banks();
+        assert_eq!(banks.len(), 2 * NSF_BANK_SIZE);
+        assert_eq!(banks[0x122], 0);
+        assert_eq!(banks[0x123], 0xAA);
+    }
+}
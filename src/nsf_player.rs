@@ -0,0 +1,184 @@
+use error_stack::{bail, Result};
+
+use crate::apu_log::{ApuLog, NTSC_CPU_CLOCK, NTSC_CYCLES_PER_FRAME};
+use crate::cpu::CPU;
+use crate::error::NesError;
+use crate::nsf_format::NsfFile;
+
+// Routines are called with this address as their return address, so
+// reaching it means the routine returned with RTS.
+const RETURN_ADDR: u16 = 0x5FF0;
+
+// PLAY may overrun its period, but one that hasn't returned after this
+// many periods is treated as stuck.
+const MAX_PLAY_PERIODS: u64 = 8;
+
+/**
+Drives an NSF tune: loads the data, runs INIT for the selected song and
+then calls PLAY at the rate given in the header, recording every APU
+register write on the way.
+*/
+pub struct NsfPlayer {
+    cpu: CPU,
+    init_addr: u16,
+    play_addr: u16,
+    play_period: u64,
+}
+
+impl NsfPlayer {
+    pub fn new(nsf: &NsfFile) -> Self {
+        let mut cpu = CPU::default();
+        cpu.reset();
+        if nsf.is_bankswitched() {
+            cpu.bus.load_nsf_banks(nsf.banks());
+            for (slot, bank) in nsf.header.bankswitch_init.iter().enumerate() {
+                cpu.set_mem(0x5FF8 + slot as u16, *bank);
+            }
+        } else {
+            for (addr, byte) in (nsf.header.load_addr..=0xFFFF).zip(&nsf.data) {
+                cpu.set_mem(addr, *byte);
+            }
+        }
+        let play_period = match nsf.header.play_speed_ntsc {
+            0 => NTSC_CYCLES_PER_FRAME,
+            us => us as u64 * NTSC_CPU_CLOCK as u64 / 1_000_000,
+        };
+        NsfPlayer {
+            cpu,
+            init_addr: nsf.header.init_addr,
+            play_addr: nsf.header.play_addr,
+            play_period,
+        }
+    }
+
+    fn idle_until(&mut self, cycle: u64) {
+        while self.cpu.cycles() < cycle {
+            let remaining = cycle - self.cpu.cycles();
+            self.cpu.bus.tick(remaining.min(u8::MAX as u64) as u8);
+        }
+    }
+
+    // Calls the routine at `addr` and runs it until it returns or the
+    // cpu reaches `deadline`.
+    fn call(&mut self, addr: u16, deadline: u64) -> Result<(), NesError> {
+        self.cpu.push16(RETURN_ADDR - 1);
+        self.cpu.pc = addr;
+        while self.cpu.pc != RETURN_ADDR {
+            if self.cpu.cycles() >= deadline {
+                bail!(NesError::Timeout(format!("routine at {:04X}", addr)));
+            }
+            self.cpu.run_once()?;
+        }
+        Ok(())
+    }
+
+    fn init_sound_registers(&mut self) {
+        for addr in 0x4000..=0x4013 {
+            self.cpu.set_mem(addr, 0);
+        }
+        self.cpu.set_mem(0x4015, 0x00);
+        self.cpu.set_mem(0x4015, 0x0F);
+        self.cpu.set_mem(0x4017, 0x40);
+    }
+
+    // Plays `song` (1-based) for `seconds` and returns the APU writes made.
+    pub fn play(&mut self, song: u8, seconds: u64) -> Result<ApuLog, NesError> {
+        let start = self.cpu.cycles();
+        let end = start + seconds * NTSC_CPU_CLOCK as u64;
+        self.cpu.bus.start_apu_log();
+        self.init_sound_registers();
+        self.cpu.a = song - 1;
+        self.cpu.x = 0; // NTSC
+        self.cpu.y = 0;
+        self.call(self.init_addr, end)?;
+        let mut next_play = self.cpu.cycles();
+        while next_play < end {
+            self.idle_until(next_play);
+            let deadline = self.cpu.cycles() + MAX_PLAY_PERIODS * self.play_period;
+            self.call(self.play_addr, deadline)?;
+            // a PLAY that ran past its period is followed straight away
+            next_play = (next_play + self.play_period).max(self.cpu.cycles());
+        }
+        self.idle_until(end);
+        Ok(self.cpu.bus.take_apu_log().unwrap())
+    }
+
+    pub fn cycles(&self) -> u64 {
+        self.cpu.cycles()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::nsf_format::parse_nsf;
+
+    // Builds a two-song NSF with INIT at $8000 and PLAY at $8004.
+    fn nsf(code: &[u8]) -> NsfFile {
+        let mut buffer = vec![0; 0x80];
+        buffer[0..5].copy_from_slice(b"NESM\x1a");
+        buffer[0x06] = 2;
+        buffer[0x07] = 1;
+        buffer[0x08..0x0A].copy_from_slice(&0x8000u16.to_le_bytes());
+        buffer[0x0A..0x0C].copy_from_slice(&0x8000u16.to_le_bytes());
+        buffer[0x0C..0x0E].copy_from_slice(&0x8004u16.to_le_bytes());
+        buffer[0x6E..0x70].copy_from_slice(&16639u16.to_le_bytes());
+        buffer.extend_from_slice(code);
+        parse_nsf(&buffer).unwrap()
+    }
+
+    #[test]
+    fn test_play() {
+        // INIT: STA $4001; RTS
+        // PLAY: INC $4002; RTS
+        let nsf = nsf(&[0x8D, 0x01, 0x40, 0x60, 0xEE, 0x02, 0x40, 0x60]);
+        let mut player = NsfPlayer::new(&nsf);
+        let log = player.play(2, 1).unwrap();
+        let init = log
+            .writes()
+            .iter()
+            .rev()
+            .find(|w| w.addr == 0x4001)
+            .unwrap();
+        assert_eq!(init.data, 1);
+        // one second at 16639us per frame, plus the $4002 reset before INIT
+        let plays = log.writes().iter().filter(|w| w.addr == 0x4002).count();
+        assert_eq!(plays, 1 + 61);
+        assert_eq!(
+            log.writes()
+                .iter()
+                .rev()
+                .find(|w| w.addr == 0x4002)
+                .unwrap()
+                .data,
+            61
+        );
+    }
+
+    #[test]
+    fn test_slow_play() {
+        // INIT: RTS
+        // PLAY: INC $4002; busy-waits ~38600 cycles; RTS
+        let nsf = nsf(&[
+            0x60, 0xEA, 0xEA, 0xEA, 0xEE, 0x02, 0x40, 0xA0, 0x1E, 0xA2, 0x00, 0xCA, 0xD0, 0xFD,
+            0x88, 0xD0, 0xF8, 0x60,
+        ]);
+        let mut player = NsfPlayer::new(&nsf);
+        let log = player.play(1, 1).unwrap();
+        // every PLAY overruns its period, so each starts as the last returns
+        let plays = log.writes().iter().filter(|w| w.addr == 0x4002).count();
+        assert!((1 + 40..1 + 61).contains(&plays), "{} plays", plays);
+    }
+
+    #[test]
+    fn test_runaway_play() {
+        // INIT: RTS
+        // PLAY: JMP $8004
+        let nsf = nsf(&[0x60, 0xEA, 0xEA, 0xEA, 0x4C, 0x04, 0x80]);
+        let mut player = NsfPlayer::new(&nsf);
+        let Err(err) = player.play(1, 1) else {
+            panic!("a PLAY that never returns should time out");
+        };
+        assert!(matches!(err.current_context(), NesError::Timeout(_)));
+    }
+}
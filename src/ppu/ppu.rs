@@ -1,6 +1,9 @@
 use std::cell::RefCell;
 
-use crate::nes_format::Mirroring;
+use crate::{
+    bus::{AccessKind, AccessLog, AddressSpace},
+    nes_format::Mirroring,
+};
 
 struct AddressRegister {
     addr: u16,
@@ -138,6 +141,7 @@ pub struct PPU {
     oam_data: [u8; 256],
     read_buffer: RefCell<u8>,
     mirroring: Mirroring,
+    access_log: AccessLog,
 }
 
 impl Default for PPU {
@@ -150,6 +154,7 @@ impl Default for PPU {
             oam_data: [0; 256],
             read_buffer: 0.into(),
             mirroring: Mirroring::Horizontal,
+            access_log: AccessLog::default(),
         }
     }
 }
@@ -164,9 +169,15 @@ impl PPU {
             oam_data: [0; 256],
             read_buffer: 0.into(),
             mirroring,
+            access_log: AccessLog::default(),
         }
     }
 
+    // Accesses of the PPU bus through PPUDATA.
+    pub fn access_log(&self) -> &AccessLog {
+        &self.access_log
+    }
+
     fn mirror_vram_addr(&self, addr: u16) -> u16 {
         let vram_index = addr - 0x2000; // to vram vector
         let name_table = vram_index / 0x400; // to the name table index
@@ -191,7 +202,7 @@ impl PPU {
     pub fn read_data(&self) -> u8 {
         let addr = self.registers.addr.borrow().get();
         self.registers.increment_address();
-        match addr {
+        let value = match addr {
             0x0000..=0x1FFF => {
                 let res = *self.read_buffer.borrow();
                 *self.read_buffer.borrow_mut() = self.chr_rom[addr as usize];
@@ -205,11 +216,16 @@ impl PPU {
             0x3000..=0x3EFF => panic!("unused address space"),
             0x3F00..=0x3FFF => self.palette_table[(addr - 0x3f00) as usize],
             _ => panic!("Invalid PPU address: {:#X}", addr),
-        }
+        };
+        self.access_log
+            .record(AddressSpace::Ppu, AccessKind::Read, addr, value);
+        value
     }
 
     pub fn write_data(&mut self, value: u8) {
         let addr = self.registers.addr.borrow().get();
+        self.access_log
+            .record(AddressSpace::Ppu, AccessKind::Write, addr, value);
         match addr {
             0x0000..=0x1FFF => {
                 panic!("cannot write to chr rom")
@@ -0,0 +1,269 @@
+use std::collections::{BTreeMap, HashMap};
+
+use error_stack::{bail, Result, ResultExt};
+
+use crate::error::NesError;
+use crate::io::read_file_lines;
+
+// https://www.nesdev.org/wiki/PPU_registers, https://www.nesdev.org/wiki/APU_registers
+const HARDWARE_REGISTERS: &[(u16, &str)] = &[
+    (0x2000, "PPUCTRL"),
+    (0x2001, "PPUMASK"),
+    (0x2002, "PPUSTATUS"),
+    (0x2003, "OAMADDR"),
+    (0x2004, "OAMDATA"),
+    (0x2005, "PPUSCROLL"),
+    (0x2006, "PPUADDR"),
+    (0x2007, "PPUDATA"),
+    (0x4000, "SQ1_VOL"),
+    (0x4001, "SQ1_SWEEP"),
+    (0x4002, "SQ1_LO"),
+    (0x4003, "SQ1_HI"),
+    (0x4004, "SQ2_VOL"),
+    (0x4005, "SQ2_SWEEP"),
+    (0x4006, "SQ2_LO"),
+    (0x4007, "SQ2_HI"),
+    (0x4008, "TRI_LINEAR"),
+    (0x400a, "TRI_LO"),
+    (0x400b, "TRI_HI"),
+    (0x400c, "NOISE_VOL"),
+    (0x400e, "NOISE_LO"),
+    (0x400f, "NOISE_HI"),
+    (0x4010, "DMC_FREQ"),
+    (0x4011, "DMC_RAW"),
+    (0x4012, "DMC_START"),
+    (0x4013, "DMC_LEN"),
+    (0x4014, "OAMDMA"),
+    (0x4015, "SND_CHN"),
+    (0x4016, "JOY1"),
+    (0x4017, "JOY2"),
+];
+
+/**
+Names of CPU addresses, from the hardware registers and from symbol files
+written by other tools:
+
+- FCEUX `.nl`: `$C000#Reset#comment`
+- Mesen `.mlb`: `P:0000:Reset` (PRG ROM offset), `R:0010:buttons`
+  (internal RAM), `G:2000:...` (registers), `S:`/`W:` (cartridge RAM),
+  and the Mesen 2 spellings `NesPrgRom:`, `NesInternalRam:` and so on
+- ca65 `.dbg`: the `sym` lines of type `lab`
+- our assembler's `.sym`: `name = $C000`
+
+Names that aren't valid assembler symbols, such as ca65 cheap locals,
+are skipped, and a name is only given to one address.
+*/
+#[derive(Default)]
+pub struct SymbolTable {
+    names: BTreeMap<u16, String>,
+    addrs: HashMap<String, u16>,
+}
+
+fn is_symbol(s: &str) -> bool {
+    let mut chars = s.chars();
+    match chars.next() {
+        Some(c) if c.is_ascii_alphabetic() || c == '_' => {
+            chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+        }
+        _ => false,
+    }
+}
+
+fn parse_hex(s: &str) -> Option<u32> {
+    u32::from_str_radix(s, 16).ok()
+}
+
+// The CPU address of an offset into a PRG ROM of `prg_size` bytes. A
+// 32KB ROM is mapped at $8000, otherwise only the last 16KB is, at $C000.
+fn prg_addr(offset: u32, prg_size: usize) -> Option<u16> {
+    let mapped = if prg_size == 0x8000 { 0x8000 } else { 0x4000 };
+    let start = (prg_size as u32).checked_sub(mapped)?;
+    match offset.checked_sub(start) {
+        Some(offset) if offset < mapped => Some((0x10000 - mapped + offset) as u16),
+        _ => None,
+    }
+}
+
+impl SymbolTable {
+    pub fn hardware() -> Self {
+        let mut symbols = SymbolTable::default();
+        for &(addr, name) in HARDWARE_REGISTERS {
+            symbols.insert(addr, name);
+        }
+        symbols
+    }
+
+    // Names `addr`, replacing its previous name. A name already given to
+    // another address is ignored.
+    pub fn insert(&mut self, addr: u16, name: &str) {
+        if !is_symbol(name) || self.addrs.contains_key(name) {
+            return;
+        }
+        if let Some(old) = self.names.insert(addr, name.to_string()) {
+            self.addrs.remove(&old);
+        }
+        self.addrs.insert(name.to_string(), addr);
+    }
+
+    pub fn get(&self, addr: u16) -> Option<&str> {
+        self.names.get(&addr).map(|name| name.as_str())
+    }
+
+    // The address named `name`.
+    pub fn lookup(&self, name: &str) -> Option<u16> {
+        self.addrs.get(name).copied()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (u16, &str)> {
+        self.names.iter().map(|(&addr, name)| (addr, name.as_str()))
+    }
+
+    // Adds the symbols of a file in one of the formats above, chosen by
+    // its extension. PRG ROM offsets are mapped for a ROM of `prg_size`
+    // bytes.
+    pub fn load(&mut self, path: &str, prg_size: usize) -> Result<(), NesError> {
+        let lines = read_file_lines(path)
+            .change_context(NesError::Io)
+            .attach_printable_lazy(|| format!("cannot read {}", path))?;
+        match path.rsplit('.').next().unwrap_or_default() {
+            "nl" => self.parse_nl(&lines),
+            "mlb" => self.parse_mlb(&lines, prg_size),
+            "dbg" => self.parse_dbg(&lines),
+            "sym" => self.parse_sym(&lines),
+            _ => bail!(NesError::InvalidFileExtension(path.to_string())),
+        }
+        Ok(())
+    }
+
+    fn parse_nl(&mut self, lines: &[String]) {
+        for line in lines {
+            let mut fields = line.split('#');
+            let (Some(addr), Some(name)) = (fields.next(), fields.next()) else {
+                continue;
+            };
+            // `$0200/10` names an array
+            let addr = addr
+                .trim()
+                .trim_start_matches('$')
+                .split('/')
+                .next()
+                .unwrap_or_default();
+            if let Some(addr) = parse_hex(addr).filter(|&addr| addr <= 0xffff) {
+                self.insert(addr as u16, name.trim());
+            }
+        }
+    }
+
+    fn parse_mlb(&mut self, lines: &[String], prg_size: usize) {
+        for line in lines {
+            let mut fields = line.splitn(4, ':');
+            let (Some(kind), Some(range), Some(name)) =
+                (fields.next(), fields.next(), fields.next())
+            else {
+                continue;
+            };
+            let Some(offset) = parse_hex(range.split('-').next().unwrap_or_default()) else {
+                continue;
+            };
+            let addr = match kind {
+                "P" | "NesPrgRom" => prg_addr(offset, prg_size),
+                "R" | "NesInternalRam" => Some(offset as u16 & 0x7ff),
+                "S" | "W" | "NesSaveRam" | "NesWorkRam" => {
+                    (offset < 0x2000).then(|| 0x6000 + offset as u16)
+                }
+                "G" | "NesMemory" | "Register" => (offset <= 0xffff).then_some(offset as u16),
+                _ => None,
+            };
+            if let Some(addr) = addr {
+                self.insert(addr, name.trim());
+            }
+        }
+    }
+
+    fn parse_dbg(&mut self, lines: &[String]) {
+        for line in lines {
+            let Some(attrs) = line.strip_prefix("sym\t") else {
+                continue;
+            };
+            let attrs = attrs
+                .split(',')
+                .filter_map(|attr| attr.split_once('='))
+                .collect::<HashMap<_, _>>();
+            if attrs.get("type") != Some(&"lab") {
+                continue;
+            }
+            let (Some(name), Some(val)) = (attrs.get("name"), attrs.get("val")) else {
+                continue;
+            };
+            let val = val.strip_prefix("0x").and_then(parse_hex);
+            if let Some(addr) = val.filter(|&addr| addr <= 0xffff) {
+                self.insert(addr as u16, name.trim_matches('"'));
+            }
+        }
+    }
+
+    fn parse_sym(&mut self, lines: &[String]) {
+        for line in lines {
+            let Some((name, value)) = line.split_once('=') else {
+                continue;
+            };
+            let value = value.trim();
+            let value = match value.strip_prefix('$') {
+                Some(hex) => parse_hex(hex),
+                None => value.parse().ok(),
+            };
+            if let Some(addr) = value.filter(|&addr| addr <= 0xffff) {
+                self.insert(addr as u16, name.trim());
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn lines(s: &str) -> Vec<String> {
+        s.lines().map(|line| line.to_string()).collect()
+    }
+
+    #[test]
+    fn test_hardware() {
+        let symbols = SymbolTable::hardware();
+        assert_eq!(symbols.get(0x2000), Some("PPUCTRL"));
+        assert_eq!(symbols.get(0x4014), Some("OAMDMA"));
+        assert_eq!(symbols.get(0x0000), None);
+        assert_eq!(symbols.lookup("PPUSTATUS"), Some(0x2002));
+    }
+
+    #[test]
+    fn test_parse() {
+        let mut symbols = SymbolTable::hardware();
+        symbols.parse_nl(&lines(
+            "$C000#Reset#entry point\n$0200/10#sprites#\n$C010##no name\n$0300#Reset#",
+        ));
+        assert_eq!(symbols.get(0xc000), Some("Reset"));
+        assert_eq!(symbols.get(0x0200), Some("sprites"));
+        assert_eq!(symbols.get(0xc010), None);
+        assert_eq!(symbols.get(0x0300), None);
+        symbols.parse_mlb(&lines("P:0010:Nmi:comment\nR:0010-0011:pointer\nNesWorkRam:0004:save\nG:2000:Ctrl\nX:0000:other"), 0x4000);
+        assert_eq!(symbols.get(0xc010), Some("Nmi"));
+        assert_eq!(symbols.get(0x0010), Some("pointer"));
+        assert_eq!(symbols.get(0x6004), Some("save"));
+        assert_eq!(symbols.get(0x2000), Some("Ctrl"));
+        symbols.parse_dbg(&lines(concat!(
+            "sym\tid=0,name=\"main\",addrsize=absolute,scope=0,def=3,val=0x8000,seg=0,type=lab\n",
+            "sym\tid=1,name=\"COUNT\",addrsize=zeropage,scope=0,def=2,val=0x3,type=equ\n",
+            "sym\tid=2,name=\"@loop\",addrsize=absolute,scope=0,def=5,val=0x8002,type=lab",
+        )));
+        assert_eq!(symbols.get(0x8000), Some("main"));
+        assert_eq!(symbols.get(0x0003), None);
+        assert_eq!(symbols.get(0x8002), None);
+        symbols.parse_sym(&lines("buttons = $0020\ncount = 16\nBIG = 70000"));
+        assert_eq!(symbols.get(0x0020), Some("buttons"));
+        assert_eq!(symbols.get(0x0010), Some("count"));
+        assert_eq!(prg_addr(0x7fff, 0x8000), Some(0xffff));
+        assert_eq!(prg_addr(0x1c000, 0x20000), Some(0xc000));
+        assert_eq!(prg_addr(0x0000, 0x20000), None);
+    }
+}
@@ -0,0 +1,27 @@
+; asm6 syntax, the reference is built with
+;   asm6 asm6.asm asm6.bin
+
+SPEED EQU 2
+
+    enum $00
+ptr     dsb 2
+    ende
+
+    enum $0300
+buttons dsb 1
+timer   dsw 1
+    ende
+
+    org $8000
+reset   sei
+        ldx #SPEED
+loop    dex
+        bne loop
+        lda buttons
+        sta timer+1
+        lda (ptr),y
+        sta ptr
+        jmp reset
+table   db 1, 2, SPEED
+        dw reset, table
+        hex 0a0bc0
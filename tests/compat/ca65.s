@@ -0,0 +1,27 @@
+; ca65 syntax, the reference is built with
+;   ca65 ca65.s && ld65 -t none -o ca65.bin ca65.o
+
+.org $8000
+
+SPEED = 2
+
+.proc clear
+reset:  lda #0
+        rts
+.endproc
+
+.proc main
+        ldx #SPEED
+loop:   dex
+        bne loop
+        jsr clear::reset
+        lda #<table
+        ldy #>table
+        lda z:$0010
+        sta a:$10
+        jmp ::main
+.endproc
+
+table:  .byte "HI", 0
+        .word main, clear::reset
+        .res 2, $ea